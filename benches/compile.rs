@@ -0,0 +1,53 @@
+#[macro_use]
+extern crate criterion;
+extern crate rua;
+
+use criterion::{black_box, Criterion};
+use rua::compiler::Compiler;
+use rua::parser::Parser;
+use std::str::Chars;
+
+/// a large generated script, about 40k lines
+/// functions are grouped so that no single function runs out of registers
+fn generated_source(groups: usize, functions: usize) -> String {
+    let mut text = String::new();
+    for g in 0..groups {
+        text += &format!("group_{} = function(base)\n", g);
+        for f in 0..functions {
+            text += &format!(
+                "\
+    local function_{f} = function(left, right)
+        local sum_{f} = left + right * 2 - base
+        local record = {{ name = 'record_{f}', value = sum_{f}, 1, 2, 3 }}
+        record.value = record.value + 1
+        if sum_{f} > 10 and left ~= right then
+            sum_{f} = sum_{f} - record.value
+        end
+        return sum_{f}
+    end
+    local result_{f} = function_{f}(base, {f})
+",
+                f = f
+            );
+        }
+        text += "    return base\nend\n";
+    }
+    text
+}
+
+fn parse(c: &mut Criterion) {
+    let text = generated_source(100, 40);
+    c.bench_function("parse", move |b| {
+        b.iter(|| black_box(Parser::<Chars>::ast_from_text(&text).is_ok()))
+    });
+}
+
+fn compile(c: &mut Criterion) {
+    let text = generated_source(100, 40);
+    c.bench_function("compile", move |b| {
+        b.iter(|| black_box(Compiler::compile(&text, "bench.lua").is_ok()))
+    });
+}
+
+criterion_group!(benches, parse, compile);
+criterion_main!(benches);
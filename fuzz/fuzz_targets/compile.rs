@@ -0,0 +1,7 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use rua::compiler::Compiler;
+
+fuzz_target!(|text: &str| {
+    let _ = Compiler::compile(&text.to_string(), "fuzz.lua");
+});
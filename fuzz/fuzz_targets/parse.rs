@@ -0,0 +1,8 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use rua::parser::Parser;
+use std::str::Chars;
+
+fuzz_target!(|text: &str| {
+    let _ = Parser::<Chars>::ast_from_text(&text.to_string());
+});
@@ -0,0 +1,8 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use rua::lexer::Lexer;
+
+fuzz_target!(|text: &str| {
+    let lexer = Lexer::new();
+    for _ in lexer.tokenize(text.chars()) {}
+});
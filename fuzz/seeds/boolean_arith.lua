@@ -0,0 +1,3 @@
+local a, b = true, false
+local c = not ( 3 <= 2 or a == b)
+print(c)
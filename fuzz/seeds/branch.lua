@@ -0,0 +1,14 @@
+local a, b = 1, 2
+local cond = true
+
+if a ~= b then
+    c = 2
+else
+    c = 3
+end
+if cond then
+    d = 4
+else
+    d = 5
+end
+print(c, d)
@@ -0,0 +1,3 @@
+table = {name='Ann', age = 10 + 8,
+        1, 2, 3}
+print(table.name, table['age'], table[1], table[2], table[3])
@@ -0,0 +1,7 @@
+local a = 2
+func = function(para)
+    local b = 3
+    return a + para + b, 0
+end
+local b, c = func(1, 2)
+local d = b + c
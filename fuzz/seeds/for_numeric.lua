@@ -0,0 +1,8 @@
+local sum1, sum2 = 0, 0
+for i = 1, 100, 1 do
+    sum1 = sum1 + i
+end
+for i = 0, 100, 2 do
+    sum2 = sum2 + i
+end
+print(sum1, sum2)
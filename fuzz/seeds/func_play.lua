@@ -0,0 +1,4 @@
+add_sub = function(a, b)
+    return a + b, a - b
+end
+print(add_sub(1, 2))
@@ -0,0 +1,9 @@
+local a, b = true, false
+local c
+if a ~= b and 2 < 3 then
+    c = 1
+elseif 3 <= 4 then
+    c = 0
+else
+    c = 2
+end
@@ -0,0 +1,13 @@
+add = function(a, b)
+    return a + b
+end
+
+sub = function(a, b)
+    return a - b
+end
+
+a = sub(add(3, 4), 4)
+b, c = add(3, 4), sub(3, 4)
+d = sub(3, add(3, 4))
+e = sub(add(3, 4), add(1, 2))
+print(a, b, c, d, e)
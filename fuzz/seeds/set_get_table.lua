@@ -0,0 +1,5 @@
+local table = { name = 'Ann' }
+(table)['age'] = 12
+table['subtable'] = { 1, 2 }
+local a, n, sub = table['age'], table['name'],
+                  table['subtable'][1]
@@ -0,0 +1,3 @@
+local a = 3 * (1 + 1)  --6
+local b = 10 / (2 + 3)  --2
+print(a + b, b - a)
@@ -0,0 +1,13 @@
+table = nil --Bypass
+local stu = {name='Ann', grades={'A', 'A-', 'B+'}}
+
+stu.change_name = function(self)
+    self.name = 'Lee'
+end
+
+stu.grades.add_grade = function(self, g)
+    table.insert(self, g)
+end
+
+stu.change_name(stu)
+stu.grades:add_grade('C')
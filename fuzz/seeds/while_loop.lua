@@ -0,0 +1,9 @@
+local i, sum = 0, 0
+while i <= 100 do
+    sum = sum + i
+    if sum == 5000 then
+        break
+    end
+    i = i + 1
+end
+print(sum)
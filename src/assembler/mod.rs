@@ -35,7 +35,7 @@ impl Assembler {
         result.push(if chunk.is_vararg { 2_u8 } else { 0_u8 });
         result.push(chunk.stack_size as u8);
 
-        result.append(&mut Self::asm_block(&chunk.instructions));
+        result.append(&mut Self::asm_block(&chunk.instructions)?);
         result.append(&mut Self::unpack_constant(&chunk.constants));
         result.append(&mut Self::asm_function_list(&chunk.function_prototypes)?);
         result.extend_from_slice(&Self::unpack_u32(0));
@@ -47,20 +47,22 @@ impl Assembler {
     /// Generate u8 for instructions
     /// with size field
     #[allow(non_snake_case)]
-    fn asm_block(instrs: &Vec<OpMode>) -> ByteCodeVec {
+    fn asm_block(instrs: &Vec<OpMode>) -> Result<ByteCodeVec, AsmError> {
         let mut result = vec![];
-        let label_removed = Self::remove_label(instrs);
+        let label_removed = Self::remove_label(instrs)?;
         result.extend_from_slice(&Self::unpack_u32(label_removed.len() as u32));
         for instr in &label_removed {
             let packed = match instr {
                 &OpMode::iABC(op, A, B, C) => OpcodeBuilder::iABC(op, A, B, C),
                 &OpMode::iABx(op, A, Bx) => OpcodeBuilder::iABx(op, A, Bx),
                 &OpMode::iAsBx(op, A, sBx) => OpcodeBuilder::iAsBx(op, A, sBx),
+                &OpMode::Raw(word) => Some(word),
                 _ => panic!("Can not encode instruction. Has all labels been removed?"),
             };
+            let packed = packed.ok_or(AsmError::OperandOverflow)?;
             result.extend_from_slice(&Self::unpack_u32(packed));
         }
-        result
+        Ok(result)
     }
 
     fn asm_function_list(func_list: &Vec<FunctionChunk>) -> Result<ByteCodeVec, AsmError> {
@@ -149,7 +151,7 @@ impl Assembler {
         result
     }
 
-    fn remove_label(instrs: &Vec<OpMode>) -> Vec<OpMode> {
+    fn remove_label(instrs: &Vec<OpMode>) -> Result<Vec<OpMode>, AsmError> {
         // println!("Before remove: {:?}", self);
         // pass one: remove label and build index
         let mut label_removed = vec![];
@@ -168,28 +170,28 @@ impl Assembler {
             }
         }
         // pass two: replace label with number
+        let target = |label: &Label| index.get(label).ok_or(AsmError::LabelUndefined);
         let mut replaced = vec![];
         for (pos, ins) in label_removed.iter().enumerate() {
             match **ins {
                 OpMode::rJMP(ref label) => {
-                    let mut num = index.get(label).expect("Label undefined") - (pos as i32) - 1;
-                    // adjust negative jump
-                    if num != 0 {
-                        replaced.push(OpMode::iAsBx(OpName::JMP, 0, num));
-                    }
+                    // a jump to the next instruction is kept, removing it would
+                    // shift the positions counted above and split a test from its jump
+                    let num = target(label)? - (pos as i32) - 1;
+                    replaced.push(OpMode::iAsBx(OpName::JMP, 0, num));
                 }
                 OpMode::rForPrep(reg, ref label) => {
-                    let num = index.get(label).expect("Label undefined") - (pos as i32) - 1;
+                    let num = target(label)? - (pos as i32) - 1;
                     replaced.push(OpMode::iAsBx(OpName::FORPREP, reg, num));
                 }
                 OpMode::rForLoop(reg, ref label) => {
-                    let num = index.get(label).expect("Label undefined") - (pos as i32) - 1;
+                    let num = target(label)? - (pos as i32) - 1;
                     replaced.push(OpMode::iAsBx(OpName::FORLOOP, reg, num));
                 }
                 _ => replaced.push((*ins).clone()),
             }
         }
-        replaced
+        Ok(replaced)
     }
 }
 
@@ -232,8 +234,8 @@ pub mod tests {
             -- Nothing
         "))
             .expect("Syntax Error");
-        let mut ir_gen = IRGen::new();
-        assert_eq!(ir_gen.generate_ir(&ast), Ok(()));
+        let mut ir_gen = IRGen::new(&ast);
+        assert_eq!(ir_gen.generate_ir(), Ok(()));
         let bytecode = Assembler::assemble(ir_gen.get_chunk("empty.lua"))
             .expect("Assemble failed");
 
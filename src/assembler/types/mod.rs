@@ -3,6 +3,8 @@
 #[derive(Debug, PartialEq, Clone)]
 pub enum AsmError {
     LabelUndefined,
+    /// an operand does not fit its field of the instruction
+    OperandOverflow,
 }
 
 pub type ByteCodeVec = Vec<u8>;
\ No newline at end of file
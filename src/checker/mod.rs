@@ -0,0 +1,661 @@
+use std::collections::HashMap;
+use std::fmt;
+use ir_generator::resolver::{DeclSite, LocalId, Resolution};
+use lexer::tokens::{FlagType, Location};
+use parser::types::*;
+
+/// a value that does not fit its annotation, or an operation that always fails
+#[derive(Clone, Debug, PartialEq)]
+pub struct TypeError {
+    pub location: Location,
+    pub message: String,
+}
+
+impl fmt::Display for TypeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.location, self.message)
+    }
+}
+
+/// types of the values of an expression list
+struct Values {
+    types: Vec<Type>,
+    /// if it ends with a call of unknown results or `...`
+    open: bool,
+}
+
+impl Values {
+    /// missing values are nil
+    fn get(&self, i: usize) -> Type {
+        match self.types.get(i) {
+            Some(ty) => ty.clone(),
+            None if self.open => Type::Any,
+            None => Type::Nil,
+        }
+    }
+}
+
+/// checks the annotations of the typed dialect, using the names bound by the resolver
+/// unannotated locals, globals and fields are `any`, so plain Lua is not rejected
+/// nil fits every type
+#[derive(Debug)]
+pub struct Checker<'a> {
+    ast: &'a Ast,
+    resolution: &'a Resolution,
+    /// annotated locals, and locals holding an annotated function that are never assigned
+    locals: HashMap<LocalId, Type>,
+    /// annotated results of the enclosing functions, the innermost last
+    returns: Vec<Option<Vec<Type>>>,
+    /// item types of the array constructors whose items differ
+    mixed_arrays: HashMap<ExprId, Vec<(ExprId, Type)>>,
+    errors: Vec<TypeError>,
+}
+
+impl<'a> Checker<'a> {
+    /// errors in source order
+    pub fn check(ast: &'a Ast, resolution: &'a Resolution) -> Vec<TypeError> {
+        let mut checker = Checker {
+            ast,
+            resolution,
+            locals: HashMap::new(),
+            returns: vec![],
+            mixed_arrays: HashMap::new(),
+            errors: vec![],
+        };
+        if let Node::Block(root) = ast.root {
+            checker.returns.push(None);
+            checker.block(root);
+        }
+        checker.errors.sort_by_key(|error| error.location);
+        checker.errors
+    }
+
+    fn error(&mut self, location: Location, message: String) {
+        self.errors.push(TypeError { location, message });
+    }
+
+    /// report if the value does not fit the type
+    fn expect(&mut self, expected: &Type, actual: &Type, location: Location) {
+        if !accepts(expected, actual) {
+            let message = format!("expected {}, got {}",
+                                  self.describe(expected),
+                                  self.describe(actual));
+            self.error(location, message);
+        }
+    }
+
+    /// an array constructor of mixed items has each item checked against the expected one,
+    /// though its own type is `{any}`
+    fn expect_value(&mut self,
+                    expected: &Type,
+                    actual: &Type,
+                    expr: Option<ExprId>,
+                    location: Location) {
+        if let Type::Array(ref item_type) = *expected {
+            if let Some(items) = expr.and_then(|expr| self.mixed_arrays.get(&expr).cloned()) {
+                for (item, ty) in items {
+                    let location = self.ast.location(item);
+                    self.expect_value(item_type, &ty, Some(item), location);
+                }
+                return;
+            }
+        }
+        self.expect(expected, actual, location);
+    }
+
+    /// arithmetic operand, strings are converted at run time
+    fn expect_number(&mut self, actual: &Type, location: Location) {
+        match *actual {
+            Type::Any | Type::Number | Type::String => {}
+            _ => {
+                let message = format!("expected number, got {}", self.describe(actual));
+                self.error(location, message);
+            }
+        }
+    }
+
+    /// numbers are converted to strings
+    fn expect_string(&mut self, actual: &Type, location: Location) {
+        match *actual {
+            Type::Any | Type::Number | Type::String => {}
+            _ => {
+                let message = format!("expected string, got {}", self.describe(actual));
+                self.error(location, message);
+            }
+        }
+    }
+
+    fn block(&mut self, block: BlockId) {
+        let ast = self.ast;
+        for (i, stat) in ast[block].stats.iter().enumerate() {
+            self.stat(stat, DeclSite::Stat(block, i), ast[block].locations[i]);
+        }
+        if let Some(ref ret) = ast[block].ret {
+            self.ret(ret);
+        }
+    }
+
+    fn stat(&mut self, stat: &Stat, site: DeclSite, location: Location) {
+        let ast = self.ast;
+        match *stat {
+            Stat::Empty | Stat::Break => {}
+            Stat::Ret(ref exprs) => self.ret(exprs),
+            Stat::AssignLocal(_, ref exprs) => {
+                let values = self.values(exprs);
+                let annotations = ast.annotations.locals.get(&location);
+                for (i, &id) in self.resolution.declared(site).iter().enumerate() {
+                    let value = values.get(i);
+                    match annotations.and_then(|types| types[i].clone()) {
+                        Some(ty) => {
+                            let value_location = exprs.get(i).map_or(location, |&expr| {
+                                ast.location(expr)
+                            });
+                            self.expect_value(&ty, &value, exprs.get(i).cloned(), value_location);
+                            self.locals.insert(id, ty);
+                        }
+                        // the signature stays valid as long as the local is not assigned
+                        None => {
+                            if let Type::Function(Some(_)) = value {
+                                if !self.resolution.is_assigned(id) {
+                                    self.locals.insert(id, value);
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+            Stat::Assign(ref targets, ref exprs) => {
+                let values = self.values(exprs);
+                for (i, &target) in targets.iter().enumerate() {
+                    let expected = match ast[target] {
+                        Expr::TableRef(table, key) => self.index(table, key, target),
+                        _ => self.type_of(target),
+                    };
+                    let value_location = exprs.get(i).map_or(location, |&expr| ast.location(expr));
+                    self.expect_value(&expected, &values.get(i), exprs.get(i).cloned(),
+                                      value_location);
+                }
+            }
+            Stat::IfElse(test, then_block, else_block) => {
+                self.type_of(test);
+                self.block(then_block);
+                if let Some(else_block) = else_block {
+                    self.block(else_block);
+                }
+            }
+            Stat::While(test, block) => {
+                self.type_of(test);
+                self.block(block);
+            }
+            Stat::ForRange(_, ref exprs, block) => {
+                self.values(exprs);
+                self.block(block);
+            }
+            Stat::ForNumeric(_, start, end, step, block) => {
+                for &expr in &[start, end, step] {
+                    let ty = self.type_of(expr);
+                    self.expect_number(&ty, ast.location(expr));
+                }
+                self.block(block);
+            }
+            Stat::GeneralCall(func, ref args) => {
+                self.call(func, args);
+            }
+            Stat::ColonCall(table, _, ref args) => {
+                self.type_of(table);
+                self.values(args);
+            }
+        }
+    }
+
+    fn ret(&mut self, exprs: &[ExprId]) {
+        let values = self.values(exprs);
+        let expected = match self.returns.last() {
+            Some(Some(expected)) => expected.clone(),
+            _ => return,
+        };
+        if values.types.len() > expected.len() {
+            let location = self.ast.location(exprs[expected.len().min(exprs.len() - 1)]);
+            let message = format!("returns {} values, expected {}",
+                                  values.types.len(),
+                                  expected.len());
+            self.error(location, message);
+        }
+        for (i, ty) in expected.iter().enumerate() {
+            // a missing value is nil
+            if let Some(&expr) = exprs.get(i).or_else(|| exprs.last()) {
+                self.expect_value(ty, &values.get(i), exprs.get(i).cloned(),
+                                  self.ast.location(expr));
+            }
+        }
+    }
+
+    fn function(&mut self, body: BlockId) -> Type {
+        let signature = self.ast.annotations.functions.get(&body);
+        if let Some(signature) = signature {
+            let params = self.resolution.declared(DeclSite::Params(body));
+            for (&id, ty) in params.iter().zip(&signature.params) {
+                if let Some(ref ty) = *ty {
+                    self.locals.insert(id, ty.clone());
+                }
+            }
+        }
+        self.returns.push(signature.and_then(|signature| signature.returns.clone()));
+        self.block(body);
+        self.returns.pop();
+        Type::Function(signature.cloned())
+    }
+
+    /// the last expression may be a call returning several values
+    fn values(&mut self, exprs: &[ExprId]) -> Values {
+        let mut values = Values {
+            types: vec![],
+            open: false,
+        };
+        for (i, &expr) in exprs.iter().enumerate() {
+            if i + 1 == exprs.len() {
+                if let Expr::GeneralCall(func, ref args) = self.ast[expr] {
+                    match self.call(func, args) {
+                        Some(results) => values.types.extend(results),
+                        None => {
+                            values.types.push(Type::Any);
+                            values.open = true;
+                        }
+                    }
+                    break;
+                }
+                // any number of values
+                if let Expr::Vararg = self.ast[expr] {
+                    values.open = true;
+                    break;
+                }
+            }
+            let ty = self.type_of(expr);
+            values.types.push(ty);
+        }
+        values
+    }
+
+    /// ret: the annotated results
+    fn call(&mut self, func: ExprId, args: &[ExprId]) -> Option<Vec<Type>> {
+        let func_type = self.type_of(func);
+        let values = self.values(args);
+        let location = self.ast.location(func);
+        match func_type {
+            Type::Function(Some(signature)) => {
+                let count = signature.params.len();
+                if values.types.len() > count && !signature.is_vararg {
+                    let extra = args.get(count).or_else(|| args.last()).cloned();
+                    let location = extra.map_or(location, |expr| self.ast.location(expr));
+                    let message = format!("too many arguments, expected {}", count);
+                    self.error(location, message);
+                }
+                for (i, param) in signature.params.iter().enumerate() {
+                    if let Some(ref param) = *param {
+                        let arg = args.get(i).or_else(|| args.last()).cloned();
+                        let location = arg.map_or(location, |expr| self.ast.location(expr));
+                        self.expect_value(param, &values.get(i), args.get(i).cloned(), location);
+                    }
+                }
+                signature.returns
+            }
+            Type::Nil | Type::Boolean | Type::Number | Type::String => {
+                let message = format!("attempt to call a {} value", self.describe(&func_type));
+                self.error(location, message);
+                None
+            }
+            _ => None,
+        }
+    }
+
+    /// type of the first value of the expression
+    fn type_of(&mut self, expr: ExprId) -> Type {
+        let ast = self.ast;
+        match ast[expr] {
+            Expr::Num(_) => Type::Number,
+            Expr::Str(_) => Type::String,
+            Expr::Boole(_) => Type::Boolean,
+            Expr::Nil => Type::Nil,
+            Expr::Vararg => Type::Any,
+            Expr::Var(_) => {
+                self.resolution
+                    .local_of(expr)
+                    .and_then(|id| self.locals.get(&id).cloned())
+                    .unwrap_or(Type::Any)
+            }
+            Expr::Paren(inner) => self.type_of(inner),
+            Expr::UnaryOp(op, operand) => {
+                let ty = self.type_of(operand);
+                match op {
+                    FlagType::Minus => {
+                        self.expect_number(&ty, ast.location(operand));
+                        Type::Number
+                    }
+                    FlagType::Not => Type::Boolean,
+                    FlagType::Len => {
+                        let has_length = matches!(ty, Type::Any | Type::String | Type::Table |
+                                                      Type::Array(_) | Type::Record(_));
+                        if !has_length {
+                            let message = format!("attempt to get length of {}",
+                                                  self.describe(&ty));
+                            self.error(ast.location(operand), message);
+                        }
+                        Type::Number
+                    }
+                    _ => Type::Any,
+                }
+            }
+            Expr::BinOp(op, left, right) => {
+                let (left_type, right_type) = (self.type_of(left), self.type_of(right));
+                match op {
+                    FlagType::Plus | FlagType::Minus | FlagType::Mul | FlagType::Div |
+                    FlagType::Pow => {
+                        self.expect_number(&left_type, ast.location(left));
+                        self.expect_number(&right_type, ast.location(right));
+                        Type::Number
+                    }
+                    FlagType::LESS | FlagType::LEQ | FlagType::GREATER | FlagType::GEQ => {
+                        let comparable = matches!((&left_type, &right_type),
+                                                  (Type::Any, _) | (_, Type::Any) |
+                                                  (Type::Number, Type::Number) |
+                                                  (Type::String, Type::String));
+                        if !comparable {
+                            let message = format!("attempt to compare {} with {}",
+                                                  self.describe(&left_type),
+                                                  self.describe(&right_type));
+                            self.error(ast.location(expr), message);
+                        }
+                        Type::Boolean
+                    }
+                    FlagType::DoubleDot => {
+                        self.expect_string(&left_type, ast.location(left));
+                        self.expect_string(&right_type, ast.location(right));
+                        Type::String
+                    }
+                    FlagType::EQ | FlagType::NEQ => Type::Boolean,
+                    // either operand may be the result
+                    FlagType::AND | FlagType::OR if left_type == right_type => left_type,
+                    _ => Type::Any,
+                }
+            }
+            Expr::FunctionDef(_, body) => self.function(body),
+            Expr::GeneralCall(func, ref args) => {
+                match self.call(func, args) {
+                    Some(results) => results.into_iter().next().unwrap_or(Type::Nil),
+                    None => Type::Any,
+                }
+            }
+            Expr::ColonCall(table, _, ref args) => {
+                self.type_of(table);
+                self.values(args);
+                Type::Any
+            }
+            Expr::TableCtor(ref entrys) => self.table(expr, entrys),
+            Expr::TableRef(table, key) => self.index(table, key, expr),
+        }
+    }
+
+    /// `{}` is a table, a list of values of one type is an array,
+    /// fields with constant names are a record
+    fn table(&mut self, expr: ExprId, entrys: &[TableEntry]) -> Type {
+        let ast = self.ast;
+        let mut items = vec![];
+        let mut fields = vec![];
+        let mut other_keys = false;
+        for &(key, value) in entrys {
+            let value_type = self.type_of(value);
+            match key.map(|key| (key, &ast[key])) {
+                None => items.push((value, value_type)),
+                Some((_, &Expr::Str(name))) => fields.push((name, value_type)),
+                Some((key, _)) => {
+                    self.type_of(key);
+                    other_keys = true;
+                }
+            }
+        }
+        if other_keys || (!items.is_empty() && !fields.is_empty()) || entrys.is_empty() {
+            Type::Table
+        } else if fields.is_empty() {
+            let first = items[0].1.clone();
+            if items.iter().all(|item| item.1 == first) {
+                Type::Array(Box::new(first))
+            } else {
+                self.mixed_arrays.insert(expr, items);
+                Type::Array(Box::new(Type::Any))
+            }
+        } else {
+            Type::Record(fields)
+        }
+    }
+
+    /// type of `table[key]`
+    fn index(&mut self, table: ExprId, key: ExprId, expr: ExprId) -> Type {
+        let ast = self.ast;
+        let table_type = self.type_of(table);
+        match table_type {
+            Type::Record(ref fields) => {
+                if let Expr::Str(name) = ast[key] {
+                    match fields.iter().find(|field| field.0 == name) {
+                        Some(field) => field.1.clone(),
+                        None => {
+                            let message = format!("no field '{}' in {}",
+                                                  ast.name(name),
+                                                  self.describe(&table_type));
+                            self.error(ast.location(key), message);
+                            Type::Any
+                        }
+                    }
+                } else {
+                    self.type_of(key);
+                    Type::Any
+                }
+            }
+            Type::Array(ref item) => {
+                let key_type = self.type_of(key);
+                if !matches!(key_type, Type::Any | Type::Number) {
+                    let message = format!("expected number index, got {}",
+                                          self.describe(&key_type));
+                    self.error(ast.location(key), message);
+                }
+                (**item).clone()
+            }
+            Type::Nil | Type::Boolean | Type::Number | Type::Function(_) => {
+                self.type_of(key);
+                let message = format!("attempt to index a {} value", self.describe(&table_type));
+                self.error(ast.location(expr), message);
+                Type::Any
+            }
+            // strings are indexed through their metatable
+            _ => {
+                self.type_of(key);
+                Type::Any
+            }
+        }
+    }
+
+    /// the type as written in annotations
+    fn describe(&self, ty: &Type) -> String {
+        let list = |types: &[Type]| {
+            types.iter().map(|ty| self.describe(ty)).collect::<Vec<_>>().join(", ")
+        };
+        match *ty {
+            Type::Any => "any".to_string(),
+            Type::Nil => "nil".to_string(),
+            Type::Boolean => "boolean".to_string(),
+            Type::Number => "number".to_string(),
+            Type::String => "string".to_string(),
+            Type::Table => "table".to_string(),
+            Type::Array(ref item) => format!("{{{}}}", self.describe(item)),
+            Type::Record(ref fields) => {
+                let fields = fields
+                    .iter()
+                    .map(|&(name, ref ty)| format!("{}: {}", self.ast.name(name), self.describe(ty)))
+                    .collect::<Vec<_>>();
+                format!("{{{}}}", fields.join(", "))
+            }
+            Type::Function(None) => "function".to_string(),
+            Type::Function(Some(ref signature)) => {
+                let params = signature
+                    .params
+                    .iter()
+                    .map(|param| param.clone().unwrap_or(Type::Any))
+                    .collect::<Vec<_>>();
+                let vararg = match (signature.is_vararg, params.is_empty()) {
+                    (false, _) => "",
+                    (true, true) => "...",
+                    (true, false) => ", ...",
+                };
+                let returns = match signature.returns {
+                    None => String::new(),
+                    Some(ref types) if types.len() == 1 => format!(": {}", list(types)),
+                    Some(ref types) => format!(": ({})", list(types)),
+                };
+                format!("function({}{}){}", list(&params), vararg, returns)
+            }
+        }
+    }
+}
+
+/// if a value of type actual fits where expected is annotated
+fn accepts(expected: &Type, actual: &Type) -> bool {
+    match (expected, actual) {
+        (&Type::Any, _) | (_, &Type::Any) | (_, &Type::Nil) => true,
+        (&Type::Table, &Type::Array(_)) |
+        (&Type::Table, &Type::Record(_)) |
+        (&Type::Array(_), &Type::Table) |
+        (&Type::Record(_), &Type::Table) => true,
+        (Type::Array(expected), Type::Array(actual)) => accepts(expected, actual),
+        // fields that are not annotated are not checked
+        (Type::Record(expected), Type::Record(actual)) => {
+            expected.iter().all(|&(name, ref expected)| {
+                actual
+                    .iter()
+                    .filter(|field| field.0 == name)
+                    .all(|field| accepts(expected, &field.1))
+            })
+        }
+        (&Type::Function(None), &Type::Function(_)) |
+        (&Type::Function(_), &Type::Function(None)) => true,
+        (expected, actual) => expected == actual,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ir_generator::resolver::Resolver;
+    use parser::Parser;
+
+    fn check(text: &str) -> Vec<String> {
+        let ast = Parser::<::std::str::Chars>::typed_ast_from_text(text)
+            .expect("Parse Error");
+        let resolution = Resolver::resolve(&ast).expect("Resolve Error");
+        Checker::check(&ast, &resolution)
+            .iter()
+            .map(|error| error.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn annotated_locals() {
+        let text = "\
+local n: number, s: string = 1, 'a'
+local b: boolean = 2
+local t: table, u: any
+n = s
+local p: {x: number, y: string} = {x = 1, y = 'a'}
+p.x = 'b'
+local q = p.z
+local a: {number} = {1, 2}
+a[1] = true
+local r: {number} = {x = 1}
+print(a['k'], n < s, -p.y, n(), nil + 1)
+local undefined
+undefined = 3
+local c: string = n .. s .. b
+local m: number = s .. n
+";
+        assert_eq!(
+            check(text),
+            vec![
+                "2:20: expected boolean, got number",
+                "4:5: expected number, got string",
+                "6:7: expected number, got string",
+                "7:13: no field 'z' in {x: number, y: string}",
+                "9:8: expected number, got boolean",
+                "10:21: expected {number}, got {x: number}",
+                "11:9: expected number index, got string",
+                "11:15: attempt to compare number with string",
+                "11:28: attempt to call a number value",
+                "11:33: expected number, got nil",
+                "14:29: expected string, got boolean",
+                "15:19: expected number, got string",
+            ]
+        );
+    }
+
+    #[test]
+    fn mixed_arrays() {
+        let text = "\
+local t: {string} = {'a', 2}
+local u: {number} = {1, 'b', true}
+local v: {{string}} = {{'a'}, {1, 'b'}}
+local w: {any} = {1, 'a'}
+local names = function(list: {string}) end
+names({'a', 2})
+local x = {1, 'a'}
+local y: {number} = x
+";
+        assert_eq!(
+            check(text),
+            vec![
+                "1:27: expected string, got number",
+                "2:25: expected number, got string",
+                "2:30: expected number, got boolean",
+                "3:32: expected string, got number",
+                "6:13: expected string, got number",
+            ]
+        );
+    }
+
+    #[test]
+    fn functions() {
+        let text = "\
+local add = function(a: number, b: number): number
+    return a + b
+end
+local pair = function(): (string, number)
+    return 'a', 'b'
+end
+local dyn = function(x) return x, x end
+print(add(1, 'x'), add(1, 2, 3), dyn(1, 2, 3))
+local s: string, m: number = pair()
+local c: string = add(1, 2)
+local f: function = add
+local g = function(s: string, ...): boolean return s end
+";
+        assert_eq!(
+            check(text),
+            vec![
+                "5:17: expected number, got string",
+                "8:14: expected number, got string",
+                "8:30: too many arguments, expected 2",
+                "10:19: expected string, got number",
+                "12:52: expected boolean, got string",
+            ]
+        );
+    }
+
+    #[test]
+    fn dynamic_code_passes() {
+        let text = "\
+local t = {}
+t.x = 1
+local f = function(a, b) return a + b end
+f = 'not a function'
+local s = '10' + 1
+print(t.y, f, s, undefined.field)
+";
+        assert!(check(text).is_empty());
+    }
+}
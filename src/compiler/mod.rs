@@ -1,46 +1,122 @@
 use parser::Parser;
+use parser::types::ParserError;
+use checker::{Checker, TypeError};
 use ir_generator::IRGen;
+use ir_generator::resolver::Resolver;
+use optimizer;
+use optimizer::Warning;
+use ir_generator::types::{CompileError, CompileOptions};
 use assembler::Assembler;
-use assembler::types::ByteCodeVec;
+use assembler::types::{AsmError, ByteCodeVec};
 use std::str::Chars;
+use std::io;
 use std::io::prelude::*;
 use std::path::Path;
 use std::fs::File;
-use std::error::Error;
+use std::fmt;
 
 #[derive(Debug)]
 pub struct Compiler {
 
 }
 
+/// errors from each stage of compiling
+#[derive(Debug)]
+pub enum CompilerError {
+    Io(io::Error),
+    Syntax(ParserError),
+    Compile(CompileError),
+    /// mismatches found by the checker of the typed dialect
+    Type(Vec<TypeError>),
+    Assemble(AsmError),
+}
+
+impl fmt::Display for CompilerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            CompilerError::Syntax(ParserError::Limit(ref err)) => write!(f, "{}", err),
+            CompilerError::Compile(ref err) => write!(f, "{}", err),
+            CompilerError::Type(ref errors) => {
+                let errors = errors.iter().map(|err| err.to_string()).collect::<Vec<_>>();
+                write!(f, "{}", errors.join("\n"))
+            }
+            ref err => write!(f, "{:?}", err),
+        }
+    }
+}
+
 impl Compiler {
-    pub fn from_string(text: &String, source_name: &str) -> ByteCodeVec {
-        let ast = Parser::<Chars>::ast_from_text(text).expect("Syntax Error");
-        let mut ir_gen = IRGen::new();
-        ir_gen.generate_ir(&ast).expect("Generating IR failed");
+    pub fn compile(text: &String, source_name: &str) -> Result<ByteCodeVec, CompilerError> {
+        Compiler::compile_with_options(text, source_name, CompileOptions::default())
+    }
 
-        println!("{:?}", ir_gen.get_chunk(source_name));
+    pub fn compile_with_options(
+        text: &String,
+        source_name: &str,
+        options: CompileOptions,
+    ) -> Result<ByteCodeVec, CompilerError> {
+        Compiler::compile_with_warnings(text, source_name, options).map(|(bytecode, _)| bytecode)
+    }
 
+    /// also returns warnings about the code removed by the optimizer
+    pub fn compile_with_warnings(
+        text: &String,
+        source_name: &str,
+        options: CompileOptions,
+    ) -> Result<(ByteCodeVec, Vec<Warning>), CompilerError> {
+        let mut ast = if options.typed {
+            Parser::<Chars>::typed_ast_from_text(text)
+        } else {
+            Parser::<Chars>::ast_from_text(text)
+        }.map_err(CompilerError::Syntax)?;
+        if options.typed {
+            let resolution = Resolver::resolve_with_options(&ast, &options)
+                .map_err(CompilerError::Compile)?;
+            let errors = Checker::check(&ast, &resolution);
+            if !errors.is_empty() {
+                return Err(CompilerError::Type(errors));
+            }
+        }
+        let warnings = optimizer::optimize(&mut ast);
+        let mut ir_gen = IRGen::with_options(&ast, options);
+        ir_gen.generate_ir().map_err(CompilerError::Compile)?;
         let bytecode = Assembler::assemble(ir_gen.get_chunk(source_name))
-            .expect("Assemble failed");
-        bytecode
+            .map_err(CompilerError::Assemble)?;
+        Ok((bytecode, warnings))
     }
 
-    pub fn from_file(path_str: &str) -> ByteCodeVec {
-        let path = Path::new(path_str);
-        let mut file = match File::open(&path) {
-            Ok(f) => f,
-            Err(why) => panic!("Could not open {}: {}", path.display(), why.description()),
-        };
+    pub fn compile_file(path_str: &str) -> Result<ByteCodeVec, CompilerError> {
+        Compiler::compile_file_with_options(path_str, CompileOptions::default())
+    }
 
+    pub fn compile_file_with_options(
+        path_str: &str,
+        options: CompileOptions,
+    ) -> Result<ByteCodeVec, CompilerError> {
+        Compiler::compile_file_with_warnings(path_str, options).map(|(bytecode, _)| bytecode)
+    }
+
+    pub fn compile_file_with_warnings(
+        path_str: &str,
+        options: CompileOptions,
+    ) -> Result<(ByteCodeVec, Vec<Warning>), CompilerError> {
+        let path = Path::new(path_str);
         let mut source = String::new();
-        file.read_to_string(&mut source).expect("Read failed.");
-        Compiler::from_string(&source,
-                              &String::from(path.file_name()
-                                  .unwrap()
-                                  .to_str()
-                                  .unwrap()
-                                  .to_string()))
+        File::open(&path)
+            .and_then(|mut file| file.read_to_string(&mut source))
+            .map_err(CompilerError::Io)?;
+        let source_name = path.file_name()
+            .and_then(|name| name.to_str())
+            .unwrap_or(path_str);
+        Compiler::compile_with_warnings(&source, source_name, options)
+    }
+
+    pub fn from_string(text: &String, source_name: &str) -> ByteCodeVec {
+        Compiler::compile(text, source_name).expect("Compiling failed")
+    }
+
+    pub fn from_file(path_str: &str) -> ByteCodeVec {
+        Compiler::compile_file(path_str).expect("Compiling failed")
     }
 }
 
@@ -101,6 +177,25 @@ mod tests {
         run_and_check(&name, bc, "true\r\n");
     }
 
+    #[test]
+    fn logic_values() {
+        let code = "
+            local none = function() end
+            local x
+            local t = {k = 3}
+            local v, y = none() or 7, x or 5
+            print(v, y, t and t.k, x and x.k, false or nil)
+            local d = 1
+            d = x or d
+            d = d and d + 1 or 0
+            print(d, d > 1 and 'big' or 'small', (none() or 2) + 1)
+        "
+            .to_string();
+        let name = "logic_values".to_string();
+        let bc = Compiler::from_string(&code, &name);
+        run_and_check(&name, bc, "7\t5\t3\tnil\tnil\r\n2\tbig\t3\r\n");
+    }
+
     #[test]
     fn branch() {
         let code = "\
@@ -159,6 +254,53 @@ mod tests {
         run_and_check(&name, bc, "5050\t2550\r\n");
     }
 
+    #[test]
+    fn for_generic() {
+        let code = "\
+            local t = {10, 20, 30, x = 1, y = 2}
+            local sum, count = 0, 0
+            for k, v in pairs(t) do
+                sum = sum + v
+                count = count + 1
+            end
+            print(sum, count)
+            for i, v in ipairs({'a', 'b'}) do
+                print(i, v)
+            end
+            local range = function(n)
+                local i = 0
+                return function()
+                    i = i + 1
+                    if i <= n then return i, i * i end
+                end
+            end
+            for i, square in range(2) do print(i, square) end
+            for i in range(10) do
+                if i > 2 then break end
+                print('early', i)
+            end
+            local step = function(limit, prev)
+                if prev < limit then return prev + 1 end
+            end
+            for a, b, c, d in step, 2, 0 do print(a, b, c, d) end
+            for i in ipairs(t) do
+                for j in range(2) do
+                    count = count + i * j
+                end
+            end
+            print(count)
+        "
+            .to_string();
+        let name = "for_generic".to_string();
+        let bc = Compiler::from_string(&code, &name);
+        run_and_check(
+            &name,
+            bc,
+            "63\t5\r\n1\ta\r\n2\tb\r\n1\t1\r\n2\t4\r\nearly\t1\r\nearly\t2\r\n\
+             1\tnil\tnil\tnil\r\n2\tnil\tnil\tnil\r\n23\r\n",
+        );
+    }
+
     #[test]
     fn build_table() {
         let code = "\
@@ -172,6 +314,47 @@ mod tests {
         run_and_check(&name, bc, "Ann\t18\t1\t2\t3\r\n");
     }
 
+    #[test]
+    fn concat() {
+        let code = "\
+            local name, n = 'x', 2
+            local s = name .. n .. '-' .. 1.5
+            local t = {k = 'v'}
+            s = s .. t.k
+            print(s, 1 + 2 .. 3, name .. name == 'xx', (n .. n) .. (name .. ''))
+            print(10 ^ 20 .. '', 1 / 3 .. '', #(s .. s))
+        "
+            .to_string();
+        let name = "concat".to_string();
+        let bc = Compiler::from_string(&code, &name);
+        run_and_check(&name, bc, "x2-1.5v\t33\ttrue\t22x\r\n1e+20\t0.33333333333333\t14\r\n");
+    }
+
+    #[test]
+    fn empty_string() {
+        let code = "local e = '' print(e == \"\", #e, e)".to_string();
+        let name = "empty_string".to_string();
+        let bc = Compiler::from_string(&code, &name);
+        run_and_check(&name, bc, "true\t0\t\r\n");
+    }
+
+    #[test]
+    fn table_reads_target() {
+        // the fields read the local the new table is assigned to
+        let code = "\
+            local t = {5}
+            t = {t[1], 2}
+            print(t[1], t[2])
+            local u = {5}
+            u = {x = u[1]}
+            print(u.x)
+        "
+            .to_string();
+        let name = "table_reads_target".to_string();
+        let bc = Compiler::from_string(&code, &name);
+        run_and_check(&name, bc, "5\t2\r\n5\r\n");
+    }
+
     #[test]
     fn func_play() {
         let code = "\
@@ -198,14 +381,25 @@ mod tests {
             end
 
             a = sub(add(3, 4), 4)
-            -- b = sub(3, add(3, 4))
-            -- c = sub(add(3, 4), add(1, 2))
+            b = sub(3, add(3, 4))
+            c = sub(add(3, 4), add(1, 2))
 
-            print(a)
+            print(a, b, c)
+            print(add(1, 2), sub(add(1, add(2, 3)), 4), add(sub(9, add(1, 1)), 1) * 2)
+            local t = {add(1, 1), sub(5, add(1, 1)), n = add(2, 2)}
+            print(t[1], t[2], t.n, add(add(add(add(1, 1), 1), 1), 1))
+            local s = {v = 10}
+            s.plus = function(self, x) return self.v + x end
+            print(s:plus(s:plus(add(1, 2))), sub(s:plus(1), 1), (add(1, 2)))
+            print(string.format('%s-%s', tostring(add(1, 2)), tostring(sub(1, 2))))
         ".to_string();
         let name = "two_function".to_string();
         let bc = Compiler::from_string(&code, &name);
-        run_and_check(&name, bc, "3\t-4\t4\r\n");
+        run_and_check(
+            &name,
+            bc,
+            "3\t-4\t4\r\n3\t2\t16\r\n2\t3\t4\t5\r\n23\t10\t3\r\n3--1\r\n",
+        );
     }
 
     #[test]
@@ -225,6 +419,523 @@ mod tests {
         let bc = Compiler::from_string(&code, &name);
         run_and_check(&name, bc, "7\t-1\r\n");
     }
+
+    #[test]
+    fn paren_truncate() {
+        let code = "\
+            pair = function()
+                return 1, 2
+            end
+            local a, b = pair()
+            local c, d = (pair())
+            local e = (2) * (a + b)
+            if (a == 1) then
+                print(a, b, c, d, e)
+            end
+        ".to_string();
+        let name = "paren_truncate".to_string();
+        let bc = Compiler::from_string(&code, &name);
+        run_and_check(&name, bc, "1\t2\t1\tnil\t6\r\n");
+    }
+
+    #[test]
+    fn prefix_chains() {
+        let code = "\
+            local t = { inner = { deep = {} } }
+            t.inner.deep.leaf = 3
+            t[1] = { m = function(self) return { x = 7 } end }
+            getobj = function() return t end
+            getobj().field = 5
+            make = function() return function() return 42 end end
+            local n = make()()
+            local x = t[1]:m().x
+            local up = ('s'):upper()
+            local leaf = getobj().inner.deep.leaf
+            print(t.field, leaf, n, x, up)
+        ".to_string();
+        let name = "prefix_chains".to_string();
+        let bc = Compiler::from_string(&code, &name);
+        run_and_check(&name, bc, "5\t3\t42\t7\tS\r\n");
+    }
+
+    #[test]
+    fn name_resolution() {
+        let code = "\
+            local x, y = 1, 2
+            f = function()
+                return function() return y + x + y end
+            end
+            local g = f()
+            local sum = g()
+            local k = 'a'
+            local t = { a = 10 }
+            print(sum, t[k], t.k)
+        ".to_string();
+        let name = "name_resolution".to_string();
+        let bc = Compiler::from_string(&code, &name);
+        run_and_check(&name, bc, "5\t10\tnil\r\n");
+    }
+
+    #[test]
+    fn block_scopes() {
+        let code = "\
+            local x = 1
+            if x == 1 then
+                local x = x + 1
+                inner = x
+                local c = 5
+                getc = function() return c end
+            end
+            local x = x * 10
+            local d = 6
+            local sum = 0
+            for i = 1, 3 do
+                local sq = i * i
+                sum = sum + sq
+            end
+            local c = getc()
+            print(inner, x, sum, c)
+        ".to_string();
+        let name = "block_scopes".to_string();
+        let bc = Compiler::from_string(&code, &name);
+        run_and_check(&name, bc, "2\t10\t14\t5\r\n");
+    }
+
+    #[test]
+    fn undefined_globals() {
+        let code = "\
+            local s = string.upper(\"ok\")
+            print(undefined_thing, s)
+            later = 1
+        ".to_string();
+        let name = "undefined_globals".to_string();
+        let bc = Compiler::from_string(&code, &name);
+        run_and_check(&name, bc, "nil\tOK\r\n");
+
+        let mut strict = CompileOptions {
+            strict_globals: true,
+            ..CompileOptions::default()
+        };
+        let err = Compiler::compile_with_options(&code, &name, strict.clone()).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "2:19: undefined global 'undefined_thing'"
+        );
+        let code = "print(string.upper(\"ok\"), later)\nlater = 1".to_string();
+        assert!(Compiler::compile_with_options(&code, &name, strict.clone()).is_ok());
+
+        // globals provided by the host
+        let code = "host.spawn(string.lower(\"X\"))".to_string();
+        let err = Compiler::compile_with_options(&code, &name, strict.clone()).unwrap_err();
+        assert_eq!(err.to_string(), "1:1: undefined global 'host'");
+        strict.globals.add_definitions("host.spawn").expect("Bad definitions");
+        assert!(Compiler::compile_with_options(&code, &name, strict).is_ok());
+    }
+    #[test]
+    fn constant_folding() {
+        let code = "\
+            local a, b, c = 3 * (1 + 1), '10' + 1, -(2)
+            local d, e = nil or 5, false and undefined_thing
+            if not (1 > 2) and 'a' < 'b' then
+                print(a, b, c, 1 / 0, d, e, not nil, 1 == '1')
+            end
+            if a ~= b then
+                a = 1
+            elseif true then
+                a = 0
+            else
+                a = 2
+            end
+            if false then a = 3 else print(a) end
+        ".to_string();
+        let name = "constant_folding".to_string();
+        let bc = Compiler::from_string(&code, &name);
+        run_and_check(&name, bc, "6\t11\t-2\tinf\t5\tfalse\ttrue\tfalse\r\n1\r\n");
+    }
+
+    #[test]
+    fn dead_code() {
+        let code = "\
+            local unused = function() return 1 end
+            local n = 0
+            while true do
+                n = n + 1
+                if n == 3 then break end
+            end
+            if false then print('never') elseif n > 2 then print(n) end
+            if true then local n = 'inner' print(n) else print('never') end
+            while false do print('never') end
+        ".to_string();
+        let name = "dead_code".to_string();
+        let (bc, warnings) =
+            Compiler::compile_with_warnings(&code, &name, CompileOptions::default())
+                .expect("Compiling failed");
+        run_and_check(&name, bc, "3\r\ninner\r\n");
+        assert_eq!(warnings.len(), 4);
+    }
+
+    #[test]
+    fn type_annotations() {
+        let typed = "\
+            local scale: number = 2
+            local point: {x: number, y: number} = {x = 1, y = 2}
+            local mul = function(a: number, b: number): number return a * b end
+            local names: {string} = {'a', 'b'}
+            print(mul(point.x, scale), names[2])
+        ".to_string();
+        let plain = "\
+            local scale = 2
+            local point = {x = 1, y = 2}
+            local mul = function(a, b) return a * b end
+            local names = {'a', 'b'}
+            print(mul(point.x, scale), names[2])
+        ".to_string();
+        let name = "type_annotations".to_string();
+        let options = CompileOptions {
+            typed: true,
+            ..CompileOptions::default()
+        };
+        let bc = Compiler::compile_with_options(&typed, &name, options.clone())
+            .expect("Compiling failed");
+        // annotations are erased
+        assert_eq!(bc, Compiler::from_string(&plain, &name));
+        run_and_check(&name, bc, "2\tb\r\n");
+
+        // plain Lua is not the typed dialect, but passes its checker
+        assert!(Compiler::compile(&typed, &name).is_err());
+        assert!(Compiler::compile_with_options(&plain, &name, options.clone()).is_ok());
+
+        let code = "local n: number = 'one'\nlocal s: string = mul(1)".to_string();
+        let err = Compiler::compile_with_options(&code, &name, options).unwrap_err();
+        assert_eq!(err.to_string(), "1:19: expected number, got string");
+    }
+
+    #[test]
+    fn unary_operators() {
+        let code = "\
+            local t, s, x = {1, 2, 3}, 'hello', 3
+            print(-x, -x ^ 2, 2 ^ -1, 2 ^ 3 ^ 2, #t, #s, -#t, - -x, x * -2)
+            local y, n = -x, #t + 1
+            print(y, n, -(x + 1), #'abc', 2 ^ 10, #{1, 2})
+        ".to_string();
+        let name = "unary_operators".to_string();
+        let bc = Compiler::from_string(&code, &name);
+        run_and_check(&name, bc, "-3\t-9\t0.5\t512\t3\t5\t-3\t3\t-6\r\n-3\t4\t-4\t3\t1024\t2\r\n");
+    }
+
+    #[test]
+    fn multiple_results() {
+        let code = "\
+            local three = function() return 1, 2, 3 end
+            local pass = function() return three() end
+            print(pass())
+            print(0, three())
+            print(three(), (three()))
+            local t, u, v = {three()}, {three(), three()}, {three(), x = 1}
+            print(#t, #u, #v, t[3])
+            local a, b, c = 0, three()
+            x, y, z = 9, three()
+            print(a, b, c, x, y, z)
+            local obj = {n = 5}
+            obj.get = function(self, k) return self.n, k end
+            print(obj:get(three()))
+            print(string.format('%d-%d-%d', three()))
+            local d, e
+            d, e = three()
+            print(d, e)
+            d, e = 5
+            print(d, e)
+            local spread = function(...)
+                local f, g = 0, 0
+                f, g = ...
+                return f, g
+            end
+            print(spread(7, 8), spread(7))
+        ".to_string();
+        let name = "multiple_results".to_string();
+        let bc = Compiler::from_string(&code, &name);
+        run_and_check(
+            &name,
+            bc,
+            "1\t2\t3\r\n0\t1\t2\t3\r\n1\t1\r\n3\t4\t1\t3\r\n0\t1\t2\t9\t1\t2\r\n5\t1\r\n1-2-3\r\n\
+             1\t2\r\n5\tnil\r\n7\t7\tnil\r\n",
+        );
+    }
+
+    #[test]
+    fn varargs() {
+        let code = "\
+            local count = function(...) return select('#', ...) end
+            local forward = function(first, ...) return count(first, ...) end
+            print(count(), count(nil, nil), forward(1, nil, 3, nil))
+            local show = function(...) print('args', ...) end
+            show(1, 2)
+            show()
+        ".to_string();
+        let name = "varargs".to_string();
+        let bc = Compiler::from_string(&code, &name);
+        run_and_check(&name, bc, "0\t2\t4\r\nargs\t1\t2\r\nargs\r\n");
+    }
+
+    #[test]
+    fn vararg_values() {
+        let code = "\
+            local id = function(...) return ... end
+            local pack = function(...) return {...} end
+            local t = pack(4, nil, 6)
+            print(id(1, 2, 3), select('#', id()), #pack(1, 2), t[1], t[3])
+            local split = function(...)
+                local a, b = ...
+                local c = ...
+                local u = {..., 9}
+                print(a, b, c, (...), u[1], u[2])
+                print(..., 2)
+            end
+            split(7, 8)
+            split()
+        ".to_string();
+        let name = "vararg_values".to_string();
+        let bc = Compiler::from_string(&code, &name);
+        run_and_check(
+            &name,
+            bc,
+            "1\t0\t2\t4\t6\r\n7\t8\t7\t7\t7\t9\r\n7\t2\r\n\
+             nil\tnil\tnil\tnil\tnil\t9\r\nnil\t2\r\n",
+        );
+    }
+
+    #[test]
+    fn vararg_outside_vararg() {
+        let name = "vararg_outside_vararg".to_string();
+        let code = "local f = function(a) print(...) end\nf(1, 2)".to_string();
+        let err = Compiler::compile(&code, &name).unwrap_err();
+        assert_eq!(err.to_string(), "1:29: cannot use '...' outside a vararg function");
+        let code = "local f = function(...) return function() return ... end end\nf()".to_string();
+        let err = Compiler::compile(&code, &name).unwrap_err();
+        assert_eq!(err.to_string(), "1:50: cannot use '...' outside a vararg function");
+    }
+
+    #[test]
+    fn method_calls() {
+        let code = "\
+            local obj = {n = 3}
+            obj.count = function(self) return self.n end
+            obj.grow = function(self, by)
+                if by == nil then by = 1 end
+                self.n = self.n + by
+                return self
+            end
+            obj.both = function(self) return self.n, self.n * 2 end
+            obj.has = function(self, k) return self[k] ~= nil end
+            local n = obj:count()
+            print(n, obj:grow():count(), obj:grow(2):grow():count())
+            local a, b = obj:both()
+            local t = {obj:both()}
+            print(a, b, #t, ('x'):rep(3):upper(), obj:count() + obj:count())
+            print(obj:both())
+            if obj:has('n') and not obj:has('m') then print('has n') end
+            local list = {items = {1, 2}, i = 0}
+            list.next = function(self) self.i = self.i + 1 return self.items[self.i] end
+            local total = 0
+            while list:next() do total = total + 1 end
+            if list.items[2] then print(total) end
+        ".to_string();
+        let name = "method_calls".to_string();
+        let bc = Compiler::from_string(&code, &name);
+        run_and_check(
+            &name,
+            bc,
+            "3\t4\t7\r\n7\t14\t2\tXXX\t14\r\n7\t14\r\nhas n\r\n2\r\n",
+        );
+    }
+
+    #[test]
+    fn closures_in_loops() {
+        let code = "\
+            local fs = {}
+            for i = 1, 3 do fs[i] = function() return i end end
+            print(fs[1](), fs[2](), fs[3]())
+            local gs, j = {}, 0
+            while j < 3 do
+                j = j + 1
+                local k = j * 10
+                gs[j] = function() k = k + 1 return k end
+            end
+            print(gs[1](), gs[1](), gs[2](), gs[3]())
+            local hs = {}
+            for idx, v in ipairs({'a', 'b', 'c'}) do
+                hs[idx] = function() return v end
+                if idx == 2 then break end
+            end
+            print(hs[1](), hs[2](), hs[3])
+            local ks = {}
+            for i = 1, 5 do
+                local square = i * i
+                ks[i] = function() return square end
+                if i == 3 then break end
+            end
+            local after, n = 99, 0
+            print(ks[1](), ks[2](), ks[3](), after)
+            while true do
+                for i = 1, 2 do n = n + i end
+                if n > 0 then
+                    local inner = n
+                    fs[4] = function() return inner end
+                end
+                break
+            end
+            local reused = 7
+            print(fs[4](), reused)
+        ".to_string();
+        let name = "closures_in_loops".to_string();
+        let bc = Compiler::from_string(&code, &name);
+        run_and_check(
+            &name,
+            bc,
+            "1\t2\t3\r\n11\t12\t21\t31\r\na\tb\tnil\r\n1\t4\t9\t99\r\n3\t7\r\n",
+        );
+    }
+
+    #[test]
+    fn tail_call() {
+        let code = "\
+            local loop
+            loop = function(n, acc)
+                local get = function() return n end
+                if n == 0 then return acc end
+                return loop(n - 1, acc + get())
+            end
+            print(loop(300000, 0))
+            even = function(n) if n == 0 then return true end return odd(n - 1) end
+            odd = function(n) if n == 0 then return false end return even(n - 1) end
+            print(even(100001), odd(100001))
+            local machine = {left = 200000}
+            machine.step = function(self, k)
+                if self.left == 0 then return 'stopped', k end
+                self.left = self.left - 1
+                return self:step(k + 1)
+            end
+            print(machine:step(0))
+            local pair = function() return 1, 2 end
+            local first = function() return (pair()) end
+            local both = function() return pair() end
+            print(first())
+            print(both())
+        ".to_string();
+        let name = "tail_call".to_string();
+        let bc = Compiler::from_string(&code, &name);
+        run_and_check(
+            &name,
+            bc,
+            "45000150000\r\nfalse\ttrue\r\nstopped\t200000\r\n1\r\n1\t2\r\n",
+        );
+    }
+
+    #[test]
+    fn upvalue_assignment() {
+        let code = "\
+            local make = function()
+                local count = 0
+                local inc = function() count = count + 1 return count end
+                local get = function() return count end
+                local reset = function()
+                    local deeper = function() count = 0 end
+                    deeper()
+                end
+                return inc, get, reset
+            end
+            local inc, get, reset = make()
+            local inc2, get2 = make()
+            inc() inc() inc2()
+            local a, b = get(), get2()
+            print(a, b)
+            reset()
+            a, b = inc(), get2()
+            print(a, b)
+            local cache, calls = {}, 0
+            local fib
+            fib = function(n)
+                local hit = cache[n]
+                if hit then return hit end
+                calls = calls + 1
+                local value = n
+                if n > 1 then value = fib(n - 1) + fib(n - 2) end
+                cache[n] = value
+                return value
+            end
+            a = fib(30)
+            print(a, calls)
+        ".to_string();
+        let name = "upvalue_assignment".to_string();
+        let bc = Compiler::from_string(&code, &name);
+        run_and_check(&name, bc, "2\t1\r\n1\t1\r\n832040\t31\r\n");
+    }
+
+    #[test]
+    fn many_statements() {
+        let mut code = "local zero, one = 0, 1\nb = zero\n".to_string();
+        for i in 0..2000 {
+            code.push_str("b = b + one\n");
+            if i % 2 == 0 {
+                code.push_str("if b then local l = {b, b * one, k = b} t = l end\n");
+            } else {
+                code.push_str("t = {b, k = b}\n");
+            }
+            code.push_str("if b > zero then local x, y = b t = {x, y, k = t.k} end\n");
+            code.push_str("while b < zero do local q = b end\n");
+        }
+        code.push_str("local c, d = nil\nprint(b, t.k, t[1], t[2], c, d)\n");
+        let name = "many_statements".to_string();
+        let bc = Compiler::from_string(&code, &name);
+        run_and_check(&name, bc, "2000\t2000\t2000\tnil\tnil\tnil\r\n");
+    }
+
+    #[test]
+    fn large_table() {
+        // 2001 batches of SETLIST, the last ones do not fit C
+        let items = (0..100_000)
+            .map(|i| if i % 2 == 0 { "false," } else { "{true, false}," })
+            .collect::<String>();
+        let code = format!(
+            "local three = function() return 1, 2, 3 end\n\
+             local t = {{{}three()}}\n\
+             print(#t, t[1], t[2][2], t[100000][1], t[100003])\n",
+            items
+        );
+        let name = "large_table".to_string();
+        let bc = Compiler::from_string(&code, &name);
+        run_and_check(&name, bc, "100003\tfalse\tfalse\ttrue\t3\r\n");
+    }
+
+    #[test]
+    fn many_constants() {
+        // every key and value past the first 255 constants is loaded into a register
+        let fields = (0..1000).map(|i| format!("k{} = {},", i, i)).collect::<String>();
+        let code = format!(
+            "local t = {{{}}}\n\
+             t.k998 = t.k998 * 2\n\
+             t.later = function(self, x) return self[\"k10\"] + x end\n\
+             if t.k700 == 700 and t.k999 > 998.5 then print(t:later(1000.5), t.k998) end\n\
+             local z, nz = 0, -0\n\
+             print(1 / z, 1 / nz)\n",
+            fields
+        );
+        let name = "many_constants".to_string();
+        let bc = Compiler::from_string(&code, &name);
+        run_and_check(&name, bc, "1010.5\t1996\r\ninf\t-inf\r\n");
+    }
+
+    #[test]
+    fn implementation_limits() {
+        let name = "implementation_limits".to_string();
+        let locals = (0..201).map(|i| format!("local a{} = {}\n", i, i)).collect::<String>();
+        let err = Compiler::compile(&locals, &name).unwrap_err();
+        assert_eq!(err.to_string(), "201:1: main function has more than 200 local variables");
+        let nested = format!("a = {}1{}", "(".repeat(300), ")".repeat(300));
+        let err = Compiler::compile(&nested, &name).unwrap_err();
+        assert_eq!(err.to_string(), "1:204: main function is nested more than 200 levels deep");
+    }
+
     // FIXME: Parser do not report error for
     // function(a, b)
     // a + b
@@ -0,0 +1,173 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::path::Path;
+
+/// globals and library fields of Lua 5.1
+const STD_GLOBALS: &str = "
+    _G _VERSION assert collectgarbage dofile error gcinfo getfenv getmetatable
+    ipairs load loadfile loadstring module next newproxy pairs pcall print
+    rawequal rawget rawset require select setfenv setmetatable tonumber
+    tostring type unpack xpcall
+
+    coroutine.create coroutine.resume coroutine.running coroutine.status
+    coroutine.wrap coroutine.yield
+
+    debug.debug debug.getfenv debug.gethook debug.getinfo debug.getlocal
+    debug.getmetatable debug.getregistry debug.getupvalue debug.setfenv
+    debug.sethook debug.setlocal debug.setmetatable debug.setupvalue
+    debug.traceback
+
+    io.close io.flush io.input io.lines io.open io.output io.popen io.read
+    io.stderr io.stdin io.stdout io.tmpfile io.type io.write
+
+    math.abs math.acos math.asin math.atan math.atan2 math.ceil math.cos
+    math.cosh math.deg math.exp math.floor math.fmod math.frexp math.huge
+    math.ldexp math.log math.log10 math.max math.min math.mod math.modf
+    math.pi math.pow math.rad math.random math.randomseed math.sin math.sinh
+    math.sqrt math.tan math.tanh
+
+    os.clock os.date os.difftime os.execute os.exit os.getenv os.remove
+    os.rename os.setlocale os.time os.tmpname
+
+    package.config package.cpath package.loaded package.loaders
+    package.loadlib package.path package.preload package.seeall
+
+    string.byte string.char string.dump string.find string.format string.gfind
+    string.gmatch string.gsub string.len string.lower string.match string.rep
+    string.reverse string.sub string.upper
+
+    table.concat table.foreach table.foreachi table.getn table.insert
+    table.maxn table.remove table.setn table.sort
+";
+
+/// globals a program may read without assigning them,
+/// by default the standard library of Lua 5.1
+///
+/// definitions are written as `name` or `name.field`, separated by
+/// whitespace, `--` starts a comment to the end of the line.
+/// a global with fields declared is a table without other fields,
+/// fields of any other global are not checked
+#[derive(Clone, Debug, PartialEq)]
+pub struct Globals {
+    /// known fields of each global, empty if they are not checked
+    names: HashMap<String, HashSet<String>>,
+}
+
+#[derive(Debug)]
+pub enum GlobalsError {
+    Io(io::Error),
+    /// line and text of a definition that is not `name` or `name.field`
+    BadDefinition(usize, String),
+}
+
+impl Globals {
+    /// no globals at all
+    pub fn empty() -> Globals {
+        Globals {
+            names: HashMap::new(),
+        }
+    }
+
+    pub fn std() -> Globals {
+        let mut globals = Globals::empty();
+        globals.add_definitions(STD_GLOBALS).expect("Bad std globals");
+        globals
+    }
+
+    /// std globals and the definitions of each file
+    pub fn with_files<P: AsRef<Path>>(paths: &[P]) -> Result<Globals, GlobalsError> {
+        let mut globals = Globals::std();
+        for path in paths {
+            globals.load_file(path)?;
+        }
+        Ok(globals)
+    }
+
+    pub fn load_file<P: AsRef<Path>>(&mut self, path: P) -> Result<(), GlobalsError> {
+        let mut text = String::new();
+        File::open(path)
+            .and_then(|mut file| file.read_to_string(&mut text))
+            .map_err(GlobalsError::Io)?;
+        self.add_definitions(&text)
+    }
+
+    pub fn add_definitions(&mut self, text: &str) -> Result<(), GlobalsError> {
+        for (i, line) in text.lines().enumerate() {
+            let line = line.split("--").next().unwrap_or("");
+            for definition in line.split_whitespace() {
+                let mut parts = definition.splitn(2, '.');
+                let name = parts.next().unwrap_or("");
+                let field = parts.next();
+                if !is_name(name) || !field.into_iter().all(is_name) {
+                    return Err(GlobalsError::BadDefinition(i + 1, definition.to_string()));
+                }
+                let fields = self.names.entry(name.to_string()).or_default();
+                if let Some(field) = field {
+                    fields.insert(field.to_string());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.names.contains_key(name)
+    }
+
+    /// false only if name is a global table without such field
+    pub fn has_field(&self, name: &str, field: &str) -> bool {
+        match self.names.get(name) {
+            Some(fields) => fields.is_empty() || fields.contains(field),
+            None => true,
+        }
+    }
+}
+
+impl Default for Globals {
+    fn default() -> Globals {
+        Globals::std()
+    }
+}
+
+fn is_name(text: &str) -> bool {
+    let mut chars = text.chars();
+    match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' => {
+            chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn std_globals() {
+        let globals = Globals::std();
+        assert!(globals.contains("pairs") && globals.contains("string"));
+        assert!(!globals.contains("foo"));
+        assert!(globals.has_field("string", "format"));
+        assert!(!globals.has_field("string", "split"));
+        assert!(globals.has_field("_G", "anything"));
+    }
+
+    #[test]
+    fn user_definitions() {
+        let mut globals = Globals::std();
+        globals
+            .add_definitions("-- host api\nspawn  love.graphics\nstring.split -- extension\n")
+            .expect("Bad definitions");
+        assert!(globals.contains("spawn") && globals.contains("love"));
+        assert!(globals.has_field("love", "graphics") && !globals.has_field("love", "audio"));
+        assert!(globals.has_field("string", "split") && globals.has_field("string", "len"));
+        match globals.add_definitions("ok\nnot-a-name") {
+            Err(GlobalsError::BadDefinition(2, ref text)) => assert_eq!(text, "not-a-name"),
+            other => panic!("Unexpected result {:?}", other),
+        }
+        assert!(Globals::with_files(&["no/such/file"]).is_err());
+    }
+}
@@ -1,37 +1,50 @@
 use self::opcodes::*;
-use self::symbol_table::*;
+use self::resolver::*;
 use self::resource_allocator::*;
 use self::types::*;
 use parser::types::*;
-use lexer::tokens::FlagType;
+use lexer::tokens::{FlagType, Location};
 use std::collections::HashMap;
-use std::ptr;
 use std::cmp;
 
-pub mod symbol_table;
+pub mod resolver;
 pub mod resource_allocator;
 pub mod opcodes;
 pub mod types;
 
 #[derive(Debug)]
-pub struct IRGen {
-    symbol_table: ScopedSymbolTableBuilder,
+pub struct IRGen<'a> {
+    ast: &'a Ast,
+    options: CompileOptions,
+    resolution: Resolution,
+    /// register of each local, set when it is declared
+    local_regs: Vec<Option<Usize>>,
     flag_to_op: HashMap<FlagType, OpName>,
     root_function: FunctionChunk,
 }
 
 // public interface
-impl IRGen {
-    pub fn new() -> IRGen {
+impl<'a> IRGen<'a> {
+    pub fn new(ast: &'a Ast) -> IRGen<'a> {
+        IRGen::with_options(ast, CompileOptions::default())
+    }
+
+    pub fn with_options(ast: &'a Ast, options: CompileOptions) -> IRGen<'a> {
         IRGen {
-            symbol_table: ScopedSymbolTableBuilder::new(),
+            ast: ast,
+            options,
+            resolution: Resolution::new(),
+            local_regs: vec![],
             flag_to_op: get_opflag_opname_map(),
             root_function: FunctionChunk::new(),
         }
     }
 
-    pub fn generate_ir(&mut self, ast: &Node) -> Result<(), CompileError> {
-        self.visit_unit(ast)
+    pub fn generate_ir(&mut self) -> Result<(), CompileError> {
+        self.resolution = Resolver::resolve_with_options(self.ast, &self.options)?;
+        self.local_regs = vec![None; self.resolution.local_count()];
+        let root = self.ast.root;
+        self.visit_unit(root)
     }
 
     pub fn get_chunk(&mut self, src_name: &str) -> &FunctionChunk {
@@ -41,46 +54,44 @@ impl IRGen {
 }
 
 // visit method
-impl IRGen {
-    fn visit_unit(&mut self, node: &Node) -> Result<(), CompileError> {
+impl<'a> IRGen<'a> {
+    fn visit_unit(&mut self, node: Node) -> Result<(), CompileError> {
         // TODO: add header
         // root block should not have retstat
         // is_vararg (always 2 for top level function )
-        if let Node::Block(ref block) = *node {
-            self.visit_function(block, None, &vec![], true)
-                .map(|func| self.root_function = func.prototype)
+        if let Node::Block(block) = node {
+            self.visit_function(block, true, None)
+                .map(|func| self.root_function = func)
         } else {
             // can not find entry block
             Err(CompileError::SyntexError)
         }
     }
-    /// visit function body, ret: upvalue_num, prototype
-    /// assuming scope is newly initiated
+    /// visit function body, ret: prototype
+    /// start: where the function is defined, None for the main chunk
     fn visit_function(
         &mut self,
-        block: &Block,
-        parent_alloc: Option<&mut ResourceAlloc>,
-        paras: &Vec<Name>,
+        block: BlockId,
         is_vararg: bool,
-    ) -> Result<FunctionPrototype, CompileError> {
-        let mut res_alloc = ResourceAlloc::new().parent(if parent_alloc.is_some() {
-            parent_alloc.unwrap() as *mut ResourceAlloc
-        } else {
-            ptr::null_mut()
-        });
+        start: Option<Location>,
+    ) -> Result<FunctionChunk, CompileError> {
+        let mut res_alloc = ResourceAlloc::new(start.map(|start| start.line));
+        res_alloc.is_vararg = is_vararg;
         let mut instructions = Vec::<OpMode>::new();
         let mut func_chunk = FunctionChunk::new();
         //  define parameters and reserve registers
-        for name in paras {
-            let pos = res_alloc.reg_alloc.push(Some(name));
-            self.symbol_table.define_local(name, pos);
+        let paras = self.resolution.declared(DeclSite::Params(block)).to_vec();
+        for &id in &paras {
+            let pos = res_alloc.reg_alloc.push();
+            self.bind_local(id, pos);
         }
         //  visit body instuctions
-        self.visit_block(block, &mut res_alloc, &mut instructions)?;
+        self.visit_body(block, &mut res_alloc, &mut instructions)?;
         // add a return, may be redundant
         IRGen::emit_iABC(&mut instructions, OpName::RETURN, 0, 1, 0);
+        IRGen::check_jumps(&instructions, &res_alloc)?;
         // number of upvalues
-        func_chunk.upvalue_num = res_alloc.upvalue_alloc.size() as Usize;
+        func_chunk.upvalue_num = self.resolution.upvalues(block).len() as Usize;
         // number of parameters
         func_chunk.para_num = paras.len() as Usize;
         func_chunk.is_vararg = is_vararg;
@@ -96,34 +107,76 @@ impl IRGen {
         // list of function prototypes
         func_chunk.funclist_len = res_alloc.function_alloc.size() as Usize;
         func_chunk.function_prototypes = res_alloc.function_alloc.get_function_prototypes();
-        Ok(FunctionPrototype::new(
-            func_chunk,
-            res_alloc.upvalue_alloc.into_list(),
-        ))
+        Ok(func_chunk)
     }
 
-    /// ret: number of returned
-    /// None means no return stat
+    /// a block nested in a function,
+    /// the upvalues of its captured locals are closed when it ends
     fn visit_block(
         &mut self,
-        block: &Block,
+        block: BlockId,
         res_alloc: &mut ResourceAlloc,
         instructions: &mut Vec<OpMode>,
     ) -> Result<(), CompileError> {
-        for stat in &block.stats {
-            self.visit_stat(stat, res_alloc, instructions)?;
+        let ast = self.ast;
+        let top = res_alloc.reg_alloc.top();
+        self.visit_body(block, res_alloc, instructions)?;
+        // RETURN closes them itself, loop variables are closed by the loop
+        let captured = ast[block].stats.iter().enumerate().any(|(i, stat)| match *stat {
+            Stat::AssignLocal(..) => self.captures_at(DeclSite::Stat(block, i)),
+            _ => false,
+        });
+        if captured && ast[block].ret.is_none() {
+            IRGen::emit_iABC(instructions, OpName::CLOSE, top, 0, 0);
+        }
+        Ok(())
+    }
+
+    /// statements of a block, or the body of a function
+    fn visit_body(
+        &mut self,
+        block: BlockId,
+        res_alloc: &mut ResourceAlloc,
+        instructions: &mut Vec<OpMode>,
+    ) -> Result<(), CompileError> {
+        let ast = self.ast;
+        let top = res_alloc.reg_alloc.top();
+        let stats = &ast[block].stats;
+        for (i, stat) in stats.iter().enumerate() {
+            let location = ast[block].locations[i];
+            res_alloc.stat_starts.push((instructions.len(), location));
+            let stat_top = res_alloc.reg_alloc.top();
+            res_alloc.stat_top = stat_top;
+            self.visit_stat(stat, DeclSite::Stat(block, i), res_alloc, instructions)?;
+            IRGen::check_limits(res_alloc, location)?;
+            // temporaries of the statement are dead, the locals it declares are not
+            let live = match *stat {
+                Stat::AssignLocal(ref names, _) => stat_top + names.len() as Usize,
+                _ => stat_top,
+            };
+            res_alloc.reg_alloc.release_to(live);
         }
         // if ret statement exists
-        if let Some(ref ret_exprs) = block.ret {
-            self.visit_stat(&Stat::Ret(ret_exprs.clone()), res_alloc, instructions)?;
+        if let Some(ref ret_exprs) = ast[block].ret {
+            let site = DeclSite::Stat(block, stats.len());
+            res_alloc.stat_top = res_alloc.reg_alloc.top();
+            self.visit_stat(&Stat::Ret(ret_exprs.clone()), site, res_alloc, instructions)?;
+            // an empty return uses no registers or constants
+            if let Some(&expr) = ret_exprs.first() {
+                IRGen::check_limits(res_alloc, ast.location(expr))?;
+            }
         }
+        // locals of the block are dead, their registers can be reused
+        res_alloc.reg_alloc.release_to(top);
         Ok(())
     }
 
     /// ret:
+    /// site: where locals declared by the statement are looked up
     fn visit_stat(
         &mut self,
         stat: &Stat,
+        site: DeclSite,
         res_alloc: &mut ResourceAlloc,
         instructions: &mut Vec<OpMode>,
     ) -> Result<(), CompileError> {
@@ -138,93 +191,107 @@ impl IRGen {
                 //       loadnill should be performed at the end
                 let (varlist, exprlist) =
                     self.adjust_list(varlist, exprlist, res_alloc, instructions)?;
-                for (ref var, ref expr) in varlist.into_iter().zip(exprlist.into_iter()) {
-                    match *var {
-                        Var::Name(ref name) => {
-                            // lookup , confirm if symbol is already defined
-                            match self.symbol_table.lookup(name) {
-                                Some((SymbolScope::Global, _)) | None => {
-                                    let const_pos = self.prepare_global_value(name, res_alloc); /* add name to const list and define global symbol */
-                                    let (_, reg) =
-                                        self.visit_r_expr(expr, res_alloc, instructions, None)?;
-                                    IRGen::emit_iABx(
-                                        instructions,
-                                        OpName::SETGLOBAL,
-                                        reg,
-                                        const_pos,
-                                    );
-                                }
-                                Some((SymbolScope::Local, pos)) => {
-                                    self.visit_r_expr(
-                                        expr,
-                                        res_alloc,
-                                        instructions,
-                                        Some(Expect::Reg(pos)),
-                                    )?;
-                                }
-                                Some((SymbolScope::UpValue(_), _)) => unimplemented!(),
+                for (var, expr) in varlist.into_iter().zip(exprlist.into_iter()) {
+                    match self.ast[var] {
+                        Expr::Var(Var::Name(name)) => match self.resolution.lookup(var) {
+                            Some(Resolved::Global) => {
+                                let const_pos = self.prepare_global_value(name, res_alloc);
+                                let (_, reg) =
+                                    self.visit_r_operand(expr, res_alloc, instructions, None)?;
+                                IRGen::emit_iABx(
+                                    instructions,
+                                    OpName::SETGLOBAL,
+                                    reg,
+                                    const_pos,
+                                );
                             }
+                            Some(Resolved::Local(id)) => {
+                                let pos = self.local_reg(id)?;
+                                self.visit_r_operand(
+                                    expr,
+                                    res_alloc,
+                                    instructions,
+                                    Some(Expect::Reg(pos)),
+                                )?;
+                            }
+                            Some(Resolved::UpValue(upvalue_pos)) => {
+                                let (_, reg) =
+                                    self.visit_r_operand(expr, res_alloc, instructions, None)?;
+                                IRGen::emit_iABC(
+                                    instructions,
+                                    OpName::SETUPVAL,
+                                    reg,
+                                    upvalue_pos,
+                                    0,
+                                );
+                            }
+                            None => return Err(CompileError::UndefinedSymbol),
+                        },
+                        _ => {
+                            let (_, value_creg) = match expr {
+                                ExprOrReg::Expr(expr) => {
+                                    self.reg_constid_merge(expr, res_alloc, instructions, None)?
+                                }
+                                ExprOrReg::Reg(reg) => (true, reg),
+                            };
+                            self.visit_l_expr(var, value_creg, res_alloc, instructions)?;
                         }
-                        Var::PrefixExp(ref prefix_expr) => {
-                            let (_, value_creg) =
-                                self.reg_constid_merge(expr, res_alloc, instructions, None)?;
-                            self.visit_l_expr(prefix_expr, value_creg, res_alloc, instructions)?;
-                        }
-                        _ => unimplemented!(),
                     }
                 }
                 Ok(())
             }
             // bind to new local
-            Stat::AssignLocal(ref namelist, ref exprlist) => {
-                let (namelist, exprlist) =
-                    self.adjust_list(namelist, exprlist, res_alloc, instructions)?;
-                let reg_list = self.visit_r_exprlist(&exprlist, res_alloc, instructions)?;
-                for (ref name, (is_temp, expr_reg)) in
-                    namelist.into_iter().zip(reg_list.into_iter())
-                {
-                    if is_temp {
-                        res_alloc.reg_alloc.push_set(name, expr_reg);
-                        self.symbol_table.define_local(name, expr_reg);
-                    } else {
-                        let pos = res_alloc.reg_alloc.push(Some(name));
-                        self.symbol_table.define_local(name, pos);
-                        IRGen::emit_iABC(instructions, OpName::MOVE, pos, expr_reg, 0);
-                    }
+            Stat::AssignLocal(_, ref exprlist) => {
+                let ids = self.resolution.declared(site).to_vec();
+                // the values are left on top, where the locals live from now on
+                let base = self.visit_values_to_top(ids.len(), exprlist, res_alloc, instructions)?;
+                for (reg, id) in (base..).zip(ids) {
+                    self.bind_local(id, reg);
                 }
                 Ok(())
             }
             Stat::Ret(ref exprlist) => {
-                // first: allocate a chunk of conjective registers
-                let reg_list = (0..exprlist.len())
-                    .map(|_| res_alloc.reg_alloc.push(None))
-                    .collect::<Vec<_>>();
+                // a call at the end returns all its results
+                let open = exprlist.last().map_or(false, |&expr| self.is_multi_value(expr));
+                let fixed = if open { exprlist.len() - 1 } else { exprlist.len() };
                 // if is void return, start_register is not needed
-                let ret_num = reg_list.len(); // save moved value
-                let start_register = if ret_num > 0 { reg_list[0] } else { 0 };
-                // second: visit each expr with expect return register
-                for (expr, reg) in exprlist.into_iter().zip(reg_list.into_iter()) {
-                    try!(self.visit_r_expr(expr, res_alloc, instructions, Some(Expect::Reg(reg))));
+                let start_register = if exprlist.is_empty() {
+                    0
+                } else {
+                    res_alloc.reg_alloc.top()
+                };
+                // values are placed in conjective registers
+                for &expr in &exprlist[..fixed] {
+                    let reg = res_alloc.reg_alloc.push();
+                    self.visit_r_expr(expr, res_alloc, instructions, Some(Expect::Reg(reg)))?;
+                }
+                if open {
+                    // a lone call is a tail call, its frame replaces the current one
+                    let last = exprlist[fixed];
+                    let expect_ret = match self.ast[last] {
+                        Expr::GeneralCall(..) | Expr::ColonCall(..) if fixed == 0 => {
+                            RetExpect::Tail
+                        }
+                        _ => RetExpect::Indeterminate,
+                    };
+                    self.visit_multi_value(last, expect_ret, res_alloc, instructions)?;
                 }
                 // return statement
+                // if B == 0, return R(start_register) up to the top
                 // if B == 1, no expr returned
                 // if B >= 1 return R(start_register) .. R(start_register + B - 2)
-                IRGen::emit_iABC(
-                    instructions,
-                    OpName::RETURN,
-                    start_register,
-                    (ret_num + 1) as u32,
-                    0,
-                );
+                let ret_field = if open { 0 } else { fixed as u32 + 1 };
+                IRGen::emit_iABC(instructions, OpName::RETURN, start_register, ret_field, 0);
                 Ok(())
             }
-            Stat::IfElse(ref test_expr, ref then_block, ref else_block) => {
+            Stat::IfElse(test_expr, then_block, else_block) => {
                 // if then else
-                if let &Some(ref else_block) = else_block {
+                if let Some(else_block) = else_block {
                     let then_label = res_alloc.label_alloc.new_label();
                     let else_label = res_alloc.label_alloc.new_label();
                     let next_label = res_alloc.label_alloc.new_label();
                     // jmp is not needed for then_block
+                    let top = res_alloc.reg_alloc.top();
                     let mut raw = self.visit_boolean_expr(
                         test_expr,
                         res_alloc,
@@ -232,6 +299,7 @@ impl IRGen {
                         else_label,
                         false,
                     )?;
+                    res_alloc.reg_alloc.release_to(top);
                     raw.push(OpMode::Label(then_label));
                     self.visit_block(then_block, res_alloc, &mut raw)?;
                     raw.push(OpMode::rJMP(next_label));
@@ -244,6 +312,7 @@ impl IRGen {
                     // if else
                     let then_label = res_alloc.label_alloc.new_label();
                     let next_label = res_alloc.label_alloc.new_label();
+                    let top = res_alloc.reg_alloc.top();
                     let mut raw = self.visit_boolean_expr(
                         test_expr,
                         res_alloc,
@@ -251,6 +320,7 @@ impl IRGen {
                         next_label,
                         false,
                     )?;
+                    res_alloc.reg_alloc.release_to(top);
                     raw.push(OpMode::Label(then_label));
                     self.visit_block(then_block, res_alloc, &mut raw)?;
                     raw.push(OpMode::Label(next_label));
@@ -259,13 +329,15 @@ impl IRGen {
                 }
                 Ok(())
             }
-            Stat::While(ref test_expr, ref do_block) => {
+            Stat::While(test_expr, do_block) => {
                 let begin_label = res_alloc.label_alloc.new_label();
                 let do_label = res_alloc.label_alloc.new_label();
                 let next_label = res_alloc.label_alloc.new_label();
-                // set exit for Break stat
-                res_alloc.set_loop_exit(next_label);
                 let mut raw = vec![OpMode::Label(begin_label)];
+                let top = res_alloc.reg_alloc.top();
+                // set exit for Break stat
+                let close = if self.captures_in(do_block) { Some(top) } else { None };
+                res_alloc.set_loop_exit(next_label, close);
                 raw.append(&mut self.visit_boolean_expr(
                     test_expr,
                     res_alloc,
@@ -273,6 +345,7 @@ impl IRGen {
                     next_label,
                     false,
                 )?);
+                res_alloc.reg_alloc.release_to(top);
                 raw.push(OpMode::Label(do_label));
                 self.visit_block(do_block, res_alloc, &mut raw)?;
                 raw.push(OpMode::rJMP(begin_label));
@@ -283,27 +356,37 @@ impl IRGen {
                 instructions.append(&mut raw);
                 Ok(())
             }
-            Stat::ForNumeric(ref name, ref start, ref end, ref step, ref block) => {
+            Stat::ForNumeric(_, start, end, step, block) => {
                 let block_label = res_alloc.label_alloc.new_label();
                 let test_label = res_alloc.label_alloc.new_label();
                 let next_label = res_alloc.label_alloc.new_label();
                 let mut raw = vec![];
                 // four adjacent registers
-                let start_reg = res_alloc.reg_alloc.push(None);
-                let end_reg = res_alloc.reg_alloc.push(None);
-                let step_reg = res_alloc.reg_alloc.push(None);
+                let start_reg = res_alloc.reg_alloc.push();
+                let end_reg = res_alloc.reg_alloc.push();
+                let step_reg = res_alloc.reg_alloc.push();
                 //      exposed as local
-                let local_reg = res_alloc.reg_alloc.push(Some(name));
-                self.symbol_table.define_local(name, local_reg);
+                let local_reg = res_alloc.reg_alloc.push();
+                let id = self.resolution.declared(site)[0];
+                self.bind_local(id, local_reg);
                 self.visit_r_expr(start, res_alloc, &mut raw, Some(Expect::Reg(start_reg)))?;
                 self.visit_r_expr(end, res_alloc, &mut raw, Some(Expect::Reg(end_reg)))?;
                 self.visit_r_expr(step, res_alloc, &mut raw, Some(Expect::Reg(step_reg)))?;
                 raw.push(OpMode::rForPrep(start_reg, test_label));
                 raw.push(OpMode::Label(block_label));
                 // set exit
-                res_alloc.set_loop_exit(next_label);
+                let close = if self.captures_at(site) || self.captures_in(block) {
+                    Some(local_reg)
+                } else {
+                    None
+                };
+                res_alloc.set_loop_exit(next_label, close);
                 self.visit_block(block, res_alloc, &mut raw)?;
                 res_alloc.clear_loop_exit();
+                // each iteration has its own loop variable
+                if self.captures_at(site) {
+                    IRGen::emit_iABC(&mut raw, OpName::CLOSE, local_reg, 0, 0);
+                }
                 raw.push(OpMode::Label(test_label));
                 raw.push(OpMode::rForLoop(start_reg, block_label));
                 raw.push(OpMode::Label(next_label));
@@ -311,28 +394,73 @@ impl IRGen {
                 instructions.append(&mut raw);
                 Ok(())
             }
+            Stat::ForRange(_, ref exprlist, block) => {
+                let block_label = res_alloc.label_alloc.new_label();
+                let test_label = res_alloc.label_alloc.new_label();
+                let next_label = res_alloc.label_alloc.new_label();
+                let mut raw = vec![];
+                // generator, state and control, adjusted to three values
+                let base = self.visit_values_to_top(3, exprlist, res_alloc, &mut raw)?;
+                //      exposed as locals
+                let ids = self.resolution.declared(site).to_vec();
+                for &id in &ids {
+                    let reg = res_alloc.reg_alloc.push();
+                    self.bind_local(id, reg);
+                }
+                // TFORLOOP copies the first three above them to call the generator
+                let top = res_alloc.reg_alloc.top();
+                for _ in ids.len()..3 {
+                    res_alloc.reg_alloc.push();
+                }
+                res_alloc.reg_alloc.release_to(top);
+                raw.push(OpMode::rJMP(test_label));
+                raw.push(OpMode::Label(block_label));
+                // set exit
+                let close = if self.captures_at(site) || self.captures_in(block) {
+                    Some(base + 3)
+                } else {
+                    None
+                };
+                res_alloc.set_loop_exit(next_label, close);
+                self.visit_block(block, res_alloc, &mut raw)?;
+                res_alloc.clear_loop_exit();
+                // each iteration has its own loop variables
+                if self.captures_at(site) {
+                    IRGen::emit_iABC(&mut raw, OpName::CLOSE, base + 3, 0, 0);
+                }
+                raw.push(OpMode::Label(test_label));
+                // skips the jump back once the first value is nil
+                IRGen::emit_iABC(&mut raw, OpName::TFORLOOP, base, 0, ids.len() as u32);
+                raw.push(OpMode::rJMP(block_label));
+                raw.push(OpMode::Label(next_label));
+
+                instructions.append(&mut raw);
+                Ok(())
+            }
             Stat::Break => {
-                if let Some(exit_label) = res_alloc.get_loop_exit() {
-                    instructions.push(OpMode::rJMP(exit_label));
+                if let Some(exit) = res_alloc.get_loop_exit() {
+                    // captured locals of the loop are closed on the way out
+                    if let Some(reg) = exit.close {
+                        IRGen::emit_iABC(instructions, OpName::CLOSE, reg, 0, 0);
+                    }
+                    instructions.push(OpMode::rJMP(exit.label));
                     Ok(())
                 } else {
                     Err(CompileError::SyntexError)
                 }
             }
-            Stat::GeneralCall(ref func_name, ref args, is_vararg) => self.visit_general_call(
+            Stat::GeneralCall(func_name, ref args) => self.visit_general_call(
                 func_name,
                 args,
-                is_vararg,
                 RetExpect::Num(0),
                 res_alloc,
                 instructions,
             ).and(Ok(())),
-            Stat::ColonCall(ref table_expr, ref func_name, ref args, is_vararg) => {
+            Stat::ColonCall(table_expr, func_name, ref args) => {
                 self.visit_colon_call(
                     table_expr,
                     func_name,
                     args,
-                    is_vararg,
                     RetExpect::Num(0),
                     res_alloc,
                     instructions,
@@ -346,19 +474,36 @@ impl IRGen {
     /// expect: where the result should be stored or how many result should be returned
     fn visit_r_expr(
         &mut self,
-        expr: &Expr,
+        expr: ExprId,
+        res_alloc: &mut ResourceAlloc,
+        instructions: &mut Vec<OpMode>,
+        expect: Option<Expect>,
+    ) -> Result<(bool, Usize), CompileError> {
+        let top = res_alloc.reg_alloc.top();
+        let (is_temp, reg) = self.visit_r_value(expr, res_alloc, instructions, expect)?;
+        // temporaries of subexpressions are dead once the value is computed,
+        // a value computed in a new register is the lowest of them
+        let live = if reg >= top { reg + 1 } else { top };
+        res_alloc.reg_alloc.release_to(live);
+        Ok((is_temp, reg))
+    }
+
+    fn visit_r_value(
+        &mut self,
+        expr: ExprId,
         res_alloc: &mut ResourceAlloc,
         instructions: &mut Vec<OpMode>,
         expect: Option<Expect>,
     ) -> Result<(bool, Usize), CompileError> {
-        match *expr {
+        let ast = self.ast;
+        match ast[expr] {
             Expr::Nil => {
                 let reg = if let Some(expect) = extract_expect_reg(expect)? {
                     expect
                 } else {
-                    res_alloc.reg_alloc.push(None)
+                    res_alloc.reg_alloc.push()
                 };
-                IRGen::emit_iABx(instructions, OpName::LOADNIL, reg, reg);
+                IRGen::emit_iABC(instructions, OpName::LOADNIL, reg, reg, 0);
                 Ok((true, reg))
             }
             Expr::Num(num) => {
@@ -366,7 +511,7 @@ impl IRGen {
                 let reg = if let Some(expect) = extract_expect_reg(expect)? {
                     expect
                 } else {
-                    res_alloc.reg_alloc.push(None)
+                    res_alloc.reg_alloc.push()
                 };
                 IRGen::emit_iABx(instructions, OpName::LOADK, reg, const_pos);
                 Ok((true, reg))
@@ -376,26 +521,28 @@ impl IRGen {
                 let reg = if let Some(expect) = extract_expect_reg(expect)? {
                     expect
                 } else {
-                    res_alloc.reg_alloc.push(None)
+                    res_alloc.reg_alloc.push()
                 };
                 IRGen::emit_iABC(instructions, OpName::LOADBOOL, reg, bit, 0);
                 Ok((true, reg))
             }
-            Expr::Str(ref s) => {
-                let const_pos = res_alloc.const_alloc.push(ConstType::Str(s.clone()));
+            Expr::Str(s) => {
+                let const_pos = res_alloc.const_alloc.push_str(s, &ast.interner);
                 let reg = if let Some(expect) = extract_expect_reg(expect)? {
                     expect
                 } else {
-                    res_alloc.reg_alloc.push(None)
+                    res_alloc.reg_alloc.push()
                 };
                 IRGen::emit_iABx(instructions, OpName::LOADK, reg, const_pos);
                 Ok((true, reg))
             }
-            Expr::BinOp(flag, ref left, ref right) => {
+            Expr::BinOp(flag, left, right) => {
                 // use left register as result register
                 // TODO: ignore left associative to generate optimized code
                 match flag {
-                    FlagType::Plus | FlagType::Minus | FlagType::Mul | FlagType::Div => {
+                    FlagType::Plus | FlagType::Minus | FlagType::Mul | FlagType::Div |
+                    FlagType::Pow => {
+                        let top = res_alloc.reg_alloc.top();
                         let (is_temp, left_reg) =
                             self.reg_constid_merge(left, res_alloc, instructions, None)?;
                         let (_, right_reg) =
@@ -412,85 +559,154 @@ impl IRGen {
                                 if is_temp {
                                     (true, left_reg)
                                 } else {
-                                    (true, res_alloc.reg_alloc.push(None))
+                                    // operands are read before the result is written
+                                    res_alloc.reg_alloc.release_to(top);
+                                    (true, res_alloc.reg_alloc.push())
                                 }
                             };
                         IRGen::emit_iABC(instructions, op, result_reg, left_reg, right_reg);
                         Ok((result_is_temp, result_reg))
                     }
+                    FlagType::AND | FlagType::OR => {
+                        self.visit_and_or(flag, left, right, res_alloc, instructions, expect)
+                    }
+                    FlagType::DoubleDot => {
+                        self.visit_concat(expr, res_alloc, instructions, expect)
+                    }
                     _ => self.visit_logic_arith(expr, res_alloc, instructions, expect),
                 }
             }
-            Expr::UnaryOp(op, ref left) => match op {
-                FlagType::Minus => unimplemented!(),
+            Expr::UnaryOp(op, left) => match op {
+                FlagType::Minus | FlagType::Len => {
+                    // a negative literal is a constant
+                    if let (FlagType::Minus, Expr::Num(num)) = (op, &ast[left]) {
+                        let const_pos = res_alloc.const_alloc.push(ConstType::Real(-num));
+                        let reg = match extract_expect_reg(expect)? {
+                            Some(expect) => expect,
+                            None => res_alloc.reg_alloc.push(),
+                        };
+                        IRGen::emit_iABx(instructions, OpName::LOADK, reg, const_pos);
+                        return Ok((true, reg));
+                    }
+                    let top = res_alloc.reg_alloc.top();
+                    let (is_temp, operand_reg) =
+                        self.visit_r_expr(left, res_alloc, instructions, None)?;
+                    let result_reg = match extract_expect_reg(expect)? {
+                        Some(expect) => expect,
+                        None if is_temp => operand_reg,
+                        None => {
+                            res_alloc.reg_alloc.release_to(top);
+                            res_alloc.reg_alloc.push()
+                        }
+                    };
+                    let op = if op == FlagType::Minus { OpName::UNM } else { OpName::LEN };
+                    IRGen::emit_iABC(instructions, op, result_reg, operand_reg, 0);
+                    Ok((true, result_reg))
+                }
                 FlagType::Plus => self.visit_r_expr(left, res_alloc, instructions, expect),
                 _ => self.visit_logic_arith(expr, res_alloc, instructions, expect),
             },
-            Expr::Var(ref var) => {
-                self.visit_var(var, res_alloc, instructions, extract_expect_reg(expect)?)
+            Expr::Var(_) => {
+                self.visit_var(expr, res_alloc, instructions, extract_expect_reg(expect)?)
             }
-            Expr::FunctionDef((ref namelist, is_vararg), ref function_body) => {
-                self.symbol_table.initialize_scope();
-                //  child function prototype should be wrapped in another scope
-                let function_prototype =
-                    self.visit_function(function_body, Some(res_alloc), namelist, is_vararg)?;
-                self.symbol_table.finalize_scope();
+            Expr::FunctionDef((_, is_vararg), function_body) => {
+                let start = Some(self.ast.location(expr));
+                let function_prototype = self.visit_function(function_body, is_vararg, start)?;
                 //  push function prototype in function list
-                let func_pos = res_alloc.function_alloc.push(function_prototype.prototype);
+                let func_pos = res_alloc.function_alloc.push(function_prototype);
                 //  temporary register for function
                 let reg = if let Some(expect) = extract_expect_reg(expect)? {
                     expect
                 } else {
-                    res_alloc.reg_alloc.push(None)
+                    res_alloc.reg_alloc.push()
                 };
                 IRGen::emit_iABx(instructions, OpName::CLOSURE, reg, func_pos);
                 //  generate virtual move instructions
                 //  helping vm to manage upvalue
-                for (is_immidiate, pos_in_vl, pos_in_parent) in function_prototype.upvalue_list {
-                    //  move: pass the variable in current lexical scope to closure
-                    if is_immidiate {
-                        IRGen::emit_iABC(instructions, OpName::MOVE, pos_in_vl, pos_in_parent, 0);
-                    } else {
+                let upvalues = self.resolution.upvalues(function_body);
+                for (pos_in_vl, &upvalue) in upvalues.iter().enumerate() {
+                    let pos_in_vl = pos_in_vl as Usize;
+                    match upvalue {
+                        //  move: pass the variable in current lexical scope to closure
+                        UpValueDesc::Local(id) => {
+                            let pos_in_parent = self.local_reg(id)?;
+                            IRGen::emit_iABC(
+                                instructions,
+                                OpName::MOVE,
+                                pos_in_vl,
+                                pos_in_parent,
+                                0,
+                            );
+                        }
                         // getupval: pass upvalue to the closure
-                        IRGen::emit_iABx(instructions, OpName::GETUPVAL, pos_in_vl, pos_in_parent);
+                        UpValueDesc::UpValue(pos_in_parent) => {
+                            IRGen::emit_iABC(
+                                instructions,
+                                OpName::GETUPVAL,
+                                pos_in_vl,
+                                pos_in_parent,
+                                0,
+                            );
+                        }
                     }
                 }
                 Ok((true, reg))
             }
-            Expr::GeneralCall(ref expr, ref args, is_vararg) => {
-                let central_reg = self.visit_general_call(
-                    expr,
-                    args,
-                    is_vararg,
-                    RetExpect::Num(1),
-                    res_alloc,
-                    instructions,
-                )?;
-                Ok((true, central_reg))
+            Expr::GeneralCall(..) | Expr::ColonCall(..) => {
+                let central_reg =
+                    self.visit_call(expr, RetExpect::Num(1), res_alloc, instructions)?;
+                Ok((true, IRGen::move_to_expect(central_reg, expect, instructions)?))
             }
-            Expr::ColonCall(ref _table_expr, ref _func_name, ref _args, _is_vararg) => unimplemented!(),
-
             Expr::TableCtor(ref entrys) => {
                 self.visit_table_ctor(entrys, res_alloc, instructions, expect)
             }
-            Expr::TableRef(ref table, ref key) => {
+            Expr::TableRef(table, key) => {
                 self.visit_table_ref(table, key, res_alloc, instructions, expect)
-            } // _ => {
+            }
+            // a call in parentheses is visited as a single value
+            Expr::Paren(inner) => self.visit_r_expr(inner, res_alloc, instructions, expect),
+            // the first extra argument
+            Expr::Vararg => {
+                self.check_vararg(expr, res_alloc)?;
+                let reg = if let Some(expect) = extract_expect_reg(expect)? {
+                    expect
+                } else {
+                    res_alloc.reg_alloc.push()
+                };
+                IRGen::emit_iABC(instructions, OpName::VARARG, reg, 2, 0);
+                Ok((true, reg))
+            }
+            // _ => {
               //     println!("Unmatched expr: {:?}", expr);
               //     unimplemented!();
               // }
         }
     }
 
+    /// visit an expression adjusted by `adjust_list`
+    fn visit_r_operand(
+        &mut self,
+        operand: ExprOrReg,
+        res_alloc: &mut ResourceAlloc,
+        instructions: &mut Vec<OpMode>,
+        expect: Option<Expect>,
+    ) -> Result<(bool, Usize), CompileError> {
+        match operand {
+            ExprOrReg::Expr(expr) => self.visit_r_expr(expr, res_alloc, instructions, expect),
+            // a value already placed on top by adjust_list
+            ExprOrReg::Reg(reg) => Ok((true, IRGen::move_to_expect(reg, expect, instructions)?)),
+        }
+    }
+
     fn visit_l_expr(
         &mut self,
-        expr: &Expr,
+        expr: ExprId,
         value_creg: u32,
         res_alloc: &mut ResourceAlloc,
         instructions: &mut Vec<OpMode>,
     ) -> Result<(), CompileError> {
-        match *expr {
-            Expr::TableRef(ref table, ref key) => {
+        match self.ast[expr] {
+            Expr::TableRef(table, key) => {
                 let (_, table_reg) = self.visit_r_expr(table, res_alloc, instructions, None)?;
                 let key_creg = self.visit_table_key(key, res_alloc, instructions)?;
                 IRGen::emit_iABC(
@@ -506,9 +722,72 @@ impl IRGen {
         Ok(())
     }
 
+    /// the operands of a chain of `..` are placed in consecutive registers for one CONCAT
+    fn visit_concat(
+        &mut self,
+        expr: ExprId,
+        res_alloc: &mut ResourceAlloc,
+        instructions: &mut Vec<OpMode>,
+        expect: Option<Expect>,
+    ) -> Result<(bool, u32), CompileError> {
+        // `..` is right associative, `a .. b .. c` is `a .. (b .. c)`
+        let mut operands = vec![];
+        let mut rest = expr;
+        while let Expr::BinOp(FlagType::DoubleDot, left, right) = self.ast[rest] {
+            operands.push(left);
+            rest = right;
+        }
+        operands.push(rest);
+        let top = res_alloc.reg_alloc.top();
+        for &operand in &operands {
+            let reg = res_alloc.reg_alloc.push();
+            self.visit_r_expr(operand, res_alloc, instructions, Some(Expect::Reg(reg)))?;
+            res_alloc.reg_alloc.release_to(reg + 1);
+        }
+        let last = top + operands.len() as u32 - 1;
+        // operands are read before the result is written
+        res_alloc.reg_alloc.release_to(top);
+        let result_reg = match extract_expect_reg(expect)? {
+            Some(reg) => reg,
+            None => res_alloc.reg_alloc.push(),
+        };
+        IRGen::emit_iABC(instructions, OpName::CONCAT, result_reg, top, last);
+        Ok((true, result_reg))
+    }
+
+    /// `and` and `or` give one of their operands,
+    /// the right one is only evaluated if the left one does not decide
+    fn visit_and_or(
+        &mut self,
+        op: FlagType,
+        left: ExprId,
+        right: ExprId,
+        res_alloc: &mut ResourceAlloc,
+        instructions: &mut Vec<OpMode>,
+        expect: Option<Expect>,
+    ) -> Result<(bool, u32), CompileError> {
+        // a local in the expected register may still be read by the right operand
+        let result_reg = match extract_expect_reg(expect)? {
+            Some(reg) if reg >= res_alloc.stat_top => reg,
+            _ => res_alloc.reg_alloc.push(),
+        };
+        let top = res_alloc.reg_alloc.top();
+        let end_label = res_alloc.label_alloc.new_label();
+        self.visit_r_expr(left, res_alloc, instructions, Some(Expect::Reg(result_reg)))?;
+        res_alloc.reg_alloc.release_to(top);
+        // `or` keeps a true left operand, `and` a false one
+        let keep = if op == FlagType::OR { 1 } else { 0 };
+        IRGen::emit_iABC(instructions, OpName::TEST, result_reg, 0, keep);
+        instructions.push(OpMode::rJMP(end_label));
+        self.visit_r_expr(right, res_alloc, instructions, Some(Expect::Reg(result_reg)))?;
+        res_alloc.reg_alloc.release_to(top);
+        instructions.push(OpMode::Label(end_label));
+        Ok((true, IRGen::move_to_expect(result_reg, expect, instructions)?))
+    }
+
     fn visit_logic_arith(
         &mut self,
-        expr: &Expr,
+        expr: ExprId,
         res_alloc: &mut ResourceAlloc,
         instruction: &mut Vec<OpMode>,
         expect: Option<Expect>,
@@ -516,7 +795,7 @@ impl IRGen {
         let result_reg = if let Some(expect) = extract_expect_reg(expect)? {
             expect
         } else {
-            res_alloc.reg_alloc.push(None)
+            res_alloc.reg_alloc.push()
         };
 
         let true_label = res_alloc.label_alloc.new_label();
@@ -532,14 +811,15 @@ impl IRGen {
 
     fn visit_boolean_expr(
         &mut self,
-        expr: &Expr,
+        expr: ExprId,
         res_alloc: &mut ResourceAlloc,
         true_br: Label,
         false_br: Label,
         fall_through: bool,
     ) -> Result<Vec<OpMode>, CompileError> {
-        match *expr {
-            Expr::BinOp(op, ref left, ref right) => {
+        let ast = self.ast;
+        match ast[expr] {
+            Expr::BinOp(op, left, right) => {
                 match op {
                     FlagType::OR => {
                         let label_for_right = res_alloc.label_alloc.new_label();
@@ -610,329 +890,349 @@ impl IRGen {
                         };
                         raw.push(OpMode::iABC(op_name, test_int, left_reg, right_reg));
                         raw.push(OpMode::rJMP(path));
-                        // IRGen::emit_iAsBx(&mut raw, OpName::JMP, 0, false_br);
                         Ok(raw)
                     }
-                    _ => panic!("expression not accept as boolean"),
+                    _ => self.visit_value_test(expr, res_alloc, true_br, false_br, fall_through),
                 }
             }
-            Expr::UnaryOp(op, ref left) => match op {
-                FlagType::Not => {
-                    self.visit_boolean_expr(left, res_alloc, false_br, true_br, !fall_through)
-                }
-                _ => panic!("expression not accept as boolean"),
-            },
-            Expr::Var(ref var) => {
-                let mut raw = vec![];
-                let (_, reg) = self.visit_var(var, res_alloc, &mut raw, None)?;
-                if fall_through == true {
-                    // fall to true path
-                    raw.push(OpMode::iABx(OpName::TEST, reg, 1));
-                    raw.push(OpMode::rJMP(true_br));
-                // IRGen::emit_iAsBx(&mut raw, OpName::JMP, 0, false_br);
-                } else {
-                    // fall to false path
-                    raw.push(OpMode::iABx(OpName::TEST, reg, 0));
-                    raw.push(OpMode::rJMP(false_br));
-                    // IRGen::emit_iAsBx(&mut raw, OpName::JMP, 0, true_br);
-                }
-                Ok(raw)
+            Expr::UnaryOp(FlagType::Not, left) => {
+                self.visit_boolean_expr(left, res_alloc, false_br, true_br, !fall_through)
             }
-            Expr::Boole(value) => {
-                let mut raw = vec![];
-                if value {
-                    raw.push(OpMode::rJMP(true_br));
-                } else {
-                    IRGen::emit_iAsBx(&mut raw, OpName::JMP, 0, false_br);
-                    raw.push(OpMode::rJMP(false_br));
-                }
-                Ok(raw)
+            Expr::Paren(inner) => {
+                self.visit_boolean_expr(inner, res_alloc, true_br, false_br, fall_through)
+            }
+            Expr::Boole(_) | Expr::Nil | Expr::Num(_) | Expr::Str(_) => {
+                // only nil and false are false
+                let value = !matches!(ast[expr], Expr::Boole(false) | Expr::Nil);
+                let target = if value { true_br } else { false_br };
+                Ok(vec![OpMode::rJMP(target)])
             }
-            _ => panic!("expression not accept"),
+            _ => self.visit_value_test(expr, res_alloc, true_br, false_br, fall_through),
         }
     }
 
-    fn visit_r_exprlist(
+    /// a variable, call or any other value is true unless nil or false
+    fn visit_value_test(
         &mut self,
-        exprlist: &Vec<Expr>,
+        expr: ExprId,
         res_alloc: &mut ResourceAlloc,
-        instructions: &mut Vec<OpMode>,
-    ) -> Result<Vec<(bool, u32)>, CompileError> {
-        let reg_list = exprlist
-            .into_iter()
-            .map(|expr| self.visit_r_expr(expr, res_alloc, instructions, None))
-            .filter(|r| r.is_ok())
-            .map(|r| r.unwrap())
-            .collect::<Vec<_>>();
-        if reg_list.len() != exprlist.len() {
-            Err(CompileError::SyntexError)
+        true_br: Label,
+        false_br: Label,
+        fall_through: bool,
+    ) -> Result<Vec<OpMode>, CompileError> {
+        let mut raw = vec![];
+        let (_, reg) = self.visit_r_expr(expr, res_alloc, &mut raw, None)?;
+        if fall_through {
+            // fall to true path
+            raw.push(OpMode::iABx(OpName::TEST, reg, 1));
+            raw.push(OpMode::rJMP(true_br));
         } else {
-            Ok(reg_list)
+            // fall to false path
+            raw.push(OpMode::iABx(OpName::TEST, reg, 0));
+            raw.push(OpMode::rJMP(false_br));
         }
+        Ok(raw)
     }
 
     /// ret: (is_temp, register saves the varible)
     fn visit_var(
         &mut self,
-        var: &Var,
+        expr: ExprId,
         res_alloc: &mut ResourceAlloc,
         instructions: &mut Vec<OpMode>,
         expect_reg: Option<u32>,
     ) -> Result<(bool, Usize), CompileError> {
-        match *var {
-            Var::Name(ref name) => {
-                let (scope, pos) = try!(
-                    self.symbol_table
-                        .lookup(name)
-                        .ok_or(CompileError::UndefinedSymbol)
-                );
-                match scope {
-                    SymbolScope::Global => {
-                        let const_pos = res_alloc.const_alloc.push(ConstType::Str(name.clone()));
-                        let reg = if let Some(expect) = expect_reg {
-                            expect
-                        } else {
-                            res_alloc.reg_alloc.push(None)
-                        };
-                        IRGen::emit_iABx(instructions, OpName::GETGLOBAL, reg, const_pos);
-                        Ok((true, reg))
-                    }
-                    SymbolScope::UpValue(depth) => {
-                        let immidiate_upvalue_pos =
-                            unsafe { res_alloc.propagate_upvalue(name, pos, depth) };
-                        let reg = if let Some(expect) = expect_reg {
-                            expect
-                        } else {
-                            res_alloc.reg_alloc.push(None)
-                        };
-                        // todo: optimize, reduce register number
-                        IRGen::emit_iABx(
-                            instructions,
-                            OpName::GETUPVAL,
-                            reg,
-                            immidiate_upvalue_pos,
-                        );
-                        Ok((true, reg))
-                    }
-                    SymbolScope::Local => {
-                        if let Some(expect) = expect_reg {
-                            if expect != pos {
-                                IRGen::emit_iABC(instructions, OpName::MOVE, expect, pos, 0);
-                                Ok((false, expect)) // caller-provided register is viewed as none temp
-                            } else {
-                                Ok((false, expect))
-                            }
-                        } else {
-                            // no expected register provided
-                            Ok((false, pos))
-                        }
+        let name = match self.ast[expr] {
+            Expr::Var(Var::Name(name)) => name,
+            _ => return Err(CompileError::SyntexError),
+        };
+        let resolved = self.resolution.lookup(expr).ok_or(CompileError::UndefinedSymbol)?;
+        match resolved {
+            Resolved::Global => {
+                let const_pos = res_alloc.const_alloc.push_str(name, &self.ast.interner);
+                let reg = if let Some(expect) = expect_reg {
+                    expect
+                } else {
+                    res_alloc.reg_alloc.push()
+                };
+                IRGen::emit_iABx(instructions, OpName::GETGLOBAL, reg, const_pos);
+                Ok((true, reg))
+            }
+            Resolved::UpValue(upvalue_pos) => {
+                let reg = if let Some(expect) = expect_reg {
+                    expect
+                } else {
+                    res_alloc.reg_alloc.push()
+                };
+                // todo: optimize, reduce register number
+                IRGen::emit_iABC(instructions, OpName::GETUPVAL, reg, upvalue_pos, 0);
+                Ok((true, reg))
+            }
+            Resolved::Local(id) => {
+                let pos = self.local_reg(id)?;
+                if let Some(expect) = expect_reg {
+                    if expect != pos {
+                        IRGen::emit_iABC(instructions, OpName::MOVE, expect, pos, 0);
+                        Ok((false, expect)) // caller-provided register is viewed as none temp
+                    } else {
+                        Ok((false, expect))
                     }
+                } else {
+                    // no expected register provided
+                    Ok((false, pos))
                 }
             }
-            Var::Reg(reg) => Ok((true, reg)),
-            Var::PrefixExp(ref _expr) => unimplemented!(),
         }
     }
 
+    fn bind_local(&mut self, id: LocalId, reg: Usize) {
+        self.local_regs[id.index()] = Some(reg);
+    }
+
+    /// if a closure refers to a local declared at the site
+    fn captures_at(&self, site: DeclSite) -> bool {
+        let resolution = &self.resolution;
+        resolution.declared(site).iter().any(|&id| resolution.is_captured(id))
+    }
+
+    /// if a closure refers to a local of the block or the blocks nested in it,
+    /// loop variables included
+    fn captures_in(&self, block: BlockId) -> bool {
+        let ast = self.ast;
+        ast[block].stats.iter().enumerate().any(|(i, stat)| {
+            self.captures_at(DeclSite::Stat(block, i)) || match *stat {
+                Stat::IfElse(_, then_block, Some(else_block)) => {
+                    self.captures_in(then_block) || self.captures_in(else_block)
+                }
+                Stat::IfElse(_, body, None) |
+                Stat::While(_, body) |
+                Stat::ForRange(_, _, body) |
+                Stat::ForNumeric(_, _, _, _, body) => self.captures_in(body),
+                _ => false,
+            }
+        })
+    }
+
+    /// register of a declared local
+    fn local_reg(&self, id: LocalId) -> Result<Usize, CompileError> {
+        self.local_regs[id.index()].ok_or(CompileError::UndefinedSymbol)
+    }
+
+    /// the function and its arguments are pushed on top, in a frame of their own
     fn visit_general_call(
         &mut self,
-        expr: &Expr,
-        args: &Vec<Expr>,
-        _is_vararg: bool,
+        expr: ExprId,
+        args: &Vec<ExprId>,
         expect_ret: RetExpect,
         res_alloc: &mut ResourceAlloc,
         instructions: &mut Vec<OpMode>,
     ) -> Result<u32, CompileError> {
-        // todo: vararg
-        // get function name
-        let func_pos = res_alloc.reg_alloc.push(None);
-        let ret_field: u32;
-        let arg_field: u32;
-
-        // allocate register
-        match expect_ret {
-            RetExpect::Num(ret_num) => {
-                // allocate register
-                for _ in 0..(cmp::max(ret_num, args.len() as u32)) {
-                    res_alloc.reg_alloc.push(None);
-                }
-                ret_field = ret_num + 1;
-            }
-            RetExpect::Indeterminate => {
-                ret_field = 0;
-            }
-        }
+        let func_pos = res_alloc.reg_alloc.push();
         self.visit_r_expr(expr, res_alloc, instructions, Some(Expect::Reg(func_pos)))?;
-        arg_field = self.visit_args(args, func_pos, res_alloc, instructions)?;
-        IRGen::emit_iABC(instructions, OpName::CALL, func_pos, arg_field, ret_field);
+        let arg_field = self.visit_args(args, res_alloc, instructions)?;
+        let ret_field = IRGen::ret_field(expect_ret);
+        IRGen::emit_call(instructions, func_pos, arg_field, ret_field, expect_ret);
+        IRGen::keep_results(func_pos, expect_ret, res_alloc);
         Ok(func_pos)
     }
 
     fn visit_colon_call(
         &mut self,
-        table_expr: &Expr,
-        func_name: &Name,
-        args: &Vec<Expr>,
-        _is_vararg: bool,
+        table_expr: ExprId,
+        func_name: Name,
+        args: &Vec<ExprId>,
         expect_ret: RetExpect,
         res_alloc: &mut ResourceAlloc,
         instructions: &mut Vec<OpMode>,
     ) -> Result<u32, CompileError> {
-        // make
-        let func_pos = res_alloc.reg_alloc.push(None);
-        let table_pos = res_alloc.reg_alloc.push(None);
-        let ret_field: u32;
-        let arg_field: u32;
-        // allocate registers
-        match expect_ret {
-            RetExpect::Num(ret_num) => {
-                for _ in 0..(cmp::max(ret_num, args.len() as u32)) {
-                    res_alloc.reg_alloc.push(None);
-                }
-                ret_field = ret_num + 1;
-            }
-            RetExpect::Indeterminate => {
-                ret_field = 0;
-            }
-        }
+        // SELF puts the method below the table
+        let func_pos = res_alloc.reg_alloc.push();
+        let table_pos = res_alloc.reg_alloc.push();
         self.visit_r_expr(
             table_expr,
             res_alloc,
             instructions,
             Some(Expect::Reg(table_pos)),
         )?;
-        let name_pos = self.visit_table_key(
-            &Expr::Var(Var::Name(func_name.clone())),
-            res_alloc,
-            instructions,
-        )?;
+        let name_pos = self.visit_name_key(func_name, res_alloc, instructions)?;
         IRGen::emit_iABC(instructions, OpName::SELF, func_pos, table_pos, name_pos);
-        arg_field = match self.visit_args(args, func_pos, res_alloc, instructions)? {
+        // a name loaded into a register is no longer needed
+        res_alloc.reg_alloc.release_to(table_pos + 1);
+        // arguments follow the table passed as self
+        let arg_field = match self.visit_args(args, res_alloc, instructions)? {
             0 => 0,
             i @ _ => i + 1,
         };
-        IRGen::emit_iABC(instructions, OpName::CALL, func_pos, arg_field, ret_field);
+        let ret_field = IRGen::ret_field(expect_ret);
+        IRGen::emit_call(instructions, func_pos, arg_field, ret_field, expect_ret);
+        IRGen::keep_results(func_pos, expect_ret, res_alloc);
         Ok(func_pos)
     }
 
-    fn visit_args(
+    /// visit a call whose results are left from its function register on
+    /// ret: register of the first result
+    fn visit_call(
         &mut self,
-        args: &Vec<Expr>,
-        func_pos: u32,
+        call: ExprId,
+        expect_ret: RetExpect,
         res_alloc: &mut ResourceAlloc,
         instructions: &mut Vec<OpMode>,
     ) -> Result<u32, CompileError> {
-        let mut args_reg = func_pos + 1;
-        let arg_field;
-        if args.is_empty() {
-            return Ok(1);
-        }
-        if let Expr::GeneralCall(ref t_func, ref t_args, t_is_vararg) = args[args.len() - 1] {
-            for i in 0..(args.len() - 1) {
-                self.visit_r_expr(
-                    &args[i],
-                    res_alloc,
-                    instructions,
-                    Some(Expect::Reg(args_reg)),
-                )?;
-                args_reg += 1;
+        match self.ast[call] {
+            Expr::GeneralCall(func, ref args) => {
+                self.visit_general_call(func, args, expect_ret, res_alloc, instructions)
             }
-            self.visit_general_call(
-                t_func,
-                t_args,
-                t_is_vararg,
-                RetExpect::Indeterminate,
-                res_alloc,
-                instructions,
-            )?;
-            arg_field = 0;
-        } else if let Expr::ColonCall(ref t_table, ref t_func_name, ref t_args, t_is_vararg) =
-            args[args.len() - 1]
-        {
-            for i in 0..(args.len() - 1) {
-                self.visit_r_expr(
-                    &args[i],
-                    res_alloc,
-                    instructions,
-                    Some(Expect::Reg(args_reg)),
-                )?;
-                args_reg += 1;
+            Expr::ColonCall(table, func_name, ref args) => {
+                self.visit_colon_call(table, func_name, args, expect_ret, res_alloc, instructions)
             }
-            self.visit_colon_call(
-                t_table,
-                t_func_name,
-                t_args,
-                t_is_vararg,
-                RetExpect::Indeterminate,
-                res_alloc,
-                instructions,
-            )?;
-            arg_field = 0;
+            _ => Err(CompileError::SyntexError),
+        }
+    }
+
+    /// visit a call or `...`, whose values are left on top
+    /// ret: register of the first value
+    fn visit_multi_value(
+        &mut self,
+        expr: ExprId,
+        expect_ret: RetExpect,
+        res_alloc: &mut ResourceAlloc,
+        instructions: &mut Vec<OpMode>,
+    ) -> Result<u32, CompileError> {
+        match self.ast[expr] {
+            Expr::Vararg => {
+                self.check_vararg(expr, res_alloc)?;
+                let reg = res_alloc.reg_alloc.push();
+                // B of VARARG counts the values like C of CALL
+                let num_field = IRGen::ret_field(expect_ret);
+                IRGen::emit_iABC(instructions, OpName::VARARG, reg, num_field, 0);
+                IRGen::keep_results(reg, expect_ret, res_alloc);
+                Ok(reg)
+            }
+            _ => self.visit_call(expr, expect_ret, res_alloc, instructions),
+        }
+    }
+
+    /// `...` is only valid in a vararg function
+    fn check_vararg(&self, expr: ExprId, res_alloc: &ResourceAlloc) -> Result<(), CompileError> {
+        if res_alloc.is_vararg {
+            Ok(())
         } else {
-            for expr in args {
-                self.visit_r_expr(expr, res_alloc, instructions, Some(Expect::Reg(args_reg)))?;
-                args_reg += 1;
+            Err(CompileError::NotVararg(self.ast.location(expr)))
+        }
+    }
+
+    /// only an unparenthesized call or `...` at the end of a list passes all its values
+    fn is_multi_value(&self, expr: ExprId) -> bool {
+        matches!(self.ast[expr], Expr::GeneralCall(..) | Expr::ColonCall(..) | Expr::Vararg)
+    }
+
+    /// the results of a call are on top of the stack,
+    /// an indeterminate number of them is left open for the next instruction
+    fn keep_results(func_pos: u32, expect_ret: RetExpect, res_alloc: &mut ResourceAlloc) {
+        res_alloc.reg_alloc.release_to(func_pos);
+        if let RetExpect::Num(ret_num) = expect_ret {
+            for _ in 0..ret_num {
+                res_alloc.reg_alloc.push();
+            }
+        }
+    }
+
+    /// C field of CALL
+    fn ret_field(expect_ret: RetExpect) -> u32 {
+        match expect_ret {
+            RetExpect::Num(ret_num) => ret_num + 1,
+            RetExpect::Indeterminate | RetExpect::Tail => 0,
+        }
+    }
+
+    /// push arguments on top one by one,
+    /// a call or `...` at the end passes all its values
+    /// ret: B field of CALL counting the arguments only
+    fn visit_args(
+        &mut self,
+        args: &Vec<ExprId>,
+        res_alloc: &mut ResourceAlloc,
+        instructions: &mut Vec<OpMode>,
+    ) -> Result<u32, CompileError> {
+        for (i, &arg) in args.iter().enumerate() {
+            if !self.is_multi_value(arg) {
+                let reg = res_alloc.reg_alloc.push();
+                self.visit_r_expr(arg, res_alloc, instructions, Some(Expect::Reg(reg)))?;
+            } else if i + 1 < args.len() {
+                // the first value is left in the argument slot
+                self.visit_multi_value(arg, RetExpect::Num(1), res_alloc, instructions)?;
+            } else {
+                self.visit_multi_value(arg, RetExpect::Indeterminate, res_alloc, instructions)?;
+                return Ok(0);
             }
-            arg_field = args.len() as u32 + 1;
         }
-        Ok(arg_field)
+        Ok(args.len() as u32 + 1)
     }
 
     fn adjust_list<T: Clone>(
         &mut self,
         varlist: &Vec<T>,
-        exprlist: &Vec<Expr>,
+        exprlist: &Vec<ExprId>,
         res_alloc: &mut ResourceAlloc,
         instructions: &mut Vec<OpMode>,
-    ) -> Result<(Vec<T>, Vec<Expr>), CompileError> {
+    ) -> Result<(Vec<T>, Vec<ExprOrReg>), CompileError> {
+        let operands = exprlist.iter().map(|&expr| ExprOrReg::Expr(expr));
         // balanced
         if varlist.len() == exprlist.len() {
-            return Ok((varlist.clone(), exprlist.clone()));
+            return Ok((varlist.clone(), operands.collect()));
         }
         // imbalanced & trancate
         else if varlist.len() < exprlist.len() {
             // discard resisual expressions
             let remain = varlist.len();
-            let mut truncated = exprlist.clone();
-            truncated.truncate(remain);
-            return Ok((varlist.clone(), truncated));
+            return Ok((varlist.clone(), operands.take(remain).collect()));
         }
-        // imbalanced & one function call
+        // imbalanced, values are placed in registers
+        // a call at the end fills the variables left, otherwise they are nil
         else {
-            if exprlist.len() == 1 {
-                if let Expr::GeneralCall(ref expr, ref args, is_vararg) = exprlist[0] {
-                    let central_reg = self.visit_general_call(
-                        expr,
-                        args,
-                        is_vararg,
-                        RetExpect::Num(varlist.len() as u32),
-                        res_alloc,
-                        instructions,
-                    )?;
-                    let expr_regs = (central_reg..(central_reg + varlist.len() as u32))
-                        .map(|reg| Expr::Var(Var::Reg(reg)))
-                        .collect();
-                    return Ok((varlist.clone(), expr_regs));
-                }
+            let num = varlist.len();
+            let base = self.visit_values_to_top(num, exprlist, res_alloc, instructions)?;
+            let regs = (base..(base + num as Usize)).map(ExprOrReg::Reg).collect();
+            return Ok((varlist.clone(), regs));
+        }
+    }
+
+    /// place the values of an exprlist in `num` registers pushed on top,
+    /// a call at the end fills the registers left, otherwise they are nil
+    /// values beyond `num` are dropped
+    /// ret: the first register
+    fn visit_values_to_top(
+        &mut self,
+        num: usize,
+        exprlist: &[ExprId],
+        res_alloc: &mut ResourceAlloc,
+        instructions: &mut Vec<OpMode>,
+    ) -> Result<Usize, CompileError> {
+        let base = res_alloc.reg_alloc.top();
+        for (i, &expr) in exprlist.iter().take(num).enumerate() {
+            let left = (num - i) as u32;
+            if i + 1 == exprlist.len() && left > 1 && self.is_multi_value(expr) {
+                self.visit_multi_value(expr, RetExpect::Num(left), res_alloc, instructions)?;
+                return Ok(base);
             }
-            // imbalanced & loadnill
-            let num = (varlist.len() - exprlist.len()) as u32;
-            let start_reg = res_alloc.reg_alloc.push(None);
-            let mut extended = exprlist.clone();
-            extended.push(Expr::Var(Var::Reg(start_reg)));
-            for _ in 1..num {
-                let reg = res_alloc.reg_alloc.push(None);
-                extended.push(Expr::Var(Var::Reg(reg)));
+            let reg = res_alloc.reg_alloc.push();
+            self.visit_r_expr(expr, res_alloc, instructions, Some(Expect::Reg(reg)))?;
+        }
+        if exprlist.len() < num {
+            let first = res_alloc.reg_alloc.top();
+            for _ in exprlist.len()..num {
+                res_alloc.reg_alloc.push();
             }
-            IRGen::emit_iABx(instructions, OpName::LOADNIL, start_reg, num - 1);
-            return Ok((varlist.clone(), extended));
+            let last = res_alloc.reg_alloc.top() - 1;
+            IRGen::emit_iABC(instructions, OpName::LOADNIL, first, last, 0);
         }
+        Ok(base)
     }
 
     fn visit_table_ref(
         &mut self,
-        table: &Expr,
-        key: &Expr,
+        table: ExprId,
+        key: ExprId,
         res_alloc: &mut ResourceAlloc,
         instructions: &mut Vec<OpMode>,
         expect: Option<Expect>,
@@ -940,7 +1240,7 @@ impl IRGen {
         let reg = if let Some(expect) = extract_expect_reg(expect)? {
             expect
         } else {
-            res_alloc.reg_alloc.push(None)
+            res_alloc.reg_alloc.push()
         };
         let (_, table_reg) = self.visit_r_expr(table, res_alloc, instructions, None)?;
         let key_creg = self.visit_table_key(key, res_alloc, instructions)?;
@@ -950,19 +1250,24 @@ impl IRGen {
 
     fn visit_table_key(
         &mut self,
-        key: &Expr,
+        key: ExprId,
         res_alloc: &mut ResourceAlloc,
         instructions: &mut Vec<OpMode>,
     ) -> Result<u32, CompileError> {
-        match key {
-            &Expr::Var(Var::Name(ref name)) => {
-                Ok(0x100 | res_alloc.const_alloc.push(ConstType::Str(name.clone())))
-            }
-            _ => {
-                let (_, reg_or_const) = self.reg_constid_merge(key, res_alloc, instructions, None)?;
-                Ok(reg_or_const)
-            }
-        }
+        let (_, reg_or_const) = self.reg_constid_merge(key, res_alloc, instructions, None)?;
+        Ok(reg_or_const)
+    }
+
+    /// a name used as table key is a string constant
+    fn visit_name_key(
+        &mut self,
+        name: Name,
+        res_alloc: &mut ResourceAlloc,
+        instructions: &mut Vec<OpMode>,
+    ) -> Result<u32, CompileError> {
+        let const_pos = res_alloc.const_alloc.push_str(name, &self.ast.interner);
+        let (_, rk) = IRGen::const_rk(const_pos, res_alloc, instructions, None)?;
+        Ok(rk)
     }
 
     fn visit_table_ctor(
@@ -972,19 +1277,23 @@ impl IRGen {
         instructions: &mut Vec<OpMode>,
         expect: Option<Expect>,
     ) -> Result<(bool, u32), CompileError> {
-        // create table
-        let result_reg = if let Some(expect) = extract_expect_reg(expect)? {
-            expect
-        } else {
-            res_alloc.reg_alloc.push(None)
-        };
-
-        // empty table
-        if entrys.is_empty() {
-            return Ok((true, result_reg));
-        }
         let (hash_part, array_part) = Self::split_table_entrys(entrys);
+        // SETLIST takes the items from the registers right above the table,
+        // and a local in the expected register may still be read by the fields
+        let expect_reg = extract_expect_reg(expect)?;
+        let top = res_alloc.reg_alloc.top();
+        let temporary = |reg| reg >= res_alloc.stat_top && (array_part.is_empty() || reg + 1 == top);
+        let result_reg = match expect_reg {
+            Some(reg) if temporary(reg) => reg,
+            _ => res_alloc.reg_alloc.push(),
+        };
+        // a call as the last field sets all its results
+        let open = match entrys.last() {
+            Some(&(None, value)) => self.is_multi_value(value),
+            _ => false,
+        };
 
+        // create table
         IRGen::emit_iABC(
             instructions,
             OpName::NEWTABLE,
@@ -995,63 +1304,29 @@ impl IRGen {
 
         // add elements in the array_part
         // fine, I'll follow the standard
-        let flush_num = array_part.len() as u32 / LFIELDS_PER_FLUSH;
-        let residue_num = array_part.len() as u32 % LFIELDS_PER_FLUSH;
-        let mut iter = array_part.into_iter();
-        // allocate register for list element
-        for _ in 0..(if flush_num > 0 {
-            LFIELDS_PER_FLUSH
-        } else {
-            residue_num
-        }) {
-            res_alloc.reg_alloc.push(None);
-        }
-        // first dealing with flush chuck
-        for flush_id in 0..flush_num {
-            // reuse register pool in each flush
-            let mut dest_reg = result_reg + 1;
-            for _ in 0..LFIELDS_PER_FLUSH {
-                self.visit_r_expr(
-                    &iter.next().unwrap(),
-                    res_alloc,
-                    instructions,
-                    Some(Expect::Reg(dest_reg)),
-                )?;
-                dest_reg += 1;
+        let item_num = array_part.len();
+        for (i, item) in array_part.into_iter().enumerate() {
+            // items are flushed every LFIELDS_PER_FLUSH
+            let flush_id = i as u32 / LFIELDS_PER_FLUSH;
+            if open && i + 1 == item_num {
+                self.visit_multi_value(item, RetExpect::Indeterminate, res_alloc, instructions)?;
+                IRGen::emit_setlist(instructions, result_reg, 0, flush_id + 1);
+                break;
+            }
+            let reg = res_alloc.reg_alloc.push();
+            self.visit_r_expr(item, res_alloc, instructions, Some(Expect::Reg(reg)))?;
+            let pending = reg - result_reg;
+            if pending == LFIELDS_PER_FLUSH || i + 1 == item_num {
+                IRGen::emit_setlist(instructions, result_reg, pending, flush_id + 1);
+                // reuse register pool in each flush
+                res_alloc.reg_alloc.release_to(result_reg + 1);
             }
-            // FIXME: consider when flush_id is too large to encode
-            IRGen::emit_iABC(
-                instructions,
-                OpName::SETLIST,
-                result_reg,
-                LFIELDS_PER_FLUSH,
-                flush_id + 1,
-            );
-        }
-        // then finish residue part
-        let mut dest_reg = result_reg + 1;
-        for _ in 0..residue_num {
-            self.visit_r_expr(
-                &iter.next().unwrap(),
-                res_alloc,
-                instructions,
-                Some(Expect::Reg(dest_reg)),
-            )?;
-            dest_reg += 1;
-        }
-        if residue_num > 0 {
-            IRGen::emit_iABC(
-                instructions,
-                OpName::SETLIST,
-                result_reg,
-                residue_num,
-                flush_num + 1,
-            );
         }
         // add pairs in the hash_part
         for (key, value) in hash_part {
-            let key_rkc = self.visit_table_key(&key, res_alloc, instructions)?;
-            let (_, value_rkc) = self.reg_constid_merge(&value, res_alloc, instructions, None)?;
+            let top = res_alloc.reg_alloc.top();
+            let key_rkc = self.visit_table_key(key, res_alloc, instructions)?;
+            let (_, value_rkc) = self.reg_constid_merge(value, res_alloc, instructions, None)?;
             IRGen::emit_iABC(
                 instructions,
                 OpName::SETTABLE,
@@ -1059,19 +1334,56 @@ impl IRGen {
                 key_rkc,
                 value_rkc,
             );
+            res_alloc.reg_alloc.release_to(top);
         }
-        Ok((true, result_reg))
+        Ok((true, IRGen::move_to_expect(result_reg, expect, instructions)?))
     }
 }
 
-impl IRGen {
-    /// allocate name in const
-    /// and define in global scope
+impl<'a> IRGen<'a> {
+    /// allocate name of a global in const
     /// avoiding duplication included
-    fn prepare_global_value(&mut self, name: &str, res_alloc: &mut ResourceAlloc) -> Usize {
-        let pos = res_alloc.const_alloc.push(ConstType::Str(name.to_string()));
-        self.symbol_table.define_global(name);
-        pos
+    fn prepare_global_value(&mut self, name: Name, res_alloc: &mut ResourceAlloc) -> Usize {
+        res_alloc.const_alloc.push_str(name, &self.ast.interner)
+    }
+
+    /// a call leaves its result in the function register,
+    /// move it if the caller expects another one
+    fn move_to_expect(
+        central_reg: u32,
+        expect: Option<Expect>,
+        instructions: &mut Vec<OpMode>,
+    ) -> Result<u32, CompileError> {
+        match extract_expect_reg(expect)? {
+            Some(reg) if reg != central_reg => {
+                IRGen::emit_iABC(instructions, OpName::MOVE, reg, central_reg, 0);
+                Ok(reg)
+            }
+            _ => Ok(central_reg),
+        }
+    }
+
+    /// TAILCALL also closes the upvalues of the caller
+    #[allow(non_snake_case)]
+    fn emit_call(instructions: &mut Vec<OpMode>, A: u32, B: u32, C: u32, expect_ret: RetExpect) {
+        let op = if expect_ret == RetExpect::Tail {
+            OpName::TAILCALL
+        } else {
+            OpName::CALL
+        };
+        IRGen::emit_iABC(instructions, op, A, B, C);
+    }
+
+    /// set B items above the table in R(A) as the batch of the array part,
+    /// a batch too large for C follows the instruction
+    #[allow(non_snake_case)]
+    fn emit_setlist(instructions: &mut Vec<OpMode>, A: u32, B: u32, batch: u32) {
+        if batch <= MAXARG_C {
+            IRGen::emit_iABC(instructions, OpName::SETLIST, A, B, batch);
+        } else {
+            IRGen::emit_iABC(instructions, OpName::SETLIST, A, B, 0);
+            instructions.push(OpMode::Raw(batch));
+        }
     }
 
     /// put iABx instruction in bytecode vector
@@ -1084,28 +1396,105 @@ impl IRGen {
     fn emit_iABC(instructions: &mut Vec<OpMode>, op: OpName, A: u32, B: u32, C: u32) {
         instructions.push(OpMode::iABC(op, A, B, C));
     }
-
-    #[allow(non_snake_case)]
-    fn emit_iAsBx(instructions: &mut Vec<OpMode>, op: OpName, A: u32, sBx: i32) {
-        instructions.push(OpMode::iAsBx(op, A, sBx));
-    }
 }
 
 /// helper functions
-impl IRGen {
+impl<'a> IRGen<'a> {
+    /// registers and constants used so far must fit their operands
+    fn check_limits(res_alloc: &ResourceAlloc, location: Location) -> Result<(), CompileError> {
+        let limit = if res_alloc.reg_alloc.size() as usize > Limit::Registers.max() {
+            Limit::Registers
+        } else if res_alloc.const_alloc.size() > Limit::Constants.max() {
+            Limit::Constants
+        } else {
+            return Ok(());
+        };
+        Err(IRGen::limit_error(res_alloc, limit, location))
+    }
+
+    /// every jump must fit in sBx once labels are removed
+    fn check_jumps(instructions: &[OpMode], res_alloc: &ResourceAlloc) -> Result<(), CompileError> {
+        // position of each instruction without labels, and index of each label
+        let mut positions = Vec::with_capacity(instructions.len());
+        let mut labels = HashMap::new();
+        let mut pos = 0;
+        for (i, ins) in instructions.iter().enumerate() {
+            positions.push(pos);
+            match *ins {
+                OpMode::Label(label) => {
+                    labels.insert(label, i);
+                }
+                _ => pos += 1,
+            }
+        }
+        for (i, ins) in instructions.iter().enumerate() {
+            let label = match *ins {
+                OpMode::rJMP(label) | OpMode::rForPrep(_, label) | OpMode::rForLoop(_, label) => {
+                    label
+                }
+                _ => continue,
+            };
+            // undefined labels are reported by the assembler
+            let target = match labels.get(&label) {
+                Some(&target) => target,
+                None => continue,
+            };
+            let distance = positions[target] as i64 - positions[i] as i64 - 1;
+            if distance.unsigned_abs() as usize > Limit::JumpDistance.max() {
+                // the statement the jump leaves or loops back to
+                let first = cmp::min(i, target);
+                let location = res_alloc.stat_starts
+                    .iter()
+                    .rev()
+                    .find(|&&(start, _)| start <= first)
+                    .map_or(Location::default(), |&(_, location)| location);
+                return Err(IRGen::limit_error(res_alloc, Limit::JumpDistance, location));
+            }
+        }
+        Ok(())
+    }
+
+    fn limit_error(res_alloc: &ResourceAlloc, limit: Limit, location: Location) -> CompileError {
+        CompileError::Limit(LimitError {
+            limit,
+            function: res_alloc.function,
+            location,
+        })
+    }
+
     /// split Vec<TableEntry> to hash part(named) and array part(unnamed)
-    fn split_table_entrys(entrys: &Vec<TableEntry>) -> (Vec<(Expr, Expr)>, Vec<Expr>) {
+    fn split_table_entrys(entrys: &Vec<TableEntry>) -> (Vec<(ExprId, ExprId)>, Vec<ExprId>) {
         let mut hash_part = vec![];
         let mut array_part = vec![];
-        for &(ref key, ref value) in entrys {
+        for &(key, value) in entrys {
             match key {
-                &Some(ref k) => hash_part.push((k.clone(), value.clone())),
-                &None => array_part.push(value.clone()),
+                Some(k) => hash_part.push((k, value)),
+                None => array_part.push(value),
             }
         }
         (hash_part, array_part)
     }
 
+    /// a constant as RK(_) operand
+    /// one beyond MAXINDEXRK is loaded into a register first
+    fn const_rk(
+        const_pos: Usize,
+        res_alloc: &mut ResourceAlloc,
+        instructions: &mut Vec<OpMode>,
+        expect: Option<Expect>,
+    ) -> Result<(bool, u32), CompileError> {
+        if const_pos <= MAXINDEXRK {
+            return Ok((false, 0x100 | const_pos));
+        }
+        let reg = if let Some(expect) = extract_expect_reg(expect)? {
+            expect
+        } else {
+            res_alloc.reg_alloc.push()
+        };
+        IRGen::emit_iABx(instructions, OpName::LOADK, reg, const_pos);
+        Ok((true, reg))
+    }
+
     /// for RK(_) field, register id and const id can be merged
     /// use 9th bit as sign bit
     /// 8bit as num field
@@ -1113,24 +1502,33 @@ impl IRGen {
     /// unsigned (0) for register
     fn reg_constid_merge(
         &mut self,
-        expr: &Expr,
+        expr: ExprId,
         res_alloc: &mut ResourceAlloc,
         instructions: &mut Vec<OpMode>,
         expect: Option<Expect>,
     ) -> Result<(bool, u32), CompileError> {
-        let reg_or_const = match expr {
-            &Expr::Num(num) => (
-                false,
-                0x100 | res_alloc.const_alloc.push(ConstType::Real(num)),
-            ),
-            &Expr::Boole(boolean) => (
-                false,
-                0x100 | res_alloc.const_alloc.push(ConstType::Boole(boolean)),
-            ),
-            &Expr::Str(ref s) => (
-                false,
-                0x100 | res_alloc.const_alloc.push(ConstType::Str(s.clone())),
-            ),
+        let reg_or_const = match self.ast[expr] {
+            Expr::Num(num) => {
+                let const_pos = res_alloc.const_alloc.push(ConstType::Real(num));
+                IRGen::const_rk(const_pos, res_alloc, instructions, expect)?
+            }
+            Expr::Boole(boolean) => {
+                let const_pos = res_alloc.const_alloc.push(ConstType::Boole(boolean));
+                IRGen::const_rk(const_pos, res_alloc, instructions, expect)?
+            }
+            Expr::Str(s) => {
+                let const_pos = res_alloc.const_alloc.push_str(s, &self.ast.interner);
+                IRGen::const_rk(const_pos, res_alloc, instructions, expect)?
+            }
+            // a negative literal
+            Expr::UnaryOp(FlagType::Minus, operand) => match self.ast[operand] {
+                Expr::Num(num) => {
+                    let const_pos = res_alloc.const_alloc.push(ConstType::Real(-num));
+                    IRGen::const_rk(const_pos, res_alloc, instructions, expect)?
+                }
+                _ => self.visit_r_expr(expr, res_alloc, instructions, expect)?,
+            },
+            Expr::Paren(inner) => self.reg_constid_merge(inner, res_alloc, instructions, expect)?,
             _ => self.visit_r_expr(expr, res_alloc, instructions, expect)?,
         };
         Ok(reg_or_const)
@@ -1151,8 +1549,8 @@ pub mod tests {
         ",
         )).unwrap();
 
-        let mut compiler = IRGen::new();
-        assert_eq!(compiler.generate_ir(&ast), Ok(()));
+        let mut compiler = IRGen::new(&ast);
+        assert_eq!(compiler.generate_ir(), Ok(()));
     }
 
     #[test]
@@ -1167,8 +1565,8 @@ pub mod tests {
         ",
         )).unwrap();
 
-        let mut compiler = IRGen::new();
-        assert_eq!(compiler.generate_ir(&ast), Ok(()));
+        let mut compiler = IRGen::new(&ast);
+        assert_eq!(compiler.generate_ir(), Ok(()));
     }
 
     #[test]
@@ -1183,8 +1581,8 @@ pub mod tests {
             local d = b + c
         ",
         )).unwrap();
-        let mut compiler = IRGen::new();
-        assert_eq!(compiler.generate_ir(&ast), Ok(()));
+        let mut compiler = IRGen::new(&ast);
+        assert_eq!(compiler.generate_ir(), Ok(()));
         // println!("{:?}", compiler.root_function);
     }
     #[test]
@@ -1195,8 +1593,8 @@ pub mod tests {
             local c = not ( 2 <= 3 or a == b )
         ",
         )).unwrap();
-        let mut compiler = IRGen::new();
-        assert_eq!(compiler.generate_ir(&ast), Ok(()));
+        let mut compiler = IRGen::new(&ast);
+        assert_eq!(compiler.generate_ir(), Ok(()));
         // println!("{:?}", compiler.root_function);
     }
     #[test]
@@ -1214,8 +1612,8 @@ pub mod tests {
             end
         ",
         )).expect("Parse Error");
-        let mut compiler = IRGen::new();
-        assert_eq!(compiler.generate_ir(&ast), Ok(()));
+        let mut compiler = IRGen::new(&ast);
+        assert_eq!(compiler.generate_ir(), Ok(()));
         // println!("{:?}", compiler.root_function);
     }
     #[test]
@@ -1232,8 +1630,8 @@ pub mod tests {
             end
         ",
         )).expect("Parse Error");
-        let mut compiler = IRGen::new();
-        assert_eq!(compiler.generate_ir(&ast), Ok(()));
+        let mut compiler = IRGen::new(&ast);
+        assert_eq!(compiler.generate_ir(), Ok(()));
         // println!("Byte Code: {:?}", compiler.root_function);
     }
     #[test]
@@ -1249,8 +1647,8 @@ pub mod tests {
             end
         ",
         )).expect("Parse Error");
-        let mut compiler = IRGen::new();
-        assert_eq!(compiler.generate_ir(&ast), Ok(()));
+        let mut compiler = IRGen::new(&ast);
+        assert_eq!(compiler.generate_ir(), Ok(()));
         println!("Byte code: {:?}", compiler.root_function);
     }
     #[test]
@@ -1260,8 +1658,8 @@ pub mod tests {
             local a, b, c = 1, 2, 1.5
         ",
         )).expect("Parse Error");
-        let mut compiler = IRGen::new();
-        assert_eq!(compiler.generate_ir(&ast), Ok(()));
+        let mut compiler = IRGen::new(&ast);
+        assert_eq!(compiler.generate_ir(), Ok(()));
         println!("Byte code: {:?}", compiler.root_function);
     }
     #[test]
@@ -1273,9 +1671,9 @@ pub mod tests {
                            1, 2, 3 }
         ",
         )).expect("Parse Error");
-        let mut compiler = IRGen::new();
+        let mut compiler = IRGen::new(&ast);
         println!("Ast: {:?}", ast);
-        assert_eq!(compiler.generate_ir(&ast), Ok(()));
+        assert_eq!(compiler.generate_ir(), Ok(()));
         println!("Byte code: {:?}", compiler.root_function);
     }
     #[test]
@@ -1289,9 +1687,9 @@ pub mod tests {
                               table['subtable'][1]
         ",
         )).expect("Parse Error");
-        let mut compiler = IRGen::new();
+        let mut compiler = IRGen::new(&ast);
         println!("Ast: {:?}", ast);
-        assert_eq!(compiler.generate_ir(&ast), Ok(()));
+        assert_eq!(compiler.generate_ir(), Ok(()));
         println!("Byte code: {:?}", compiler.root_function);
     }
     #[test]
@@ -1313,9 +1711,9 @@ pub mod tests {
             stu.grades:add_grade('C')
         ",
         )).expect("Parse Error");
-        let mut compiler = IRGen::new();
+        let mut compiler = IRGen::new(&ast);
         println!("Ast: {:?}", ast);
-        assert_eq!(compiler.generate_ir(&ast), Ok(()));
+        assert_eq!(compiler.generate_ir(), Ok(()));
         println!("Byte code: {:?}", compiler.root_function);
     }
 
@@ -1328,7 +1726,76 @@ pub mod tests {
         ",
         )).expect("Parse error");
 
-        let mut compiler = IRGen::new();
-        assert_eq!(compiler.generate_ir(&ast), Ok(()));
+        let mut compiler = IRGen::new(&ast);
+        assert_eq!(compiler.generate_ir(), Ok(()));
+    }
+
+    #[test]
+    fn register_reuse() {
+        let mut text = "local zero, one = 0, 1\nb = zero\n".to_string();
+        for _ in 0..3000 {
+            text.push_str("b = b + one\n");
+            text.push_str("if b > zero then local x, y = b, {b, b * one, k = b} u = y end\n");
+            text.push_str("print(b, (b + one) * (b - one), u.k)\n");
+        }
+        let ast = Parser::<Chars>::ast_from_text(&text).expect("Parse error");
+        let mut compiler = IRGen::new(&ast);
+        assert_eq!(compiler.generate_ir(), Ok(()));
+        assert_eq!(compiler.root_function.stack_size, 7);
+    }
+
+    #[test]
+    fn implementation_limits() {
+        let limit_error = |text: String| {
+            let ast = Parser::<Chars>::ast_from_text(&text).expect("Parse error");
+            match IRGen::new(&ast).generate_ir() {
+                Err(err @ CompileError::Limit(_)) => err.to_string(),
+                other => panic!("Limit error expected, got {:?}", other),
+            }
+        };
+        let locals = (0..201).map(|i| format!("local a{} = {}\n", i, i)).collect::<String>();
+        assert_eq!(limit_error(format!("f = function()\n{}end", locals)),
+                   "202:1: function at line 1 has more than 200 local variables");
+        let names = (0..61).map(|i| format!("u{}", i)).collect::<Vec<_>>().join(", ");
+        let upvalues = format!("local {}\nf = function() print({}) end", names, names);
+        assert_eq!(limit_error(upvalues),
+                   "2:5: function at line 2 has more than 60 upvalues");
+        let items = (0..0x3FFFF).map(|i| format!("{},", i)).collect::<String>();
+        let constants = format!("x = 1\nx = {{{}}}", items);
+        assert_eq!(limit_error(constants), "2:1: main function has more than 262143 constants");
+        let registers = format!("print({})", vec!["a"; 300].join(", "));
+        assert_eq!(limit_error(registers), "1:1: main function needs more than 250 registers");
+    }
+
+    #[test]
+    fn constant_dedup() {
+        let ast = Parser::<Chars>::ast_from_text(&String::from(
+            "x = {a = 1, b = 1, c = true, d = true, e = 0, f = -0, g = \"a\"}",
+        )).unwrap();
+        let mut compiler = IRGen::new(&ast);
+        assert_eq!(compiler.generate_ir(), Ok(()));
+        let constants = &compiler.root_function.constants;
+        assert_eq!(constants.len(), 12);
+        // 0 and -0 are equal but kept apart
+        match (&constants[8], &constants[10]) {
+            (&ConstType::Real(zero), &ConstType::Real(neg_zero)) => {
+                assert!(!zero.is_sign_negative() && neg_zero.is_sign_negative())
+            }
+            other => panic!("zeros expected, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn jump_distance() {
+        let mut res_alloc = ResourceAlloc::new(Some(3));
+        res_alloc.stat_starts.push((0, Location { line: 4, column: 5 }));
+        let mut instructions = vec![OpMode::rJMP(1)];
+        instructions.extend(vec![OpMode::iABC(OpName::MOVE, 0, 1, 0); 0x1FFFF]);
+        instructions.push(OpMode::Label(1));
+        assert_eq!(IRGen::check_jumps(&instructions, &res_alloc), Ok(()));
+        instructions.insert(1, OpMode::iABC(OpName::MOVE, 0, 1, 0));
+        let err = IRGen::check_jumps(&instructions, &res_alloc).unwrap_err();
+        assert_eq!(err.to_string(),
+                   "4:5: function at line 3 has a jump over more than 131071 instructions");
     }
 }
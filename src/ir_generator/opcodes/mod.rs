@@ -37,6 +37,8 @@ pub enum OpMode {
     rJMP(Label),
     rForPrep(u32, Label),
     rForLoop(u32, Label),
+    /// a word that is not an instruction, the batch of an extended SETLIST
+    Raw(u32),
 }
 
 #[allow(dead_code)]
@@ -108,7 +110,8 @@ pub fn get_opflag_opname_map() -> HashMap<FlagType, OpName> {
         FlagType::Plus => OpName::ADD,
         FlagType::Minus => OpName::SUB,
         FlagType::Mul => OpName::MUL,
-        FlagType::Div => OpName::DIV
+        FlagType::Div => OpName::DIV,
+        FlagType::Pow => OpName::POW
     }
 }
 
@@ -117,30 +120,31 @@ pub fn get_opflag_opname_map() -> HashMap<FlagType, OpName> {
 pub struct OpcodeBuilder {
 }
 
+/// None if a parameter does not fit its field
 #[allow(non_snake_case)]
 impl OpcodeBuilder {
-    pub fn iABC(op: OpName, A: u32, B: u32, C: u32) -> u32 {
+    pub fn iABC(op: OpName, A: u32, B: u32, C: u32) -> Option<u32> {
         if A > mask_1(SIZE_A, 0) || B > mask_1(SIZE_B, 0) || C > mask_1(SIZE_C, 0) {
-            panic!("Instuction parameter overflow");
+            return None;
         }
-        (op as u32) | A << POS_A | B << POS_B | C << POS_C
+        Some((op as u32) | A << POS_A | B << POS_B | C << POS_C)
     }
 
-    pub fn iABx(op: OpName, A: u32, Bx: u32) -> u32 {
-        if A > mask_1(SIZE_A, 0) || Bx > mask_0(SIZE_Bx, 0) {
-            panic!("Instuction parameter overflow");
+    pub fn iABx(op: OpName, A: u32, Bx: u32) -> Option<u32> {
+        if A > mask_1(SIZE_A, 0) || Bx > mask_1(SIZE_Bx, 0) {
+            return None;
         }
-        (op as u32) | A << POS_A | Bx << POS_Bx
+        Some((op as u32) | A << POS_A | Bx << POS_Bx)
     }
 
-    pub fn iAsBx(op: OpName, A: u32, sBx: i32) -> u32 {
+    pub fn iAsBx(op: OpName, A: u32, sBx: i32) -> Option<u32> {
+        if A > mask_1(SIZE_A, 0) || !(-0x1ffff..=0x20000).contains(&sBx) {
+            return None;
+        }
         // trancate
         let bias = Wrapping(0x1ffff_u32);
         let trancated = (Wrapping(sBx as u32) + bias).0 & 0x3ffff_u32;
-        if A > mask_1(SIZE_A, 0) {
-            panic!("Instuction parameter overflow");
-        }
-        (op as u32) | A << POS_A | trancated << POS_Bx
+        Some((op as u32) | A << POS_A | trancated << POS_Bx)
     }
 }
 
@@ -157,13 +161,15 @@ mod tests {
     #[test]
     fn test_opcode_generator() {
         // loadk 0 1
-        assert_eq!(OpcodeBuilder::iABx(OpName::LOADK, 0, 1), 0x00004001_u32);
-        assert_eq!(OpcodeBuilder::iABC(OpName::RETURN, 0, 1, 0), 0x0080001e_u32);        
-        assert_eq!(OpcodeBuilder::iABC(OpName::RETURN, 3, 4, 0), 0x020000de_u32);
-        assert_eq!(OpcodeBuilder::iABx(OpName::LOADK, 1, 1), 0x00004041_u32);
-        assert_eq!(OpcodeBuilder::iABC(OpName::ADD, 2, 0, 1), 0x0000408c_u32);
-        assert_eq!(OpcodeBuilder::iABC(OpName::LT, 0, 1, 0), 0x00800018_u32);
-        assert_eq!(OpcodeBuilder::iAsBx(OpName::JMP, 0, 1), 0x80000016_u32);
-
+        assert_eq!(OpcodeBuilder::iABx(OpName::LOADK, 0, 1), Some(0x00004001_u32));
+        assert_eq!(OpcodeBuilder::iABC(OpName::RETURN, 0, 1, 0), Some(0x0080001e_u32));
+        assert_eq!(OpcodeBuilder::iABC(OpName::RETURN, 3, 4, 0), Some(0x020000de_u32));
+        assert_eq!(OpcodeBuilder::iABx(OpName::LOADK, 1, 1), Some(0x00004041_u32));
+        assert_eq!(OpcodeBuilder::iABC(OpName::ADD, 2, 0, 1), Some(0x0000408c_u32));
+        assert_eq!(OpcodeBuilder::iABC(OpName::LT, 0, 1, 0), Some(0x00800018_u32));
+        assert_eq!(OpcodeBuilder::iAsBx(OpName::JMP, 0, 1), Some(0x80000016_u32));
+        assert_eq!(OpcodeBuilder::iABC(OpName::MOVE, 256, 0, 0), None);
+        assert_eq!(OpcodeBuilder::iABx(OpName::LOADK, 0, 0x40000), None);
+        assert_eq!(OpcodeBuilder::iAsBx(OpName::JMP, 0, -0x20000), None);
     }
 }
@@ -0,0 +1,690 @@
+use std::collections::{HashMap, HashSet};
+use parser::types::*;
+use lexer::interner::Symbol;
+use lexer::tokens::Location;
+use super::types::{CompileError, CompileOptions, Usize};
+
+/// index of a local variable in `Resolution`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct LocalId(u32);
+
+impl LocalId {
+    pub fn index(self) -> usize {
+        self.0 as usize
+    }
+}
+
+/// what a name refers to where it is used
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Resolved {
+    Local(LocalId),
+    /// position in the upvalue list of the enclosing function
+    UpValue(Usize),
+    Global,
+}
+
+/// how a closure captures one of its upvalues when it is created
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum UpValueDesc {
+    /// a local of the function creating the closure
+    Local(LocalId),
+    /// an upvalue of the function creating the closure
+    UpValue(Usize),
+}
+
+/// where locals are declared
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum DeclSite {
+    /// parameters of the function whose body is the block
+    Params(BlockId),
+    /// the statement at the index of the block
+    Stat(BlockId, usize),
+}
+
+/// names of the ast bound to locals, upvalues and globals
+#[derive(Debug, Default)]
+pub struct Resolution {
+    locals: Vec<Name>,
+    uses: HashMap<ExprId, Resolved>,
+    decls: HashMap<DeclSite, Vec<LocalId>>,
+    upvalues: HashMap<BlockId, Vec<UpValueDesc>>,
+    captured: HashSet<LocalId>,
+    read: HashSet<LocalId>,
+    /// locals assigned after their declaration
+    assigned: HashSet<LocalId>,
+    /// the local each name refers to, also through upvalues
+    referred: HashMap<ExprId, LocalId>,
+    /// locals declared while a local of the same name is visible
+    shadowed: HashMap<LocalId, LocalId>,
+}
+
+impl Resolution {
+    pub fn new() -> Resolution {
+        Resolution {
+            locals: vec![],
+            uses: HashMap::new(),
+            decls: HashMap::new(),
+            upvalues: HashMap::new(),
+            captured: HashSet::new(),
+            read: HashSet::new(),
+            assigned: HashSet::new(),
+            referred: HashMap::new(),
+            shadowed: HashMap::new(),
+        }
+    }
+
+    pub fn local_count(&self) -> usize {
+        self.locals.len()
+    }
+
+    pub fn local_name(&self, id: LocalId) -> Name {
+        self.locals[id.index()]
+    }
+
+    /// binding of a name expression
+    pub fn lookup(&self, expr: ExprId) -> Option<Resolved> {
+        self.uses.get(&expr).cloned()
+    }
+
+    /// locals declared at the site, in declaration order
+    pub fn declared(&self, site: DeclSite) -> &[LocalId] {
+        self.decls.get(&site).map_or(&[], |ids| ids.as_slice())
+    }
+
+    /// upvalues of the function whose body is the block
+    pub fn upvalues(&self, body: BlockId) -> &[UpValueDesc] {
+        self.upvalues.get(&body).map_or(&[], |list| list.as_slice())
+    }
+
+    /// if a closure refers to the local
+    pub fn is_captured(&self, id: LocalId) -> bool {
+        self.captured.contains(&id)
+    }
+
+    /// if the value of the local is ever used
+    pub fn is_read(&self, id: LocalId) -> bool {
+        self.read.contains(&id)
+    }
+
+    /// if the local is the target of an assignment
+    pub fn is_assigned(&self, id: LocalId) -> bool {
+        self.assigned.contains(&id)
+    }
+
+    /// the local a name expression refers to, in any enclosing function
+    pub fn local_of(&self, expr: ExprId) -> Option<LocalId> {
+        self.referred.get(&expr).cloned()
+    }
+
+    /// the local hidden by the declaration of id
+    pub fn shadowed(&self, id: LocalId) -> Option<LocalId> {
+        self.shadowed.get(&id).cloned()
+    }
+}
+
+/// locals and upvalues of a function being resolved
+#[derive(Debug)]
+struct FuncScope {
+    body: BlockId,
+    /// where the function starts, None for the main chunk
+    start: Option<Location>,
+    /// visible locals of each enclosing block, the innermost last
+    /// later declarations shadow earlier ones
+    blocks: Vec<HashMap<Name, LocalId>>,
+    /// locals declared in each enclosing block, shadowed ones included
+    active: Vec<usize>,
+    upvalues: Vec<UpValueDesc>,
+    /// position in upvalues of each captured local
+    captured: HashMap<LocalId, Usize>,
+}
+
+impl FuncScope {
+    fn new(body: BlockId, start: Option<Location>) -> FuncScope {
+        FuncScope {
+            body,
+            start,
+            // parameters are declared in a scope around the body
+            blocks: vec![HashMap::new()],
+            active: vec![0],
+            upvalues: vec![],
+            captured: HashMap::new(),
+        }
+    }
+
+    fn limit_error(&self, limit: Limit, location: Location) -> CompileError {
+        CompileError::Limit(LimitError {
+            limit,
+            function: self.start.map(|start| start.line),
+            location,
+        })
+    }
+}
+
+/// binds every name of the ast before code generation
+/// enclosing functions are kept on a stack, the innermost last
+#[derive(Debug)]
+pub struct Resolver<'a> {
+    ast: &'a Ast,
+    funcs: Vec<FuncScope>,
+    /// globals assigned anywhere in the source
+    globals: HashSet<Symbol>,
+    /// global reads, checked once all assignments are known
+    global_reads: Vec<(Name, ExprId)>,
+    /// constant fields of globals assigned anywhere in the source
+    fields: HashSet<(Name, Symbol)>,
+    /// reads of a constant field of a global: global, field, table ref
+    field_reads: Vec<(Name, Symbol, ExprId)>,
+    options: &'a CompileOptions,
+    resolution: Resolution,
+}
+
+impl<'a> Resolver<'a> {
+    pub fn resolve(ast: &'a Ast) -> Result<Resolution, CompileError> {
+        Resolver::resolve_with_options(ast, &CompileOptions::default())
+    }
+
+    pub fn resolve_with_options(
+        ast: &'a Ast,
+        options: &'a CompileOptions,
+    ) -> Result<Resolution, CompileError> {
+        let mut resolver = Resolver {
+            ast,
+            funcs: vec![],
+            globals: HashSet::new(),
+            global_reads: vec![],
+            fields: HashSet::new(),
+            field_reads: vec![],
+            options,
+            resolution: Resolution::new(),
+        };
+        match ast.root {
+            Node::Block(block) => resolver.function(&[], block, None)?,
+            // can not find entry block
+            Node::Expr(_) => return Err(CompileError::SyntexError),
+        }
+        if resolver.options.strict_globals {
+            resolver.check_globals()?;
+        }
+        Ok(resolver.resolution)
+    }
+
+    /// in strict mode, every global read must be assigned somewhere
+    /// in the source or be one of the known globals,
+    /// fields read from known global tables must exist
+    fn check_globals(&self) -> Result<(), CompileError> {
+        let ast = self.ast;
+        let globals = &self.options.globals;
+        let undefined_globals = self.global_reads
+            .iter()
+            .filter(|&&(name, _)| !self.globals.contains(&name))
+            .filter(|&&(name, _)| !globals.contains(ast.name(name)))
+            .map(|&(name, expr)| {
+                let location = ast.location(expr);
+                (location, CompileError::UndefinedGlobal(ast.name(name).to_string(), location))
+            });
+        // fields of a global table assigned in the source are not known
+        let undefined_fields = self.field_reads
+            .iter()
+            .filter(|&&(name, _, _)| !self.globals.contains(&name))
+            .filter(|&&(name, field, _)| !self.fields.contains(&(name, field)))
+            .filter(|&&(name, field, _)| !globals.has_field(ast.name(name), ast.name(field)))
+            .map(|&(name, field, expr)| {
+                let location = ast.location(expr);
+                let err = CompileError::UndefinedField(
+                    ast.name(name).to_string(),
+                    ast.name(field).to_string(),
+                    location,
+                );
+                (location, err)
+            });
+        match undefined_globals.chain(undefined_fields).min_by_key(|&(location, _)| location) {
+            Some((_, err)) => Err(err),
+            None => Ok(()),
+        }
+    }
+
+    fn function(
+        &mut self,
+        paras: &[Name],
+        body: BlockId,
+        start: Option<Location>,
+    ) -> Result<(), CompileError> {
+        self.funcs.push(FuncScope::new(body, start));
+        self.declare(DeclSite::Params(body), paras)?;
+        self.block(body)?;
+        let scope = self.funcs.pop().expect("Function scope underflow");
+        if scope.upvalues.len() > Limit::UpValues.max() {
+            let location = scope.start.unwrap_or_default();
+            return Err(scope.limit_error(Limit::UpValues, location));
+        }
+        self.resolution.upvalues.insert(scope.body, scope.upvalues);
+        Ok(())
+    }
+
+    fn block(&mut self, block: BlockId) -> Result<(), CompileError> {
+        let ast = self.ast;
+        self.enter_scope();
+        for (i, stat) in ast[block].stats.iter().enumerate() {
+            self.stat(stat, DeclSite::Stat(block, i))?;
+        }
+        if let Some(ref ret) = ast[block].ret {
+            self.exprs(ret)?;
+        }
+        self.leave_scope();
+        Ok(())
+    }
+
+    fn enter_scope(&mut self) {
+        let scope = self.current();
+        scope.blocks.push(HashMap::new());
+        scope.active.push(0);
+    }
+
+    fn leave_scope(&mut self) {
+        let scope = self.current();
+        scope.blocks.pop();
+        scope.active.pop();
+    }
+
+    fn current(&mut self) -> &mut FuncScope {
+        self.funcs.last_mut().expect("No function scope")
+    }
+
+    fn stat(&mut self, stat: &Stat, site: DeclSite) -> Result<(), CompileError> {
+        match *stat {
+            Stat::Empty | Stat::Break => Ok(()),
+            Stat::Assign(ref targets, ref exprs) => {
+                self.exprs(exprs)?;
+                for &target in targets {
+                    match self.ast[target] {
+                        // assigning to an unknown name defines a global
+                        Expr::Var(Var::Name(name)) => {
+                            let resolved = match self.lookup(name) {
+                                Some((resolved, id)) => {
+                                    self.resolution.assigned.insert(id);
+                                    self.resolution.referred.insert(target, id);
+                                    resolved
+                                }
+                                None => {
+                                    self.globals.insert(name);
+                                    Resolved::Global
+                                }
+                            };
+                            self.resolution.uses.insert(target, resolved);
+                        }
+                        // a table ref target writes the field instead of reading it
+                        Expr::TableRef(table, key) => {
+                            self.exprs(&[table, key])?;
+                            if let Some(field) = self.global_field(table, key) {
+                                self.fields.insert(field);
+                            }
+                        }
+                        _ => self.expr(target)?,
+                    }
+                }
+                Ok(())
+            }
+            Stat::AssignLocal(ref names, ref exprs) => {
+                self.exprs(exprs)?;
+                self.declare(site, names)
+            }
+            Stat::IfElse(test, then_block, else_block) => {
+                self.expr(test)?;
+                self.block(then_block)?;
+                if let Some(else_block) = else_block {
+                    self.block(else_block)?;
+                }
+                Ok(())
+            }
+            Stat::While(test, block) => {
+                self.expr(test)?;
+                self.block(block)
+            }
+            // loop variables are only visible in the body
+            Stat::ForRange(ref names, ref exprs, block) => {
+                self.exprs(exprs)?;
+                self.enter_scope();
+                self.declare(site, names)?;
+                self.block(block)?;
+                self.leave_scope();
+                Ok(())
+            }
+            Stat::ForNumeric(name, start, end, step, block) => {
+                self.exprs(&[start, end, step])?;
+                self.enter_scope();
+                self.declare(site, &[name])?;
+                self.block(block)?;
+                self.leave_scope();
+                Ok(())
+            }
+            Stat::GeneralCall(func, ref args) => {
+                self.expr(func)?;
+                self.exprs(args)
+            }
+            Stat::ColonCall(table, _, ref args) => {
+                self.expr(table)?;
+                self.exprs(args)
+            }
+            Stat::Ret(ref exprs) => self.exprs(exprs),
+        }
+    }
+
+    fn exprs(&mut self, exprs: &[ExprId]) -> Result<(), CompileError> {
+        for &expr in exprs {
+            self.expr(expr)?;
+        }
+        Ok(())
+    }
+
+    fn expr(&mut self, expr: ExprId) -> Result<(), CompileError> {
+        let ast = self.ast;
+        match ast[expr] {
+            Expr::Num(_) | Expr::Boole(_) | Expr::Str(_) | Expr::Nil | Expr::Vararg => Ok(()),
+            Expr::Var(Var::Name(name)) => {
+                // unknown names are globals, which may be nil
+                let resolved = match self.lookup(name) {
+                    Some((resolved, id)) => {
+                        self.resolution.read.insert(id);
+                        self.resolution.referred.insert(expr, id);
+                        resolved
+                    }
+                    None => {
+                        self.global_reads.push((name, expr));
+                        Resolved::Global
+                    }
+                };
+                self.resolution.uses.insert(expr, resolved);
+                Ok(())
+            }
+            Expr::TableRef(table, key) => {
+                self.exprs(&[table, key])?;
+                if let Some((name, field)) = self.global_field(table, key) {
+                    self.field_reads.push((name, field, expr));
+                }
+                Ok(())
+            }
+            Expr::BinOp(_, left, right) => {
+                self.expr(left)?;
+                self.expr(right)
+            }
+            Expr::UnaryOp(_, operand) | Expr::Paren(operand) => self.expr(operand),
+            Expr::FunctionDef((ref paras, _), body) => {
+                self.function(paras, body, Some(ast.location(expr)))
+            }
+            Expr::GeneralCall(func, ref args) => {
+                self.expr(func)?;
+                self.exprs(args)
+            }
+            Expr::ColonCall(table, _, ref args) => {
+                self.expr(table)?;
+                self.exprs(args)
+            }
+            Expr::TableCtor(ref entrys) => {
+                for &(key, value) in entrys {
+                    if let Some(key) = key {
+                        self.expr(key)?;
+                    }
+                    self.expr(value)?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// global and field of a resolved `global.field` or `global["field"]`
+    fn global_field(&self, table: ExprId, key: ExprId) -> Option<(Name, Symbol)> {
+        match (&self.ast[table], &self.ast[key]) {
+            (&Expr::Var(Var::Name(name)), &Expr::Str(field))
+                if self.resolution.lookup(table) == Some(Resolved::Global) => Some((name, field)),
+            _ => None,
+        }
+    }
+
+    /// new locals are visible from the next statement on
+    fn declare(&mut self, site: DeclSite, names: &[Name]) -> Result<(), CompileError> {
+        let mut ids = Vec::with_capacity(names.len());
+        for &name in names {
+            let id = LocalId(self.resolution.locals.len() as u32);
+            if let Some(outer) = self.visible(name) {
+                self.resolution.shadowed.insert(id, outer);
+            }
+            self.resolution.locals.push(name);
+            self.current()
+                .blocks
+                .last_mut()
+                .expect("No block scope")
+                .insert(name, id);
+            ids.push(id);
+        }
+        self.resolution.decls.insert(site, ids);
+        let scope = self.funcs.last_mut().expect("No function scope");
+        *scope.active.last_mut().expect("No block scope") += names.len();
+        if scope.active.iter().sum::<usize>() > Limit::Locals.max() {
+            let location = match site {
+                DeclSite::Stat(block, i) => self.ast[block].locations[i],
+                DeclSite::Params(_) => scope.start.unwrap_or_default(),
+            };
+            return Err(scope.limit_error(Limit::Locals, location));
+        }
+        Ok(())
+    }
+
+    /// None if the name is not a local of any enclosing function
+    /// ret: (binding in the current function, local referred to)
+    fn lookup(&mut self, name: Name) -> Option<(Resolved, LocalId)> {
+        let level = self.funcs.len() - 1;
+        self.lookup_at(level, name)
+    }
+
+    /// the local a name refers to, without capturing it
+    fn visible(&self, name: Name) -> Option<LocalId> {
+        self.funcs
+            .iter()
+            .rev()
+            .flat_map(|func| func.blocks.iter().rev())
+            .filter_map(|block| block.get(&name))
+            .next()
+            .cloned()
+    }
+
+    /// look up a name from the function at level outwards,
+    /// capturing it as upvalue in every function it passes through
+    /// ret: (binding in the function at level, local captured)
+    fn lookup_at(&mut self, level: usize, name: Name) -> Option<(Resolved, LocalId)> {
+        let local = self.funcs[level]
+            .blocks
+            .iter()
+            .rev()
+            .filter_map(|block| block.get(&name))
+            .next()
+            .cloned();
+        if let Some(id) = local {
+            return Some((Resolved::Local(id), id));
+        }
+        if level == 0 {
+            return None;
+        }
+        let (outer, id) = self.lookup_at(level - 1, name)?;
+        if let Resolved::Local(id) = outer {
+            self.resolution.captured.insert(id);
+        }
+        let scope = &mut self.funcs[level];
+        if let Some(&pos) = scope.captured.get(&id) {
+            return Some((Resolved::UpValue(pos), id));
+        }
+        let pos = scope.upvalues.len() as Usize;
+        scope.upvalues.push(match outer {
+            Resolved::Local(id) => UpValueDesc::Local(id),
+            Resolved::UpValue(outer_pos) => UpValueDesc::UpValue(outer_pos),
+            Resolved::Global => unreachable!(),
+        });
+        scope.captured.insert(id, pos);
+        Some((Resolved::UpValue(pos), id))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use parser::Parser;
+    use lexer::tokens::Location;
+    use std::str::Chars;
+
+    fn resolve(text: &str) -> (Ast, Resolution) {
+        let ast = Parser::<Chars>::ast_from_text(&text.to_string()).expect("Parse Error");
+        let resolution = Resolver::resolve(&ast).expect("Resolve Error");
+        (ast, resolution)
+    }
+
+    fn resolve_strict(text: &str) -> Result<Resolution, CompileError> {
+        let ast = Parser::<Chars>::ast_from_text(&text.to_string()).expect("Parse Error");
+        let options = CompileOptions {
+            strict_globals: true,
+            ..CompileOptions::default()
+        };
+        Resolver::resolve_with_options(&ast, &options)
+    }
+
+    /// locals declared by a statement of the main chunk
+    fn declared_at(ast: &Ast, resolution: &Resolution, index: usize) -> Vec<LocalId> {
+        match ast.root {
+            Node::Block(root) => resolution.declared(DeclSite::Stat(root, index)).to_vec(),
+            _ => panic!("Root node should be a block"),
+        }
+    }
+
+    /// bindings of the uses of a name, in source order
+    fn uses_of(ast: &Ast, resolution: &Resolution, name: &str) -> Vec<Resolved> {
+        let name = ast.interner.get(name).expect("Name not interned");
+        let mut uses = resolution
+            .uses
+            .iter()
+            .filter(|&(&expr, _)| ast[expr] == Expr::Var(Var::Name(name)))
+            .map(|(&expr, &resolved)| (expr, resolved))
+            .collect::<Vec<_>>();
+        uses.sort_by_key(|&(expr, _)| expr);
+        uses.into_iter().map(|(_, resolved)| resolved).collect()
+    }
+
+    #[test]
+    fn locals_and_globals() {
+        let (ast, resolution) = resolve("local a = 1\nb = a\nprint(b)\nlocal a = a");
+        let a = declared_at(&ast, &resolution, 0)[0];
+        assert_eq!(
+            uses_of(&ast, &resolution, "a"),
+            vec![Resolved::Local(a), Resolved::Local(a)]
+        );
+        assert_eq!(
+            uses_of(&ast, &resolution, "b"),
+            vec![Resolved::Global, Resolved::Global]
+        );
+        assert_eq!(resolution.local_count(), 2);
+        assert_eq!(uses_of(&ast, &resolution, "print"), vec![Resolved::Global]);
+    }
+
+    #[test]
+    fn block_scopes() {
+        let (ast, resolution) = resolve(
+            "local x = 1
+            if x then
+                local x = x
+                y = x
+            end
+            for i = 1, 2 do local x = i end
+            local x = x
+            z = x",
+        );
+        let outer = declared_at(&ast, &resolution, 0)[0];
+        let shadowed = declared_at(&ast, &resolution, 3)[0];
+        let uses = uses_of(&ast, &resolution, "x");
+        assert_eq!(uses.len(), 5);
+        // the inner x is only visible in the if block
+        assert_eq!(uses[0], Resolved::Local(outer));
+        assert_eq!(uses[1], Resolved::Local(outer));
+        assert!(uses[2] != Resolved::Local(outer));
+        assert_eq!(uses[3], Resolved::Local(outer));
+        assert_eq!(uses[4], Resolved::Local(shadowed));
+        assert!(shadowed != outer);
+        assert!(uses_of(&ast, &resolution, "i").iter().all(|r| *r != Resolved::Global));
+        assert_eq!(
+            resolve_strict("for i = 1, 2 do end\nj = i").unwrap_err(),
+            CompileError::UndefinedGlobal("i".to_string(), Location::new(2, 5))
+        );
+    }
+
+    #[test]
+    fn reads_and_shadows() {
+        let (ast, resolution) = resolve(
+            "local a, b = 1
+            b = 2
+            f = function(a) return a end
+            if a then local b = b end",
+        );
+        let outer = declared_at(&ast, &resolution, 0);
+        assert!(resolution.is_read(outer[0]) && resolution.is_read(outer[1]));
+        let params = resolution
+            .decls
+            .iter()
+            .filter_map(|(site, ids)| match *site {
+                DeclSite::Params(_) => ids.first().cloned(),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(resolution.shadowed(params[0]), Some(outer[0]));
+        assert!(resolution.is_read(params[0]));
+        let (ast, resolution) = resolve("local x = 1\nx = 2");
+        let x = declared_at(&ast, &resolution, 0)[0];
+        assert!(!resolution.is_read(x));
+        assert_eq!(resolution.shadowed(x), None);
+    }
+
+    #[test]
+    fn upvalue_chain() {
+        let (ast, resolution) = resolve(
+            "local x, y = 1, 2
+            f = function()
+                return function() return y + x + y end
+            end",
+        );
+        let outer = declared_at(&ast, &resolution, 0);
+        // the inner function reuses the upvalue of y
+        assert_eq!(
+            uses_of(&ast, &resolution, "y"),
+            vec![Resolved::UpValue(0), Resolved::UpValue(0)]
+        );
+        assert_eq!(uses_of(&ast, &resolution, "x"), vec![Resolved::UpValue(1)]);
+        // the middle function captures both locals for the inner one
+        assert!(resolution.upvalues.values().any(|list| {
+            list == &vec![UpValueDesc::Local(outer[1]), UpValueDesc::Local(outer[0])]
+        }));
+        assert!(resolution.upvalues.values().any(|list| {
+            list == &vec![UpValueDesc::UpValue(0), UpValueDesc::UpValue(1)]
+        }));
+        assert!(resolution.is_captured(outer[0]) && resolution.is_captured(outer[1]));
+    }
+
+    #[test]
+    fn undefined_name() {
+        let (ast, resolution) = resolve("a = b");
+        assert_eq!(uses_of(&ast, &resolution, "b"), vec![Resolved::Global]);
+        assert_eq!(
+            resolve_strict("a = 1\nlocal t = {a, string.len('x')}\nreturn a + t[1] * b.c").unwrap_err(),
+            CompileError::UndefinedGlobal("b".to_string(), Location::new(3, 19))
+        );
+        // globals assigned later in the source are declared
+        assert!(resolve_strict("function_a = function() return b end\nb = print").is_ok());
+    }
+
+    #[test]
+    fn global_fields() {
+        assert_eq!(
+            resolve_strict("local n = string.len('x')\nreturn math.pi, math.tau").unwrap_err(),
+            CompileError::UndefinedField("math".to_string(), "tau".to_string(), Location::new(2, 17))
+        );
+        // new fields may be written, and locals named like globals are not checked
+        assert!(resolve_strict("string.split = 1\nreturn string.split").is_ok());
+        assert!(resolve_strict("math.tau = 1").is_ok());
+        assert!(resolve_strict("local math = {}\nreturn math.tau").is_ok());
+        assert!(resolve_strict("return _G.anything, string['sub']").is_ok());
+    }
+}
@@ -1,63 +1,62 @@
 use std::collections::HashMap;
-use std::ptr;
+use std::cmp;
 use super::types::*;
+use lexer::interner::{Interner, Symbol};
+use lexer::tokens::Location;
 
+/// registers of a function used as a stack
+/// locals and temporaries are pushed on top and released together
+/// when the statement or block that needs them ends
 #[derive(Debug)]
 pub struct RegisterAlloc {
-    name_indexer: HashMap<String, u32>,
+    /// first free register
     counter: u32,
+    /// most registers in use at once
+    max: u32,
 }
 
-// TODO: add register pool for nameless varibles
-//       so that temporary register can be reused
 impl RegisterAlloc {
     pub fn new() -> RegisterAlloc {
-        RegisterAlloc {
-            name_indexer: HashMap::new(),
-            counter: 0,
-        }
+        RegisterAlloc { counter: 0, max: 0 }
     }
 
-    pub fn push(&mut self, name: Option<&str>) -> Usize {
-        match name {
-            Some(name) => {
-                self.name_indexer.insert(name.to_string(), self.counter);
-                self.increament_count();
-                self.counter - 1
-            }
-            None => {
-                self.name_indexer.insert("__TEMP__".to_string() + &self.counter.to_string(),
-                                         self.counter);
-                self.increament_count();
-                self.counter - 1
-            }  
-        }
+    /// the limit is checked after each statement
+    pub fn push(&mut self) -> Usize {
+        self.counter += 1;
+        self.max = cmp::max(self.max, self.counter);
+        self.counter - 1
     }
 
-    //  allocate an register with provided register position
-    //  used when convering a temperary register to a named register
-    pub fn push_set(&mut self, name: &str, pos: Usize) -> Usize {
-        self.name_indexer.insert(name.to_string(), pos);
-        // do not need to increase counter
-        pos
+    /// stack size of the function
+    pub fn size(&self) -> Usize {
+        self.max
     }
 
-    pub fn size(&self) -> Usize {
+    /// first free register
+    pub fn top(&self) -> Usize {
         self.counter
     }
 
-    fn increament_count(&mut self) {
-        self.counter += 1;
-        if self.counter > 0xFF {
-            panic!("Register number overflow");
-        }
+    /// registers from top on can be reused
+    pub fn release_to(&mut self, top: Usize) {
+        self.counter = cmp::min(top, self.counter);
     }
 }
 
+/// key of a constant other than a string
+/// numbers are compared by bits, so 0 and -0 stay apart
+#[derive(Debug, PartialEq, Eq, Hash)]
+enum ConstKey {
+    Nil,
+    Boole(bool),
+    Real(u64),
+}
+
 #[derive(Debug)]
 pub struct ConstAlloc {
     storage: Vec<ConstType>,
-    str_index: HashMap<String, usize>,
+    str_index: HashMap<Symbol, usize>,
+    index: HashMap<ConstKey, usize>,
 }
 
 impl ConstAlloc {
@@ -65,86 +64,47 @@ impl ConstAlloc {
         ConstAlloc {
             storage: Vec::new(),
             str_index: HashMap::new(),
+            index: HashMap::new(),
         }
     }
 
+    /// number, boolean and nil constants are deduplicated
+    /// the limit is checked after each statement
     pub fn push(&mut self, val: ConstType) -> Usize {
-        if self.storage.len() >= 0xFF {
-            panic!("Const value number overflow");
-        }
-        match val {
-            ConstType::Str(ref s) => {
-                if let Some(&final_pos) = self.str_index.get(s) {
-                    final_pos as Usize
-                } else {
-                    self.storage.push(val.clone());
-                    let final_pos = self.storage.len() - 1;
-                    self.str_index.insert(s.clone(), final_pos);
-                    final_pos as Usize
-                }
-            }
-            _ => {
-                self.storage.push(val.clone());
-                (self.storage.len() - 1) as Usize
-            }
-        }
-    }
-
-    pub fn dump(self) -> Vec<ConstType> {
-        self.storage
-    }
-}
-
-#[derive(Debug)]
-pub struct UpValueAlloc {
-    name_indexer: HashMap<String, (Usize, Usize, Usize)>, /* (depth, pos in upvalue list, pos in parent stack) */
-    counter: usize,
-}
-
-impl UpValueAlloc {
-    pub fn new() -> UpValueAlloc {
-        UpValueAlloc {
-            name_indexer: HashMap::new(),
-            counter: 0,
+        let key = match val {
+            ConstType::Nil => Some(ConstKey::Nil),
+            ConstType::Boole(b) => Some(ConstKey::Boole(b)),
+            ConstType::Real(num) => Some(ConstKey::Real(num.to_bits())),
+            ConstType::Str(_) => None,
+        };
+        if let Some(&final_pos) = key.as_ref().and_then(|key| self.index.get(key)) {
+            return final_pos as Usize;
         }
-    }
-
-    pub fn push(&mut self, name: &str, depth: Usize, final_pos_in_parent: Usize) -> Usize {
-        match self.name_indexer.get(name) {
-            Some(&(_, pos, _)) => pos, // avoid duplication
-            None => {
-                self.name_indexer.insert(name.to_string(),
-                                         (depth, self.counter as Usize, final_pos_in_parent));
-                self.counter = self.counter + 1;
-                (self.counter - 1) as Usize
-            }
+        self.storage.push(val);
+        let final_pos = self.storage.len() - 1;
+        if let Some(key) = key {
+            self.index.insert(key, final_pos);
         }
+        final_pos as Usize
     }
 
-    pub fn into_list(self) -> Vec<(bool, Usize, Usize)> {
-        let mut list = Vec::with_capacity(self.name_indexer.len());
-        for &(depth, pos_in_ul, pos_in_parent) in self.name_indexer.values() {
-            // is_immidiate
-            // when is_immidiate, generate move
-            //     else generate getupvalue
-            list.push((if depth > 1 { false } else { true }, pos_in_ul, pos_in_parent));
+    /// string constants are deduplicated by symbol
+    /// the string is copied only when first pushed
+    pub fn push_str(&mut self, symbol: Symbol, interner: &Interner) -> Usize {
+        if let Some(&final_pos) = self.str_index.get(&symbol) {
+            return final_pos as Usize;
         }
-        list
-    }
-
-    /// if upvalue is already pushed into upvalue list
-    pub fn get(&self, name: &str) -> Option<Usize> {
-        self.name_indexer.get(name).map(|t| t.0) // extract pos in upvalue list
+        let final_pos = self.push(ConstType::Str(interner.resolve(symbol).to_string()));
+        self.str_index.insert(symbol, final_pos as usize);
+        final_pos
     }
 
-    // modify the register number where upvalue stored in upper scope
-    pub fn set_upper_index(&mut self, name: &str, final_pos: Usize) {
-        let &(depth, self_final_pos, _) = self.name_indexer.get(name).unwrap();
-        *self.name_indexer.get_mut(name).unwrap() = (depth, self_final_pos, final_pos);
+    pub fn size(&self) -> usize {
+        self.storage.len()
     }
 
-    pub fn size(&self) -> usize {
-        self.name_indexer.len()
+    pub fn dump(self) -> Vec<ConstType> {
+        self.storage
     }
 }
 
@@ -190,72 +150,58 @@ impl LabelAlloc {
     }
 }
 
+/// where `break` jumps to
+#[derive(Debug, Clone, Copy)]
+pub struct LoopExit {
+    pub label: Label,
+    /// first register of the loop to close, if the loop has captured locals
+    pub close: Option<Usize>,
+}
+
 #[derive(Debug)]
 pub struct ResourceAlloc {
     pub reg_alloc: RegisterAlloc,
     pub const_alloc: ConstAlloc,
     pub function_alloc: FunctionAlloc,
-    pub upvalue_alloc: UpValueAlloc,
     pub label_alloc: LabelAlloc,
-    pub parent: *mut ResourceAlloc,
-    loop_exit: Option<Label>,
+    /// exits of the enclosing loops, the innermost last
+    loop_exits: Vec<LoopExit>,
+    /// line where the function starts, None for the main chunk
+    pub function: Option<u32>,
+    /// the function takes `...`
+    pub is_vararg: bool,
+    /// first instruction of each statement, labels included, and where it starts
+    pub stat_starts: Vec<(usize, Location)>,
+    /// first free register when the current statement started,
+    /// the ones below hold locals and loop states
+    pub stat_top: Usize,
 }
 
 impl ResourceAlloc {
-    pub fn new() -> ResourceAlloc {
+    pub fn new(function: Option<u32>) -> ResourceAlloc {
         ResourceAlloc {
             reg_alloc: RegisterAlloc::new(),
             const_alloc: ConstAlloc::new(),
             function_alloc: FunctionAlloc::new(),
-            upvalue_alloc: UpValueAlloc::new(),
             label_alloc: LabelAlloc::new(),
-            parent: ptr::null_mut(),
-            loop_exit: None,
-        }
-    }
-
-    pub fn parent(mut self, p: *mut ResourceAlloc) -> ResourceAlloc {
-        self.parent = p;
-        self
-    }
-
-    pub unsafe fn propagate_upvalue(&mut self,
-                                    upvalue_name: &str,
-                                    final_pos: Usize,
-                                    depth: Usize)
-                                    -> Usize {
-        //  upvalue is already pushed into upvalue list
-        if let Some(pos) = self.upvalue_alloc.get(upvalue_name) {
-            return pos;
-        }
-
-        let mut current = self as *mut ResourceAlloc;
-        let mut parent = self.parent;
-        let mut upvalue_index = (*current).upvalue_alloc.push(upvalue_name, depth, final_pos);
-        // savefe the immidiate parent upvalue position, for returning
-        let immidiate_upvalue_index = upvalue_index;
-        // excute when depth >= 2
-        for i in 1..depth {
-            //  alloc upvalue in parent, and get where upvalue stored
-            upvalue_index = (*parent).upvalue_alloc.push(upvalue_name, depth - i, final_pos);
-            //  use this value to update current upvalue list
-            (*current).upvalue_alloc.set_upper_index(upvalue_name, upvalue_index);
-            // move up
-            current = parent;
-            parent = (*parent).parent;
+            loop_exits: vec![],
+            function,
+            is_vararg: false,
+            stat_starts: vec![],
+            stat_top: 0,
         }
-        immidiate_upvalue_index
     }
 
-    pub fn set_loop_exit(&mut self, label: Label) {
-        self.loop_exit = Some(label);
+    pub fn set_loop_exit(&mut self, label: Label, close: Option<Usize>) {
+        self.loop_exits.push(LoopExit { label, close });
     }
 
+    /// the exit of the enclosing loop is restored
     pub fn clear_loop_exit(&mut self) {
-        self.loop_exit = None;
+        self.loop_exits.pop();
     }
 
-    pub fn get_loop_exit(&self) -> Option<Label> {
-        self.loop_exit
+    pub fn get_loop_exit(&self) -> Option<LoopExit> {
+        self.loop_exits.last().cloned()
     }
 }
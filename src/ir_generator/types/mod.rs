@@ -4,11 +4,19 @@ use std::mem::transmute;
 use std::iter::Iterator;
 use super::opcodes::OpMode;
 use super::opcodes::mask_1;
+use parser::types::{ExprId, LimitError};
+use lexer::tokens::Location;
+use globals::Globals;
+use std::fmt;
 
 pub type Usize = u32;
 /// jump label
 pub type Label = i32;
 pub const LFIELDS_PER_FLUSH: u32 = 50;
+/// largest C of an instruction, a SETLIST batch beyond it follows as a raw word
+pub const MAXARG_C: u32 = 0x1FF;
+/// largest constant index an RK(_) operand can hold
+pub const MAXINDEXRK: u32 = 0xFF;
 pub const MIN_STACK_SIZE: Usize = 2;
 
 #[derive(Debug, PartialEq, Clone)]
@@ -40,10 +48,20 @@ pub fn extract_expect_num(ex: Option<Expect>) -> Result<usize, CompileError> {
     }
 }
 
+/// an expression in the ast, or a value already placed in a register
+/// produced when adjusting an exprlist to its varlist
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum ExprOrReg {
+    Expr(ExprId),
+    Reg(u32),
+}
+
 #[derive(Debug, PartialEq, Clone, Copy)]
 pub enum RetExpect {
     Num(u32),
     Indeterminate,
+    /// all results are returned by the callee in place of the caller
+    Tail,
 }
 
 #[derive(Debug, PartialEq)]
@@ -51,6 +69,49 @@ pub enum CompileError {
     SyntexError,
     InconsistentRet,
     UndefinedSymbol,
+    /// read of a global never assigned, in strict globals mode
+    UndefinedGlobal(String, Location),
+    /// read of a field missing from a known global table, in strict globals mode
+    UndefinedField(String, String, Location),
+    /// an implementation limit of Lua 5.1 is exceeded
+    Limit(LimitError),
+    /// `...` in a function that is not vararg
+    NotVararg(Location),
+    /// a rule the linter does not know, named in a `rua:ignore` comment
+    UnknownRule(String, Location),
+}
+
+impl fmt::Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            CompileError::UndefinedGlobal(ref name, location) => {
+                write!(f, "{}: undefined global '{}'", location, name)
+            }
+            CompileError::UndefinedField(ref name, ref field, location) => {
+                write!(f, "{}: undefined field '{}' of global '{}'", location, field, name)
+            }
+            CompileError::Limit(ref err) => write!(f, "{}", err),
+            CompileError::NotVararg(location) => {
+                write!(f, "{}: cannot use '...' outside a vararg function", location)
+            }
+            CompileError::UnknownRule(ref name, location) => {
+                write!(f, "{}: unknown rule '{}' in rua:ignore comment", location, name)
+            }
+            ref err => write!(f, "{:?}", err),
+        }
+    }
+}
+
+/// switches of the code generator
+#[derive(Debug, Default, PartialEq, Clone)]
+pub struct CompileOptions {
+    /// reading a global that is neither assigned in the source
+    /// nor one of `globals` is an error
+    pub strict_globals: bool,
+    /// globals provided by the host, the standard library by default
+    pub globals: Globals,
+    /// the source has type annotations, which are checked and then erased
+    pub typed: bool,
 }
 
 #[derive(Debug)]
@@ -88,21 +149,6 @@ impl FunctionChunk {
     }
 }
 
-#[derive(Debug)]
-pub struct FunctionPrototype {
-    pub prototype: FunctionChunk,
-    pub upvalue_list: Vec<(bool, u32, u32)>, /* (is_immidiate(depth == 1), pos in upvalue list, parent register number) */
-}
-
-impl FunctionPrototype {
-    pub fn new(chunk: FunctionChunk, uv_list: Vec<(bool, u32, u32)>) -> FunctionPrototype {
-        FunctionPrototype {
-            prototype: chunk,
-            upvalue_list: uv_list,
-        }
-    }
-}
-
 /// Convert const value to binary
 pub trait ToBytecode {
     fn to_bytecode(&self) -> Vec<u32>;
@@ -120,10 +166,7 @@ impl ToBytecode for f64 {
 
 impl ToBytecode for String {
     fn to_bytecode(&self) -> Vec<u32> {
-        if self.len() == 0 {
-            return vec![0];
-        }
-
+        // an empty string still has its zero, size 0 is a missing string
         let mut with_zero = self.clone().into_bytes();
         with_zero.push(0);
         let len = with_zero.len() as u32;
@@ -159,29 +202,23 @@ pub trait ToF8 {
 }
 
 impl ToF8 for usize {
+    /// the smallest "floating point byte" not less than self,
+    /// (eeeeexxx) is xxx when eeeee is 0, else (1xxx) * 2^(eeeee - 1)
     fn to_f8(self) -> u32 {
         assert!(self < u32::max_value() as usize,
                 "usize is too large, can not convert to byte float");
-        if self == 0 {
-            return self as u32;
+        let mut source = self as u32;
+        let mut exp = 0;
+        while source >= 16 {
+            // round up
+            source = (source + 1) >> 1;
+            exp += 1;
         }
-
-        let source = self as u32;
-        let power = 32 - source.leading_zeros() - 1;
-        let (mag, exp) = if power >= 4 {
-            let fract_4 = source & mask_1(4, power - 4);
-            let mag = if fract_4 & 1 == 1 {
-                (fract_4 >> 1) + 1
-            } else {
-                fract_4 >> 1
-            };
-            (mag, power - 2)
+        if source < 8 {
+            source
         } else {
-            let mag = source & mask_1(3, 0);
-            (mag, if power >= 2 { power - 2 } else { 0 })
-        };
-
-        (exp << 3) | mag
+            ((exp + 1) << 3) | (source - 8)
+        }
     }
 }
 
@@ -203,5 +240,15 @@ pub mod tests {
         assert_eq!(num_3.to_f8(), 8_u32);
         assert_eq!(num_4.to_f8(), 18_u32);
         assert_eq!(num_5.to_f8(), 19_u32);
+        assert_eq!(40_usize.to_f8(), 26_u32);
+        assert_eq!(31_usize.to_f8(), 24_u32);
+        assert_eq!(100_000_usize.to_f8(), 0x75);
+        // the size hint is never smaller than the size
+        let from_f8 = |f8: u32| if f8 < 8 { f8 } else { ((f8 & 7) + 8) << ((f8 >> 3) - 1) };
+        for size in 0..5000_usize {
+            let f8 = size.to_f8();
+            assert!(f8 <= 0xFF && from_f8(f8) as usize >= size);
+            assert!(f8 == 0 || from_f8(f8 - 1) < size as u32);
+        }
     }
 }
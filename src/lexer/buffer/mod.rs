@@ -1,5 +1,6 @@
 use std::iter;
 use std::default::Default;
+use super::tokens::Location;
 
 const BUFFER_SIZE: usize = 5;
 
@@ -14,6 +15,9 @@ pub struct Buffer<Tit>
 
     is_marked: bool,
     storage: [Option<char>; BUFFER_SIZE],
+    /// where the next char is
+    location: Location,
+    marked_location: Location,
 }
 
 impl<Tit> iter::Iterator for Buffer<Tit>
@@ -24,6 +28,14 @@ impl<Tit> iter::Iterator for Buffer<Tit>
     fn next(&mut self) -> Option<char> {
         self.try_load_one();
         self.current = Self::increment(self.current);
+        match self.storage[self.current] {
+            Some('\n') => {
+                self.location.line += 1;
+                self.location.column = 1;
+            }
+            Some(_) => self.location.column += 1,
+            None => (),
+        }
         self.storage[self.current]
     }
 }
@@ -39,9 +51,15 @@ impl<Tit> Buffer<Tit>
             wrapped_iter: it,
             is_marked: false,
             storage: [Default::default(); BUFFER_SIZE],
+            location: Location::default(),
+            marked_location: Location::default(),
         }
     }
 
+    pub fn location(&self) -> Location {
+        self.location
+    }
+
     pub fn peek(&mut self) -> Option<char> {
         self.try_load_one();
         self.storage[Self::increment(self.current)]
@@ -50,6 +68,7 @@ impl<Tit> Buffer<Tit>
     pub fn mark(&mut self) {
         self.is_marked = true;
         self.marked = self.current;
+        self.marked_location = self.location;
     }
 
     pub fn rewind(&mut self) {
@@ -57,6 +76,7 @@ impl<Tit> Buffer<Tit>
             panic!("Must mark before rewinding");
         }
         self.current = self.marked;
+        self.location = self.marked_location;
         self.is_marked = false;
     }
 
@@ -98,6 +118,21 @@ mod tests {
         assert_eq!(buffer.next(), None);
     }
 
+    #[test]
+    fn location() {
+        let text = "a\nbc".to_string();
+        let mut buffer = Buffer::new(text.chars());
+        assert_eq!(buffer.location(), Location::new(1, 1));
+        buffer.next();
+        buffer.next();
+        buffer.mark();
+        assert_eq!(buffer.location(), Location::new(2, 1));
+        buffer.next();
+        assert_eq!(buffer.location(), Location::new(2, 2));
+        buffer.rewind();
+        assert_eq!(buffer.location(), Location::new(2, 1));
+    }
+
     #[test]
     #[should_panic]
     fn unpaired_rewind() {
@@ -0,0 +1,59 @@
+use std::collections::HashMap;
+
+/// handle of an interned name or string literal
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Symbol(u32);
+
+/// stores each distinct string once,
+/// so that names can be copied, compared and hashed as integers
+#[derive(Debug, Clone)]
+pub struct Interner {
+    index: HashMap<String, Symbol>,
+    strings: Vec<String>,
+}
+
+impl Interner {
+    pub fn new() -> Interner {
+        Interner {
+            index: HashMap::new(),
+            strings: vec![],
+        }
+    }
+
+    /// return the symbol of string, allocate a new one if not seen before
+    pub fn intern(&mut self, string: &str) -> Symbol {
+        if let Some(&symbol) = self.index.get(string) {
+            return symbol;
+        }
+        let symbol = Symbol(self.strings.len() as u32);
+        self.strings.push(string.to_string());
+        self.index.insert(string.to_string(), symbol);
+        symbol
+    }
+
+    /// look up without interning
+    pub fn get(&self, string: &str) -> Option<Symbol> {
+        self.index.get(string).cloned()
+    }
+
+    pub fn resolve(&self, symbol: Symbol) -> &str {
+        &self.strings[symbol.0 as usize]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn intern_and_resolve() {
+        let mut interner = Interner::new();
+        let a = interner.intern("a");
+        let b = interner.intern("b");
+        assert_ne!(a, b);
+        assert_eq!(interner.intern("a"), a);
+        assert_eq!(interner.get("b"), Some(b));
+        assert_eq!(interner.get("c"), None);
+        assert_eq!(interner.resolve(a), "a");
+    }
+}
@@ -1,16 +1,22 @@
 pub mod tokens;
 pub mod buffer;
+pub mod interner;
 
 use std::iter;
 use std::collections::HashMap;
 use std::clone::Clone;
 use self::tokens::*;
 use self::buffer::Buffer;
+use self::interner::{Interner, Symbol};
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum TokenizeError {
     Invalidoperator,
     InvalidNumber,
+    /// string literal is not closed before a newline
+    UnfinishedString,
+    /// input ends in the middle of a token
+    UnexpectedEOF,
     Error,
 }
 
@@ -44,8 +50,15 @@ where
     /// used as an iterator
     buffer: Buffer<Tit>,
     is_ended: bool,
-    keywords: &'a HashMap<String, FlagType>,
+    keywords: HashMap<Symbol, FlagType>,
     operators: &'a HashMap<String, FlagType>,
+    /// names and string literals seen so far
+    interner: Interner,
+    /// where the last token starts
+    token_start: Location,
+    /// line of the last returned token, 0 before the first one
+    last_token_line: u32,
+    comments: Vec<Comment>,
 }
 
 impl<'a, Tit> TokenIterator<'a, Tit>
@@ -57,14 +70,41 @@ where
         kt: &'a HashMap<String, FlagType>,
         st: &'a HashMap<String, FlagType>,
     ) -> TokenIterator<'a, Tit> {
+        let mut interner = Interner::new();
+        let keywords = kt.iter()
+            .map(|(keyword, &flag)| (interner.intern(keyword), flag))
+            .collect();
         TokenIterator {
             buffer: Buffer::<Tit>::new(it),
             is_ended: false,
-            keywords: kt,
+            keywords: keywords,
             operators: st,
+            interner: interner,
+            token_start: Location::default(),
+            last_token_line: 0,
+            comments: vec![],
         }
     }
 
+    /// where the last returned token starts
+    pub fn location(&self) -> Location {
+        self.token_start
+    }
+
+    /// comments skipped so far
+    pub fn comments(&self) -> &[Comment] {
+        &self.comments
+    }
+
+    pub fn interner(&self) -> &Interner {
+        &self.interner
+    }
+
+    /// give up the tokenizer and keep the names it found
+    pub fn into_interner(self) -> Interner {
+        self.interner
+    }
+
     fn consume_while<F>(&mut self, predicate: F) -> String
     where
         F: Fn(char) -> bool,
@@ -80,9 +120,10 @@ where
     /// find id in text, return Name or reserved keywords
     fn handle_identifier(&mut self) -> Token {
         let id: String = self.consume_while(|c| c.is_alphabetic() || c == '_' || c.is_numeric());
-        match self.keywords.get(&id) {
-            Some(keyword) => Token::Flag(keyword.clone()),
-            _ => Token::Name(id),
+        let symbol = self.interner.intern(&id);
+        match self.keywords.get(&symbol) {
+            Some(keyword) => Token::Flag(*keyword),
+            _ => Token::Name(symbol),
         }
     }
 
@@ -90,27 +131,30 @@ where
         // Look for longer operator first
         let max_operator_length = 3;
         self.buffer.mark();
-        let n_char_sym: String = self.buffer.by_ref().take(max_operator_length).collect();
+        // operators are matched char by char,
+        // slicing the string by bytes breaks on multi-byte chars
+        let n_char_sym: Vec<char> = self.buffer.by_ref().take(max_operator_length).collect();
+        self.buffer.rewind();
         for len in (1..(n_char_sym.len() + 1)).rev() {
-            if let Some(&sym) = self.operators.get(&n_char_sym[0..len]) {
+            let sym: String = n_char_sym[0..len].iter().collect();
+            if let Some(&flag) = self.operators.get(&sym) {
                 // advance original iterator
-                self.buffer.rewind();
                 let _ = self.buffer.by_ref().take(len).count();
-                return Ok(Token::Flag(sym));
+                return Ok(Token::Flag(flag));
             }
         }
-        return Err(TokenizeError::Invalidoperator);
+        Err(TokenizeError::Invalidoperator)
     }
 
     fn handle_string(&mut self) -> Result<Token, TokenizeError> {
         // skip starting quote and save for match
-        let start = self.buffer.next().expect("This should never failed");
+        let start = self.buffer.next().ok_or(TokenizeError::Error)?;
         let string: String = self.consume_while(|c| c != start && c != '\n');
         //  skip ending
-        if let Some(_) = self.buffer.next() {
-            Ok(Token::Str(string))
-        } else {
-            Err(TokenizeError::Error)
+        match self.buffer.next() {
+            Some(end) if end == start => Ok(Token::Str(self.interner.intern(&string))),
+            Some(_) => Err(TokenizeError::UnfinishedString),
+            None => Err(TokenizeError::UnexpectedEOF),
         }
         // TODO multiline string
     }
@@ -134,116 +178,152 @@ where
     }
 
     fn skip_comment(&mut self) {
-        while let Some(true) = self.buffer.peek().map(|c| c != '\n') {
-            self.buffer.next();
-        }
-        self.eat('\n').expect("Tokenizing failed");
+        let location = self.buffer.location();
+        self.buffer.nth(1);
+        let text = self.consume_while(|c| c != '\n');
+        // a comment may be ended by EOF
+        let _ = self.eat('\n');
+        self.comments.push(Comment {
+            location,
+            text,
+            trailing: self.last_token_line == location.line,
+        });
     }
-}
 
-impl<'a, Tit> iter::Iterator for TokenIterator<'a, Tit>
-where
-    Tit: iter::Iterator<Item = char> + Clone,
-{
-    type Item = Token;
-
-    /// Main logic for lexical analysis
-    fn next(&mut self) -> Option<Token> {
-        if self.is_ended {
-            return None;
-        }
+    /// look at the char after the peeked one
+    fn peek_second(&mut self) -> Option<char> {
+        self.buffer.mark();
+        let second = self.buffer.by_ref().nth(1);
+        self.buffer.rewind();
+        second
+    }
 
+    fn next_token(&mut self) -> Result<Token, TokenizeError> {
         loop {
+            self.token_start = self.buffer.location();
             match self.buffer.peek() {
-                Some(ch) if ch == ' ' || ch == '\n' => {
+                Some(ch) if ch.is_whitespace() => {
                     self.buffer.next();
                     continue;
                 }
                 Some(ch) if ch.is_alphabetic() || ch == '_' => {
-                    return Some(self.handle_identifier())
+                    return Ok(self.handle_identifier())
                 }
-                Some(ch) if ch.is_numeric() => {
-                    return if let Ok(token) = self.handle_number() {
-                        Some(token)
-                    } else {
-                        None
+                Some(ch) if ch.is_numeric() => return self.handle_number(),
+                Some('.') => {
+                    return match self.peek_second() {
+                        Some(next_ch) if next_ch.is_numeric() => self.handle_number(),
+                        _ => self.handle_operator(),
                     }
                 }
-                Some(ch) if ch == '.' => {
-                    self.buffer.mark();
-                    if let Some(next_ch) = self.buffer.by_ref().skip(1).next() {
-                        if !next_ch.is_numeric() {
-                            self.buffer.rewind();
-                            return self.handle_operator().ok();
-                        } else {
-                            self.buffer.rewind();
-                            return self.handle_number().ok();
-                        }
-                    }
-                }
-                Some(ch) if ch == '-' => {
-                    self.buffer.mark();
-                    if let Some('-') = self.buffer.by_ref().skip(1).next() {
-                        self.buffer.rewind();
+                Some('-') => {
+                    if let Some('-') = self.peek_second() {
                         self.skip_comment();
                     } else {
-                        self.buffer.rewind();
                         self.buffer.next();
-                        return Some(Token::Flag(FlagType::Minus));
-                    }
-                }
-                Some(ch) if ch == '\'' || ch == '\"' => {
-                    return if let Ok(string) = self.handle_string() {
-                        Some(string)
-                    } else {
-                        None
-                    }
-                }
-                Some(_) => {
-                    return if let Ok(token) = self.handle_operator() {
-                        Some(token)
-                    } else {
-                        None
+                        return Ok(Token::Flag(FlagType::Minus));
                     }
                 }
+                Some(ch) if ch == '\'' || ch == '\"' => return self.handle_string(),
+                Some(_) => return self.handle_operator(),
                 None => {
                     self.is_ended = true; // EOF should only return for once
-                    return Some(Token::Flag(FlagType::EOF));
+                    return Ok(Token::Flag(FlagType::EOF));
                 }
             }
         }
     }
 }
 
+impl<'a, Tit> iter::Iterator for TokenIterator<'a, Tit>
+where
+    Tit: iter::Iterator<Item = char> + Clone,
+{
+    type Item = Result<Token, TokenizeError>;
+
+    /// Main logic for lexical analysis
+    /// iteration stops after the first error
+    fn next(&mut self) -> Option<Result<Token, TokenizeError>> {
+        if self.is_ended {
+            return None;
+        }
+        let token = self.next_token();
+        if token.is_err() {
+            self.is_ended = true;
+        }
+        self.last_token_line = self.token_start.line;
+        Some(token)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use lexer::*;
+    use std::str::Chars;
+
+    /// token of an interned name
+    fn name(it: &TokenIterator<Chars>, name: &str) -> Token {
+        Token::Name(it.interner().get(name).expect("Name not interned"))
+    }
+
+    #[test]
+    fn token_location() {
+        let text = "a = 'x' -- note\n  b.c".to_string();
+        let lexer = Lexer::new();
+        let mut token_it = lexer.tokenize(text.chars());
+        let mut locations = vec![];
+        while let Some(Ok(_)) = token_it.next() {
+            locations.push((token_it.location().line, token_it.location().column));
+        }
+        assert_eq!(locations, vec![(1, 1), (1, 3), (1, 5), (2, 3), (2, 4), (2, 5), (2, 6)]);
+    }
+
+    #[test]
+    fn comments() {
+        let text = "-- head\na = 1 -- note\n  --tail".to_string();
+        let lexer = Lexer::new();
+        let mut token_it = lexer.tokenize(text.chars());
+        while let Some(Ok(_)) = token_it.next() {}
+        let comment = |line, column, text: &str, trailing| Comment {
+            location: Location::new(line, column),
+            text: text.to_string(),
+            trailing,
+        };
+        assert_eq!(
+            token_it.comments(),
+            &[
+                comment(1, 1, " head", false),
+                comment(2, 7, " note", true),
+                comment(3, 3, "tail", false),
+            ]
+        );
+    }
 
     #[test]
     fn expression_tokenize() {
         let text_1 = "2 * 3 - 2 * ( 3 / 22 + 1 - - 2.22)".to_string();
         let lexer = Lexer::new();
         let mut token_it_1 = lexer.tokenize(text_1.chars());
-        assert_eq!(token_it_1.next(), Some(Token::Num(2f64)));
-        assert_eq!(token_it_1.next(), Some(Token::Flag(FlagType::Mul)));
-        assert_eq!(token_it_1.next(), Some(Token::Num(3f64)));
-        assert_eq!(token_it_1.next(), Some(Token::Flag(FlagType::Minus)));
-        assert_eq!(token_it_1.next(), Some(Token::Num(2f64)));
-
-        assert_eq!(token_it_1.next(), Some(Token::Flag(FlagType::Mul)));
-        assert_eq!(token_it_1.next(), Some(Token::Flag(FlagType::LParen)));
-        assert_eq!(token_it_1.next(), Some(Token::Num(3f64)));
-        assert_eq!(token_it_1.next(), Some(Token::Flag(FlagType::Div)));
-        assert_eq!(token_it_1.next(), Some(Token::Num(22f64)));
-
-        assert_eq!(token_it_1.next(), Some(Token::Flag(FlagType::Plus)));
-        assert_eq!(token_it_1.next(), Some(Token::Num(1f64)));
-        assert_eq!(token_it_1.next(), Some(Token::Flag(FlagType::Minus)));
-        assert_eq!(token_it_1.next(), Some(Token::Flag(FlagType::Minus)));
-        assert_eq!(token_it_1.next(), Some(Token::Num(2.22f64)));
-
-        assert_eq!(token_it_1.next(), Some(Token::Flag(FlagType::RParen)));
-        assert_eq!(token_it_1.next(), Some(Token::Flag(FlagType::EOF)));
+        assert_eq!(token_it_1.next(), Some(Ok(Token::Num(2f64))));
+        assert_eq!(token_it_1.next(), Some(Ok(Token::Flag(FlagType::Mul))));
+        assert_eq!(token_it_1.next(), Some(Ok(Token::Num(3f64))));
+        assert_eq!(token_it_1.next(), Some(Ok(Token::Flag(FlagType::Minus))));
+        assert_eq!(token_it_1.next(), Some(Ok(Token::Num(2f64))));
+
+        assert_eq!(token_it_1.next(), Some(Ok(Token::Flag(FlagType::Mul))));
+        assert_eq!(token_it_1.next(), Some(Ok(Token::Flag(FlagType::LParen))));
+        assert_eq!(token_it_1.next(), Some(Ok(Token::Num(3f64))));
+        assert_eq!(token_it_1.next(), Some(Ok(Token::Flag(FlagType::Div))));
+        assert_eq!(token_it_1.next(), Some(Ok(Token::Num(22f64))));
+
+        assert_eq!(token_it_1.next(), Some(Ok(Token::Flag(FlagType::Plus))));
+        assert_eq!(token_it_1.next(), Some(Ok(Token::Num(1f64))));
+        assert_eq!(token_it_1.next(), Some(Ok(Token::Flag(FlagType::Minus))));
+        assert_eq!(token_it_1.next(), Some(Ok(Token::Flag(FlagType::Minus))));
+        assert_eq!(token_it_1.next(), Some(Ok(Token::Num(2.22f64))));
+
+        assert_eq!(token_it_1.next(), Some(Ok(Token::Flag(FlagType::RParen))));
+        assert_eq!(token_it_1.next(), Some(Ok(Token::Flag(FlagType::EOF))));
         assert_eq!(token_it_1.next(), None);
 
         let text_2 = "a, number = 0, 1
@@ -253,30 +333,30 @@ mod tests {
             .to_string();
         let mut token_it_2 = lexer.tokenize(text_2.chars());
 
-        assert_eq!(token_it_2.next(), Some(Token::Name("a".to_string())));
-        assert_eq!(token_it_2.next(), Some(Token::Flag(FlagType::Comma)));
-        assert_eq!(token_it_2.next(), Some(Token::Name("number".to_string())));
-        assert_eq!(token_it_2.next(), Some(Token::Flag(FlagType::Assign)));
-        assert_eq!(token_it_2.next(), Some(Token::Num(0f64)));
+        assert_eq!(token_it_2.next(), Some(Ok(name(&token_it_2, "a"))));
+        assert_eq!(token_it_2.next(), Some(Ok(Token::Flag(FlagType::Comma))));
+        assert_eq!(token_it_2.next(), Some(Ok(name(&token_it_2, "number"))));
+        assert_eq!(token_it_2.next(), Some(Ok(Token::Flag(FlagType::Assign))));
+        assert_eq!(token_it_2.next(), Some(Ok(Token::Num(0f64))));
 
-        assert_eq!(token_it_2.next(), Some(Token::Flag(FlagType::Comma)));
-        assert_eq!(token_it_2.next(), Some(Token::Num(1f64)));
-        assert_eq!(token_it_2.next(), Some(Token::Name("str".to_string())));
-        assert_eq!(token_it_2.next(), Some(Token::Flag(FlagType::Assign)));
+        assert_eq!(token_it_2.next(), Some(Ok(Token::Flag(FlagType::Comma))));
+        assert_eq!(token_it_2.next(), Some(Ok(Token::Num(1f64))));
+        assert_eq!(token_it_2.next(), Some(Ok(name(&token_it_2, "str"))));
+        assert_eq!(token_it_2.next(), Some(Ok(Token::Flag(FlagType::Assign))));
         assert_eq!(
             token_it_2.next(),
-            Some(Token::Str("this is a string".to_string()))
+            Some(Ok(Token::Str(token_it_2.interner().get("this is a string").unwrap())))
         );
 
-        assert_eq!(token_it_2.next(), Some(Token::Name("b".to_string())));
-        assert_eq!(token_it_2.next(), Some(Token::Flag(FlagType::Comma)));
-        assert_eq!(token_it_2.next(), Some(Token::Name("c".to_string())));
-        assert_eq!(token_it_2.next(), Some(Token::Flag(FlagType::Assign)));
-        assert_eq!(token_it_2.next(), Some(Token::Flag(FlagType::True)));
+        assert_eq!(token_it_2.next(), Some(Ok(name(&token_it_2, "b"))));
+        assert_eq!(token_it_2.next(), Some(Ok(Token::Flag(FlagType::Comma))));
+        assert_eq!(token_it_2.next(), Some(Ok(name(&token_it_2, "c"))));
+        assert_eq!(token_it_2.next(), Some(Ok(Token::Flag(FlagType::Assign))));
+        assert_eq!(token_it_2.next(), Some(Ok(Token::Flag(FlagType::True))));
 
-        assert_eq!(token_it_2.next(), Some(Token::Flag(FlagType::Comma)));
-        assert_eq!(token_it_2.next(), Some(Token::Flag(FlagType::False)));
-        assert_eq!(token_it_2.next(), Some(Token::Flag(FlagType::EOF)));
+        assert_eq!(token_it_2.next(), Some(Ok(Token::Flag(FlagType::Comma))));
+        assert_eq!(token_it_2.next(), Some(Ok(Token::Flag(FlagType::False))));
+        assert_eq!(token_it_2.next(), Some(Ok(Token::Flag(FlagType::EOF))));
         assert_eq!(token_it_2.next(), None);
     }
 
@@ -292,13 +372,13 @@ mod tests {
         let lexer = Lexer::new();
         let mut it = lexer.tokenize(text.chars());
 
-        assert_eq!(it.next(), Some(Token::Name("a".to_string())));
-        assert_eq!(it.next(), Some(Token::Flag(FlagType::Assign)));
-        assert_eq!(it.next(), Some(Token::Num(1f64)));
+        assert_eq!(it.next(), Some(Ok(name(&it, "a"))));
+        assert_eq!(it.next(), Some(Ok(Token::Flag(FlagType::Assign))));
+        assert_eq!(it.next(), Some(Ok(Token::Num(1f64))));
 
-        assert_eq!(it.next(), Some(Token::Name("b".to_string())));
-        assert_eq!(it.next(), Some(Token::Flag(FlagType::Assign)));
-        assert_eq!(it.next(), Some(Token::Num(2f64)));
+        assert_eq!(it.next(), Some(Ok(name(&it, "b"))));
+        assert_eq!(it.next(), Some(Ok(Token::Flag(FlagType::Assign))));
+        assert_eq!(it.next(), Some(Ok(Token::Num(2f64))));
     }
 
     #[test]
@@ -309,21 +389,56 @@ print(a)
 "
             .to_string();
 
-        let tokens = Lexer::new().tokenize(text.chars()).collect::<Vec<_>>();
+        let lexer = Lexer::new();
+        let mut it = lexer.tokenize(text.chars());
+        let tokens = it.by_ref()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
         assert_eq!(
             tokens,
             vec![
-                Token::Name("a".to_string()),
+                name(&it, "a"),
                 Token::Flag(FlagType::Assign),
                 Token::Num(1f64),
                 Token::Flag(FlagType::Plus),
                 Token::Num(2f64),
-                Token::Name("print".to_string()),
+                name(&it, "print"),
                 Token::Flag(FlagType::LParen),
-                Token::Name("a".to_string()),
+                name(&it, "a"),
                 Token::Flag(FlagType::RParen),
                 Token::Flag(FlagType::EOF),
             ]
         );
     }
+
+    #[test]
+    fn malformed_input() {
+        let lexer = Lexer::new();
+        let tokenize = |text: &str| -> Vec<Result<Token, TokenizeError>> {
+            lexer.tokenize(text.chars()).collect()
+        };
+        // the first name gets the same symbol in every tokenizer
+        let mut it = lexer.tokenize("a".chars());
+        it.next();
+        let a = name(&it, "a");
+
+        assert_eq!(
+            tokenize("a = 'unclosed\n"),
+            vec![
+                Ok(a.clone()),
+                Ok(Token::Flag(FlagType::Assign)),
+                Err(TokenizeError::UnfinishedString),
+            ]
+        );
+        assert_eq!(tokenize("\"abc"), vec![Err(TokenizeError::UnexpectedEOF)]);
+        assert_eq!(tokenize("1 $ 2"), vec![Ok(Token::Num(1f64)), Err(TokenizeError::Invalidoperator)]);
+        assert_eq!(tokenize("\u{20ac}"), vec![Err(TokenizeError::Invalidoperator)]);
+        assert_eq!(tokenize("1.2.3"), vec![Err(TokenizeError::InvalidNumber)]);
+        // comment without trailing newline, tab as blank
+        assert_eq!(
+            tokenize("\ta -- comment"),
+            vec![Ok(a), Ok(Token::Flag(FlagType::EOF))]
+        );
+        assert_eq!(tokenize("."), vec![Ok(Token::Flag(FlagType::Dot)), Ok(Token::Flag(FlagType::EOF))]);
+    }
 }
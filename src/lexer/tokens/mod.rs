@@ -1,4 +1,6 @@
 use std::collections::HashMap;
+use std::fmt;
+use super::interner::Symbol;
 
 macro_rules! map(
     { $($key:expr => $value:expr),+ } => {
@@ -56,6 +58,8 @@ pub enum FlagType {
     Minus,
     Mul,
     Div,
+    Pow,
+    Len,
     EQ,
     NEQ,
     LEQ,
@@ -77,8 +81,42 @@ pub enum FlagType {
 pub enum Token {
     Flag(FlagType),
     Num(f64),
-    Name(String),
-    Str(String),
+    Name(Symbol),
+    Str(Symbol),
+}
+
+/// position of a char in the source, both start from 1
+#[derive(Debug, PartialEq, Copy, Clone, Eq, Hash, PartialOrd, Ord)]
+pub struct Location {
+    pub line: u32,
+    pub column: u32,
+}
+
+impl Location {
+    pub fn new(line: u32, column: u32) -> Location {
+        Location { line, column }
+    }
+}
+
+impl Default for Location {
+    fn default() -> Location {
+        Location::new(1, 1)
+    }
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.line, self.column)
+    }
+}
+
+/// a comment, its text excludes the leading `--`
+#[derive(Debug, PartialEq, Clone)]
+pub struct Comment {
+    pub location: Location,
+    pub text: String,
+    /// a token precedes the comment on its line
+    pub trailing: bool,
 }
 
 pub fn get_keyword_table() -> HashMap<String, FlagType> {
@@ -123,6 +161,8 @@ pub fn get_operator_table() -> HashMap<String, FlagType> {
         "-"   =>  FlagType::Minus,
         "*"   =>  FlagType::Mul,
         "/"   =>  FlagType::Div,
+        "^"   =>  FlagType::Pow,
+        "#"   =>  FlagType::Len,
         "=="  =>  FlagType::EQ,
         "~="  =>  FlagType::NEQ,
         "<="  =>  FlagType::LEQ,
@@ -130,6 +170,7 @@ pub fn get_operator_table() -> HashMap<String, FlagType> {
         "<"   =>  FlagType::LESS,
         ">"   =>  FlagType::GREATER,
         "."   =>  FlagType::Dot,
+        ".."  =>  FlagType::DoubleDot,
         "..." =>  FlagType::TripleDot
         
     }
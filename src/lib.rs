@@ -0,0 +1,13 @@
+#[cfg(test)]
+extern crate tempdir;
+
+pub mod lexer;
+pub mod parser;
+pub mod ir_generator;
+pub mod bytecode_generator;
+pub mod assembler;
+pub mod compiler;
+pub mod checker;
+pub mod globals;
+pub mod linter;
+pub mod optimizer;
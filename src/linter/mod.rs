@@ -0,0 +1,668 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::path::Path;
+use std::str::Chars;
+use compiler::CompilerError;
+use globals::Globals;
+use ir_generator::resolver::{DeclSite, LocalId, Resolution, Resolved, Resolver};
+use ir_generator::types::CompileError;
+use lexer::interner::Symbol;
+use lexer::tokens::{Comment, FlagType, Location};
+use parser::Parser;
+use parser::types::*;
+
+/// mistakes reported by the linter
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Rule {
+    UnusedLocal,
+    UnusedParam,
+    Shadowing,
+    GlobalWrite,
+    Unreachable,
+    DuplicateKey,
+    SelfOutsideMethod,
+    SelfComparison,
+}
+
+const RULES: [Rule; 8] = [
+    Rule::UnusedLocal,
+    Rule::UnusedParam,
+    Rule::Shadowing,
+    Rule::GlobalWrite,
+    Rule::Unreachable,
+    Rule::DuplicateKey,
+    Rule::SelfOutsideMethod,
+    Rule::SelfComparison,
+];
+
+impl Rule {
+    pub fn all() -> &'static [Rule] {
+        &RULES
+    }
+
+    /// name used in config files and ignore comments
+    pub fn name(self) -> &'static str {
+        match self {
+            Rule::UnusedLocal => "unused-local",
+            Rule::UnusedParam => "unused-param",
+            Rule::Shadowing => "shadowing",
+            Rule::GlobalWrite => "global-write",
+            Rule::Unreachable => "unreachable",
+            Rule::DuplicateKey => "duplicate-key",
+            Rule::SelfOutsideMethod => "self-outside-method",
+            Rule::SelfComparison => "self-comparison",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Rule> {
+        RULES.iter().cloned().find(|rule| rule.name() == name)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Finding {
+    pub rule: Rule,
+    pub location: Location,
+    pub message: String,
+}
+
+impl fmt::Display for Finding {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {} ({})", self.location, self.message, self.rule.name())
+    }
+}
+
+/// rules to check and globals known to the program
+///
+/// a config file sets rules with lines of `rule = true` or `rule = false`,
+/// `--` starts a comment to the end of the line
+#[derive(Clone, Debug, Default)]
+pub struct LintConfig {
+    disabled: HashSet<Rule>,
+    /// writing other globals is reported
+    pub globals: Globals,
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(io::Error),
+    /// line and text of a setting that is not `rule = true|false`
+    BadSetting(usize, String),
+    UnknownRule(usize, String),
+}
+
+impl LintConfig {
+    /// every rule enabled, std globals
+    pub fn new() -> LintConfig {
+        LintConfig::default()
+    }
+
+    pub fn set(&mut self, rule: Rule, enabled: bool) {
+        if enabled {
+            self.disabled.remove(&rule);
+        } else {
+            self.disabled.insert(rule);
+        }
+    }
+
+    pub fn is_enabled(&self, rule: Rule) -> bool {
+        !self.disabled.contains(&rule)
+    }
+
+    pub fn load_file<P: AsRef<Path>>(&mut self, path: P) -> Result<(), ConfigError> {
+        let mut text = String::new();
+        File::open(path)
+            .and_then(|mut file| file.read_to_string(&mut text))
+            .map_err(ConfigError::Io)?;
+        self.add_settings(&text)
+    }
+
+    pub fn add_settings(&mut self, text: &str) -> Result<(), ConfigError> {
+        for (i, line) in text.lines().enumerate() {
+            let setting = line.split("--").next().unwrap_or("").trim();
+            if setting.is_empty() {
+                continue;
+            }
+            let mut parts = setting.splitn(2, '=').map(|part| part.trim());
+            let (name, value) = match (parts.next(), parts.next()) {
+                (Some(name), Some(value)) => (name, value),
+                _ => return Err(ConfigError::BadSetting(i + 1, setting.to_string())),
+            };
+            let rule = Rule::from_name(name)
+                .ok_or_else(|| ConfigError::UnknownRule(i + 1, name.to_string()))?;
+            match value {
+                "true" => self.set(rule, true),
+                "false" => self.set(rule, false),
+                _ => return Err(ConfigError::BadSetting(i + 1, setting.to_string())),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// a `rua:ignore` comment, which silences the listed rules or all rules
+/// on its line, or to the end of file if it is on a line of its own
+#[derive(Debug)]
+struct Ignore {
+    line: u32,
+    to_end: bool,
+    rules: Option<HashSet<Rule>>,
+}
+
+impl Ignore {
+    /// ret: None if the comment is not an ignore comment
+    fn from_comment(comment: &Comment) -> Option<Result<Ignore, CompileError>> {
+        let text = comment.text.trim();
+        if !text.starts_with("rua:ignore") {
+            return None;
+        }
+        let rest = &text["rua:ignore".len()..];
+        if !rest.is_empty() && !rest.starts_with(char::is_whitespace) {
+            return None;
+        }
+        Some(Ignore::with_names(rest, comment))
+    }
+
+    fn with_names(rest: &str, comment: &Comment) -> Result<Ignore, CompileError> {
+        let names = rest
+            .split(|c: char| c == ',' || c.is_whitespace())
+            .filter(|name| !name.is_empty())
+            .collect::<Vec<_>>();
+        let rules = if names.is_empty() {
+            None
+        } else {
+            let location = comment.location;
+            let rules = names
+                .into_iter()
+                .map(|name| {
+                    Rule::from_name(name)
+                        .ok_or_else(|| CompileError::UnknownRule(name.to_string(), location))
+                })
+                .collect::<Result<_, _>>()?;
+            Some(rules)
+        };
+        Ok(Ignore {
+            line: comment.location.line,
+            to_end: !comment.trailing,
+            rules,
+        })
+    }
+
+    fn covers(&self, finding: &Finding) -> bool {
+        let line = finding.location.line;
+        let in_range = if self.to_end { line >= self.line } else { line == self.line };
+        in_range && match self.rules {
+            Some(ref rules) => rules.contains(&finding.rule),
+            None => true,
+        }
+    }
+}
+
+/// constant key of a table constructor entry
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+enum ConstKey {
+    /// bits of the number
+    Num(u64),
+    Str(Symbol),
+    Boole(bool),
+}
+
+/// declared local and whether it is a parameter
+#[derive(Debug)]
+struct Decl {
+    id: LocalId,
+    location: Location,
+    is_param: bool,
+}
+
+/// checks a syntax tree, using the names bound by the resolver
+#[derive(Debug)]
+pub struct Linter<'a> {
+    ast: &'a Ast,
+    config: &'a LintConfig,
+    resolution: Resolution,
+    /// locals and parameters checked for use, loop variables are not
+    decls: Vec<Decl>,
+    locations: HashMap<LocalId, Location>,
+    findings: Vec<Finding>,
+}
+
+impl<'a> Linter<'a> {
+    /// findings in source order
+    pub fn lint(ast: &'a Ast, config: &'a LintConfig) -> Result<Vec<Finding>, CompileError> {
+        let mut linter = Linter {
+            ast,
+            config,
+            resolution: Resolver::resolve(ast)?,
+            decls: vec![],
+            locations: HashMap::new(),
+            findings: vec![],
+        };
+        match ast.root {
+            Node::Block(block) => linter.function(block, Location::default()),
+            // can not find entry block
+            Node::Expr(_) => return Err(CompileError::SyntexError),
+        }
+        linter.unused();
+        let ignores = ast.comments
+            .iter()
+            .filter_map(Ignore::from_comment)
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut findings = linter
+            .findings
+            .into_iter()
+            .filter(|finding| config.is_enabled(finding.rule))
+            .filter(|finding| !ignores.iter().any(|ignore| ignore.covers(finding)))
+            .collect::<Vec<_>>();
+        findings.sort_by_key(|finding| finding.location);
+        Ok(findings)
+    }
+
+    pub fn lint_text(text: &String, config: &LintConfig) -> Result<Vec<Finding>, CompilerError> {
+        let ast = Parser::<Chars>::ast_from_text(text).map_err(CompilerError::Syntax)?;
+        Linter::lint(&ast, config).map_err(CompilerError::Compile)
+    }
+
+    pub fn lint_file(path: &str, config: &LintConfig) -> Result<Vec<Finding>, CompilerError> {
+        let mut source = String::new();
+        File::open(path)
+            .and_then(|mut file| file.read_to_string(&mut source))
+            .map_err(CompilerError::Io)?;
+        Linter::lint_text(&source, config)
+    }
+}
+
+// walk the tree
+impl<'a> Linter<'a> {
+    fn report(&mut self, rule: Rule, location: Location, message: String) {
+        self.findings.push(Finding {
+            rule,
+            location,
+            message,
+        });
+    }
+
+    fn function(&mut self, body: BlockId, location: Location) {
+        self.declare(DeclSite::Params(body), location, Some(true));
+        self.block(body);
+    }
+
+    /// ret: if control never leaves the end of the block
+    fn block(&mut self, block: BlockId) -> bool {
+        let ast = self.ast;
+        let mut ends = false;
+        let mut reported = false;
+        for (i, stat) in ast[block].stats.iter().enumerate() {
+            let location = ast[block].locations[i];
+            // only the first unreachable statement is reported
+            if ends && !reported {
+                self.report(Rule::Unreachable, location, "unreachable code".to_string());
+                reported = true;
+            }
+            if self.stat(stat, DeclSite::Stat(block, i), location) {
+                ends = true;
+            }
+        }
+        if let Some(ref ret) = ast[block].ret {
+            self.exprs(ret);
+            return true;
+        }
+        ends
+    }
+
+    /// ret: if control never reaches the next statement
+    fn stat(&mut self, stat: &Stat, site: DeclSite, location: Location) -> bool {
+        let ast = self.ast;
+        match *stat {
+            Stat::Empty => false,
+            Stat::Break => true,
+            Stat::Ret(ref exprs) => {
+                self.exprs(exprs);
+                true
+            }
+            Stat::Assign(ref targets, ref exprs) => {
+                self.exprs(exprs);
+                for &target in targets {
+                    match ast[target] {
+                        Expr::Var(Var::Name(name)) => self.global_write(target, name),
+                        _ => self.expr(target),
+                    }
+                }
+                false
+            }
+            Stat::AssignLocal(_, ref exprs) => {
+                self.exprs(exprs);
+                self.declare(site, location, Some(false));
+                false
+            }
+            Stat::IfElse(test, then_block, else_block) => {
+                self.expr(test);
+                let then_ends = self.block(then_block);
+                let else_ends = match else_block {
+                    Some(block) => self.block(block),
+                    None => false,
+                };
+                then_ends && else_ends
+            }
+            Stat::While(test, block) => {
+                self.expr(test);
+                self.block(block);
+                false
+            }
+            Stat::ForRange(_, ref exprs, block) => {
+                self.exprs(exprs);
+                self.declare(site, location, None);
+                self.block(block);
+                false
+            }
+            Stat::ForNumeric(_, start, end, step, block) => {
+                self.exprs(&[start, end, step]);
+                self.declare(site, location, None);
+                self.block(block);
+                false
+            }
+            Stat::GeneralCall(func, ref args) => {
+                self.expr(func);
+                self.exprs(args);
+                false
+            }
+            Stat::ColonCall(table, _, ref args) => {
+                self.expr(table);
+                self.exprs(args);
+                false
+            }
+        }
+    }
+
+    fn exprs(&mut self, exprs: &[ExprId]) {
+        for &expr in exprs {
+            self.expr(expr);
+        }
+    }
+
+    fn expr(&mut self, expr: ExprId) {
+        let ast = self.ast;
+        let location = ast.location(expr);
+        match ast[expr] {
+            Expr::Num(_) | Expr::Boole(_) | Expr::Str(_) | Expr::Nil | Expr::Vararg => {}
+            Expr::Var(Var::Name(name)) => {
+                if ast.name(name) == "self" && self.resolution.lookup(expr) == Some(Resolved::Global) {
+                    let message = "'self' used outside a method".to_string();
+                    self.report(Rule::SelfOutsideMethod, location, message);
+                }
+            }
+            Expr::BinOp(op, left, right) => {
+                let is_comparison = matches!(op,
+                    FlagType::EQ | FlagType::NEQ | FlagType::LEQ | FlagType::GEQ |
+                    FlagType::LESS | FlagType::GREATER);
+                if is_comparison && self.same_value(left, right) {
+                    let message = "comparing a value with itself".to_string();
+                    self.report(Rule::SelfComparison, location, message);
+                }
+                self.exprs(&[left, right]);
+            }
+            Expr::UnaryOp(_, operand) | Expr::Paren(operand) => self.expr(operand),
+            Expr::FunctionDef(_, body) => self.function(body, location),
+            Expr::GeneralCall(func, ref args) => {
+                self.expr(func);
+                self.exprs(args);
+            }
+            Expr::ColonCall(table, _, ref args) => {
+                self.expr(table);
+                self.exprs(args);
+            }
+            Expr::TableCtor(ref entrys) => self.table_ctor(entrys),
+            Expr::TableRef(table, key) => self.exprs(&[table, key]),
+        }
+    }
+
+    fn global_write(&mut self, target: ExprId, name: Name) {
+        let ast = self.ast;
+        if self.resolution.lookup(target) == Some(Resolved::Global) &&
+            !self.config.globals.contains(ast.name(name))
+        {
+            let message = format!("setting undeclared global '{}'", ast.name(name));
+            self.report(Rule::GlobalWrite, ast.location(target), message);
+        }
+    }
+
+    fn table_ctor(&mut self, entrys: &[TableEntry]) {
+        let ast = self.ast;
+        let mut keys = HashSet::new();
+        let mut position = 0;
+        for &(key, value) in entrys {
+            let (const_key, location) = match key {
+                Some(key) => (self.const_key(key), ast.location(key)),
+                // positional entries are keyed from 1
+                None => {
+                    position += 1;
+                    let index = f64::from(position);
+                    (Some(ConstKey::Num(index.to_bits())), ast.location(value))
+                }
+            };
+            if let Some(const_key) = const_key {
+                if !keys.insert(const_key) {
+                    let message = format!("duplicate key {} in table constructor", self.key_text(const_key));
+                    self.report(Rule::DuplicateKey, location, message);
+                }
+            }
+            if let Some(key) = key {
+                self.expr(key);
+            }
+            self.expr(value);
+        }
+    }
+
+    fn const_key(&self, key: ExprId) -> Option<ConstKey> {
+        match self.ast[key] {
+            Expr::Num(num) => Some(ConstKey::Num(num.to_bits())),
+            Expr::Str(s) => Some(ConstKey::Str(s)),
+            Expr::Boole(b) => Some(ConstKey::Boole(b)),
+            Expr::Paren(inner) => self.const_key(inner),
+            _ => None,
+        }
+    }
+
+    fn key_text(&self, key: ConstKey) -> String {
+        match key {
+            ConstKey::Num(bits) => format!("[{}]", f64::from_bits(bits)),
+            ConstKey::Str(s) => format!("'{}'", self.ast.name(s)),
+            ConstKey::Boole(b) => format!("[{}]", b),
+        }
+    }
+
+    /// if both expressions read the same variable or field
+    fn same_value(&self, left: ExprId, right: ExprId) -> bool {
+        let ast = self.ast;
+        match (&ast[left], &ast[right]) {
+            (&Expr::Paren(left), _) => self.same_value(left, right),
+            (_, &Expr::Paren(right)) => self.same_value(left, right),
+            (&Expr::Var(Var::Name(left_name)), &Expr::Var(Var::Name(right_name))) => {
+                left_name == right_name &&
+                    self.resolution.lookup(left) == self.resolution.lookup(right)
+            }
+            (&Expr::TableRef(left_table, left_key), &Expr::TableRef(right_table, right_key)) => {
+                let same_key = match (self.const_key(left_key), self.const_key(right_key)) {
+                    (Some(left_key), Some(right_key)) => left_key == right_key,
+                    _ => self.same_value(left_key, right_key),
+                };
+                same_key && self.same_value(left_table, right_table)
+            }
+            _ => false,
+        }
+    }
+
+    /// record the locals declared at site
+    /// is_param: None for loop variables, whose use is not checked
+    fn declare(&mut self, site: DeclSite, location: Location, is_param: Option<bool>) {
+        let ast = self.ast;
+        for &id in self.resolution.declared(site).to_vec().iter() {
+            self.locations.insert(id, location);
+            if let Some(outer) = self.resolution.shadowed(id) {
+                let name = ast.name(self.resolution.local_name(id));
+                let message = match self.locations.get(&outer) {
+                    Some(outer) => format!("'{}' shadows a local declared at line {}", name, outer.line),
+                    None => format!("'{}' shadows an outer local", name),
+                };
+                self.report(Rule::Shadowing, location, message);
+            }
+            if let Some(is_param) = is_param {
+                self.decls.push(Decl {
+                    id,
+                    location,
+                    is_param,
+                });
+            }
+        }
+    }
+
+    /// locals never read, names starting with `_` are meant to be unused
+    fn unused(&mut self) {
+        let ast = self.ast;
+        let mut unused = vec![];
+        for decl in &self.decls {
+            let name = ast.name(self.resolution.local_name(decl.id));
+            if self.resolution.is_read(decl.id) || name.starts_with('_') {
+                continue;
+            }
+            let (rule, kind) = if decl.is_param {
+                (Rule::UnusedParam, "parameter")
+            } else {
+                (Rule::UnusedLocal, "local")
+            };
+            unused.push((rule, decl.location, format!("unused {} '{}'", kind, name)));
+        }
+        for (rule, location, message) in unused {
+            self.report(rule, location, message);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// rule and line of each finding
+    fn lint_with(text: &str, config: &LintConfig) -> Vec<(Rule, u32)> {
+        Linter::lint_text(&text.to_string(), config)
+            .expect("Lint failed")
+            .into_iter()
+            .map(|finding| (finding.rule, finding.location.line))
+            .collect()
+    }
+
+    fn lint(text: &str) -> Vec<(Rule, u32)> {
+        let mut config = LintConfig::new();
+        config.globals.add_definitions("f g").expect("Bad definitions");
+        lint_with(text, &config)
+    }
+
+    #[test]
+    fn unused_and_shadowing() {
+        let text = "local a, _b = 1, 2
+            local c = 3
+            c = 4
+            f = function(x, y) return y end
+            if a then local a = 1 print(a) end
+            for i = 1, 2 do end";
+        assert_eq!(
+            lint(text),
+            vec![
+                (Rule::UnusedLocal, 2),
+                (Rule::UnusedParam, 4),
+                (Rule::Shadowing, 5),
+            ]
+        );
+        let findings = Linter::lint_text(&text.to_string(), &LintConfig::new()).unwrap();
+        assert_eq!(findings[0].to_string(), "2:13: unused local 'c' (unused-local)");
+        assert_eq!(
+            findings[3].to_string(),
+            "5:23: 'a' shadows a local declared at line 1 (shadowing)"
+        );
+    }
+
+    #[test]
+    fn global_writes() {
+        assert_eq!(
+            lint("f = 1\nh = 2\nlocal t = {}\nt.x = h\nprint = h"),
+            vec![(Rule::GlobalWrite, 2)]
+        );
+    }
+
+    #[test]
+    fn unreachable_code() {
+        let text = "while true do
+                break
+                f()
+                g()
+            end
+            g = function()
+                if f then return 1 else return 2 end
+                f()
+            end
+            g = function()
+                if f then return 1 end
+                f()
+            end";
+        assert_eq!(lint(text), vec![(Rule::Unreachable, 3), (Rule::Unreachable, 8)]);
+    }
+
+    #[test]
+    fn suspicious_exprs() {
+        let text = "local t = {1, x = 2, [1] = 3, ['x'] = 4, y = 5}
+            local a = t.x == t.x, t[1] < t[2], (self)
+            local b = self == a
+            f(a ~= (a), b)";
+        assert_eq!(
+            lint(text),
+            vec![
+                (Rule::DuplicateKey, 1),
+                (Rule::DuplicateKey, 1),
+                (Rule::SelfComparison, 2),
+                (Rule::SelfOutsideMethod, 2),
+                (Rule::SelfOutsideMethod, 3),
+                (Rule::SelfComparison, 4),
+            ]
+        );
+    }
+
+    #[test]
+    fn ignores_and_config() {
+        let text = "local a = 1 -- rua:ignore
+            local b = 1 -- rua:ignore shadowing
+            local c = 1 -- rua:ignore unused-local, shadowing
+            h = 1
+            -- rua:ignore global-write
+            h = 2
+            local d = 1";
+        assert_eq!(
+            lint(text),
+            vec![(Rule::UnusedLocal, 2), (Rule::GlobalWrite, 4), (Rule::UnusedLocal, 7)]
+        );
+        let mut config = LintConfig::new();
+        config
+            .add_settings("-- quiet\nunused-local = false\n\nglobal-write=true")
+            .expect("Bad settings");
+        assert_eq!(lint_with(text, &config), vec![(Rule::GlobalWrite, 4)]);
+        match config.add_settings("shadowing = false\nunused = true") {
+            Err(ConfigError::UnknownRule(2, ref name)) => assert_eq!(name, "unused"),
+            other => panic!("Unexpected result {:?}", other),
+        }
+        assert!(!config.is_enabled(Rule::Shadowing));
+        let misspelled = "local a = 1\nlocal b = 1 -- rua:ignore shadowing, unused-locl";
+        match Linter::lint_text(&misspelled.to_string(), &LintConfig::new()) {
+            Err(CompilerError::Compile(ref err @ CompileError::UnknownRule(..))) => {
+                let message = "2:13: unknown rule 'unused-locl' in rua:ignore comment";
+                assert_eq!(err.to_string(), message)
+            }
+            other => panic!("Unexpected result {:?}", other),
+        }
+        match config.add_settings("shadowing") {
+            Err(ConfigError::BadSetting(1, _)) => {}
+            other => panic!("Unexpected result {:?}", other),
+        }
+    }
+}
@@ -1,25 +1,84 @@
 extern crate clap;
-#[cfg(test)]
-extern crate tempdir;
+extern crate rua;
 
-use clap::{App, Arg};
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
 use std::io::prelude::*;
 use std::fs::File;
+use std::path::Path;
+use std::process;
 
-use compiler::Compiler;
+use rua::compiler::Compiler;
+use rua::ir_generator::types::CompileOptions;
+use rua::globals::Globals;
+use rua::linter::{LintConfig, Linter};
 
-mod lexer;
-mod parser;
-mod ir_generator;
-mod bytecode_generator;
-mod assembler;
-mod compiler;
+/// config file of the linter, read when `--config` is not given
+const LINT_CONFIG: &str = ".rualint";
+
+fn globals_arg() -> Arg<'static, 'static> {
+    Arg::with_name("globals")
+        .long("globals")
+        .value_name("FILE")
+        .help("Adds the globals defined in the file to the standard ones")
+        .takes_value(true)
+        .multiple(true)
+        .number_of_values(1)
+}
+
+fn load_globals(matches: &ArgMatches) -> Globals {
+    let globals_files = matches.values_of("globals").map_or(vec![], |files| files.collect());
+    match Globals::with_files(&globals_files) {
+        Ok(globals) => globals,
+        Err(err) => {
+            eprintln!("rua: failed to load globals: {:?}", err);
+            process::exit(1);
+        }
+    }
+}
+
+/// ret: exit code, 1 if anything is found, 2 if a file can not be linted
+fn lint(matches: &ArgMatches) -> i32 {
+    let mut config = LintConfig::new();
+    config.globals = load_globals(matches);
+    let config_file = matches.value_of("config").or_else(|| {
+        if Path::new(LINT_CONFIG).exists() {
+            Some(LINT_CONFIG)
+        } else {
+            None
+        }
+    });
+    if let Some(config_file) = config_file {
+        if let Err(err) = config.load_file(config_file) {
+            eprintln!("rua: failed to load {}: {:?}", config_file, err);
+            return 2;
+        }
+    }
+    let mut code = 0;
+    for input_file in matches.values_of("INPUT").into_iter().flatten() {
+        match Linter::lint_file(input_file, &config) {
+            Ok(findings) => {
+                for finding in &findings {
+                    println!("{}:{}", input_file, finding);
+                }
+                if !findings.is_empty() && code == 0 {
+                    code = 1;
+                }
+            }
+            Err(err) => {
+                eprintln!("rua: {}: {}", input_file, err);
+                code = 2;
+            }
+        }
+    }
+    code
+}
 
 fn main() {
     let matches = App::new("rua")
         .version("0.1")
         .author("Mingyu Zhou <zhoumy46@gmail.com>")
         .about("A toy Lua compiler in Rust")
+        .setting(AppSettings::SubcommandsNegateReqs)
         .arg(
             Arg::with_name("output")
                 .short("o")
@@ -28,17 +87,76 @@ fn main() {
                 .help("Sets where to write bytecode")
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("strict-globals")
+                .long("strict-globals")
+                .help("Rejects reads of globals never assigned in the source"),
+        )
+        .arg(globals_arg())
+        .arg(
+            Arg::with_name("typed")
+                .long("typed")
+                .help("Accepts and checks type annotations such as `local x: number`"),
+        )
+        .arg(
+            Arg::with_name("warn-dead-code")
+                .long("warn-dead-code")
+                .help("Reports code removed because it never runs"),
+        )
         .arg(
             Arg::with_name("INPUT")
                 .help("Sets the input source file")
                 .required(true)
                 .index(1),
         )
+        .subcommand(
+            SubCommand::with_name("lint")
+                .about("Reports common mistakes, exits with 1 if any is found")
+                .arg(
+                    Arg::with_name("config")
+                        .long("config")
+                        .value_name("FILE")
+                        .help("Sets which rules to check, .rualint by default")
+                        .takes_value(true),
+                )
+                .arg(globals_arg())
+                .arg(
+                    Arg::with_name("INPUT")
+                        .help("Sets the source files to check")
+                        .required(true)
+                        .multiple(true),
+                ),
+        )
         .get_matches();
 
+    if let Some(matches) = matches.subcommand_matches("lint") {
+        process::exit(lint(matches));
+    }
+
     let input_file = matches.value_of("INPUT").unwrap();
     let output_file = matches.value_of("output").unwrap_or("a.out");
-    let bytecode = Compiler::from_file(input_file);
+    let options = CompileOptions {
+        strict_globals: matches.is_present("strict-globals"),
+        globals: load_globals(&matches),
+        typed: matches.is_present("typed"),
+    };
+    let bytecode = match Compiler::compile_file_with_warnings(input_file, options) {
+        Ok((bytecode, warnings)) => {
+            if matches.is_present("warn-dead-code") {
+                for warning in &warnings {
+                    eprintln!("rua: {}:{}: warning: {}",
+                              input_file, warning.location, warning.message);
+                }
+            }
+            bytecode
+        }
+        Err(err) => {
+            for line in err.to_string().lines() {
+                eprintln!("rua: {}: {}", input_file, line);
+            }
+            process::exit(1);
+        }
+    };
 
     let mut file = File::create(output_file).expect("Failed to open file to write");
     file.write_all(&bytecode).expect("Failed to write bytecode");
@@ -0,0 +1,392 @@
+use ir_generator::resolver::{DeclSite, Resolver};
+use lexer::tokens::Location;
+use parser::types::*;
+use super::Warning;
+
+/// removes code that never runs, run after folding so that conditions are constants
+/// a condition is only decided when it is a constant, which has no side effects
+#[derive(Debug)]
+pub struct DeadCode<'a> {
+    ast: &'a mut Ast,
+    warnings: Vec<Warning>,
+    /// local statements that define functions: block, index of the statement
+    local_functions: Vec<(BlockId, usize)>,
+}
+
+impl<'a> DeadCode<'a> {
+    /// ret: what has been removed
+    pub fn eliminate(ast: &'a mut Ast) -> Vec<Warning> {
+        let root = match ast.root {
+            Node::Block(root) => root,
+            Node::Expr(_) => return vec![],
+        };
+        let mut pass = DeadCode {
+            ast,
+            warnings: vec![],
+            local_functions: vec![],
+        };
+        pass.block(root);
+        pass.strip_functions();
+        pass.warnings
+    }
+
+    fn warn(&mut self, location: Location, message: &str) {
+        self.warnings.push(Warning {
+            location,
+            message: message.to_string(),
+        });
+    }
+
+    /// ret: if control never leaves the end of the block
+    fn block(&mut self, block: BlockId) -> bool {
+        let old_stats = self.ast[block].stats.clone();
+        let old_locations = self.ast[block].locations.clone();
+        let mut stats = vec![];
+        let mut locations = vec![];
+        let mut ret = self.ast[block].ret.clone();
+        let mut ends = false;
+        // an inlined return has been visited with its block
+        let mut inlined_ret = false;
+        for (i, stat) in old_stats.into_iter().enumerate() {
+            if ends {
+                // only the first unreachable statement is reported
+                self.warn(old_locations[i], "unreachable code");
+                ret = None;
+                break;
+            }
+            match self.stat(stat, old_locations[i]) {
+                Replace::Keep(stat, stat_ends) => {
+                    stats.push(stat);
+                    locations.push(old_locations[i]);
+                    ends = stat_ends;
+                }
+                Replace::Inline(inner) => {
+                    let inner = self.ast[inner].clone();
+                    stats.extend(inner.stats);
+                    locations.extend(inner.locations);
+                    if inner.ret.is_some() {
+                        // the rest of the block is unreachable
+                        if let Some(&location) = old_locations.get(i + 1) {
+                            self.warn(location, "unreachable code");
+                        } else if let Some(&expr) = ret.as_ref().and_then(|ret| ret.first()) {
+                            self.warn(self.ast.location(expr), "unreachable code");
+                        }
+                        ret = inner.ret;
+                        inlined_ret = true;
+                        break;
+                    }
+                }
+                Replace::Remove => {}
+            }
+        }
+        if ends {
+            if let Some(&expr) = ret.as_ref().and_then(|ret| ret.first()) {
+                self.warn(self.ast.location(expr), "unreachable code");
+            }
+            ret = None;
+        }
+        if let Some(ref ret) = ret {
+            if !inlined_ret {
+                self.exprs(ret);
+            }
+        }
+        for (i, stat) in stats.iter().enumerate() {
+            if let Stat::AssignLocal(_, ref exprs) = *stat {
+                if exprs.iter().any(|&expr| self.is_function(expr)) {
+                    self.local_functions.push((block, i));
+                }
+            }
+        }
+        let ends = ends || ret.is_some();
+        self.ast[block] = Block::new(stats, locations, ret);
+        ends
+    }
+
+    fn stat(&mut self, stat: Stat, location: Location) -> Replace {
+        match stat {
+            Stat::Break | Stat::Ret(_) => {
+                if let Stat::Ret(ref exprs) = stat {
+                    self.exprs(exprs);
+                }
+                Replace::Keep(stat, true)
+            }
+            Stat::IfElse(test, then_block, else_block) => {
+                let taken = match self.truthiness(test) {
+                    Some(true) => Some(then_block),
+                    Some(false) => else_block,
+                    None => {
+                        self.expr(test);
+                        let then_ends = self.block(then_block);
+                        let else_ends = match else_block {
+                            Some(block) => self.block(block),
+                            None => false,
+                        };
+                        return Replace::Keep(stat, then_ends && else_ends);
+                    }
+                };
+                if else_block.is_some() || taken.is_none() {
+                    self.warn(location, "branch never taken, the condition is constant");
+                }
+                match taken {
+                    Some(taken) => {
+                        let ends = self.block(taken);
+                        if self.declares_locals(taken) {
+                            // the block keeps its own scope
+                            self.ast[test] = Expr::Boole(true);
+                            Replace::Keep(Stat::IfElse(test, taken, None), ends)
+                        } else {
+                            Replace::Inline(taken)
+                        }
+                    }
+                    None => Replace::Remove,
+                }
+            }
+            Stat::While(test, body) => {
+                match self.truthiness(test) {
+                    Some(false) => {
+                        self.warn(location, "loop never runs, the condition is constant");
+                        Replace::Remove
+                    }
+                    truthiness => {
+                        self.expr(test);
+                        self.block(body);
+                        let endless = truthiness == Some(true) && !self.breaks(body);
+                        Replace::Keep(stat, endless)
+                    }
+                }
+            }
+            Stat::Empty => Replace::Keep(stat, false),
+            Stat::Assign(ref targets, ref exprs) => {
+                self.exprs(targets);
+                self.exprs(exprs);
+                Replace::Keep(stat.clone(), false)
+            }
+            Stat::AssignLocal(_, ref exprs) => {
+                self.exprs(exprs);
+                Replace::Keep(stat.clone(), false)
+            }
+            Stat::ForRange(_, ref exprs, block) => {
+                self.exprs(exprs);
+                self.block(block);
+                Replace::Keep(stat.clone(), false)
+            }
+            Stat::ForNumeric(_, start, end, step, block) => {
+                self.exprs(&[start, end, step]);
+                self.block(block);
+                Replace::Keep(stat, false)
+            }
+            Stat::GeneralCall(func, ref args) => {
+                self.expr(func);
+                self.exprs(args);
+                Replace::Keep(stat.clone(), false)
+            }
+            Stat::ColonCall(table, _, ref args) => {
+                self.expr(table);
+                self.exprs(args);
+                Replace::Keep(stat.clone(), false)
+            }
+        }
+    }
+
+    fn exprs(&mut self, exprs: &[ExprId]) {
+        for &expr in exprs {
+            self.expr(expr);
+        }
+    }
+
+    /// visit the bodies of functions in the expression
+    fn expr(&mut self, expr: ExprId) {
+        match self.ast[expr].clone() {
+            Expr::Num(_) | Expr::Boole(_) | Expr::Str(_) | Expr::Nil | Expr::Var(_) |
+            Expr::Vararg => {}
+            Expr::BinOp(_, left, right) | Expr::TableRef(left, right) => {
+                self.exprs(&[left, right])
+            }
+            Expr::UnaryOp(_, operand) | Expr::Paren(operand) => self.expr(operand),
+            Expr::FunctionDef(_, body) => {
+                self.block(body);
+            }
+            Expr::GeneralCall(func, args) | Expr::ColonCall(func, _, args) => {
+                self.expr(func);
+                self.exprs(&args);
+            }
+            Expr::TableCtor(entrys) => {
+                for (key, value) in entrys {
+                    if let Some(key) = key {
+                        self.expr(key);
+                    }
+                    self.expr(value);
+                }
+            }
+        }
+    }
+
+    /// truthiness of a constant condition
+    fn truthiness(&self, expr: ExprId) -> Option<bool> {
+        match self.ast[expr] {
+            Expr::Nil | Expr::Boole(false) => Some(false),
+            Expr::Boole(true) | Expr::Num(_) | Expr::Str(_) => Some(true),
+            _ => None,
+        }
+    }
+
+    fn is_function(&self, expr: ExprId) -> bool {
+        matches!(self.ast[expr], Expr::FunctionDef(..))
+    }
+
+    /// if merging the block into its parent would change the scope of a local
+    fn declares_locals(&self, block: BlockId) -> bool {
+        self.ast[block]
+            .stats
+            .iter()
+            .any(|stat| matches!(*stat, Stat::AssignLocal(..)))
+    }
+
+    /// if a break in the block leaves the loop around it
+    fn breaks(&self, block: BlockId) -> bool {
+        self.ast[block].stats.iter().any(|stat| match *stat {
+            Stat::Break => true,
+            Stat::IfElse(_, then_block, else_block) => {
+                self.breaks(then_block) || else_block.is_some_and(|block| self.breaks(block))
+            }
+            _ => false,
+        })
+    }
+
+    /// replace functions assigned to locals that are never read,
+    /// repeated since a removed function may be the only reader of another
+    fn strip_functions(&mut self) {
+        loop {
+            let resolution = match Resolver::resolve(self.ast) {
+                Ok(resolution) => resolution,
+                // reported by code generation
+                Err(_) => return,
+            };
+            let mut stripped = false;
+            for &(block, i) in &self.local_functions {
+                let exprs = match self.ast[block].stats[i] {
+                    Stat::AssignLocal(_, ref exprs) => exprs.clone(),
+                    _ => continue,
+                };
+                let ids = resolution.declared(DeclSite::Stat(block, i));
+                for (&id, expr) in ids.iter().zip(exprs) {
+                    if self.is_function(expr) && !resolution.is_read(id) {
+                        let name = self.ast.name(resolution.local_name(id)).to_string();
+                        self.warnings.push(Warning {
+                            location: self.ast.location(expr),
+                            message: format!("unused function '{}'", name),
+                        });
+                        self.ast[expr] = Expr::Nil;
+                        stripped = true;
+                    }
+                }
+            }
+            if !stripped {
+                return;
+            }
+        }
+    }
+}
+
+/// what a statement becomes
+enum Replace {
+    /// the statement, and if control never reaches the next one
+    Keep(Stat, bool),
+    /// the statements of a branch that is always taken
+    Inline(BlockId),
+    Remove,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use optimizer;
+    use parser::Parser;
+    use std::str::Chars;
+
+    /// statement kinds of the main block, and the warnings
+    fn eliminate(text: &str) -> (Ast, Vec<String>) {
+        let mut ast = Parser::<Chars>::ast_from_text(&text.to_string()).expect("Parse Error");
+        let warnings = optimizer::optimize(&mut ast);
+        let warnings = warnings.iter().map(|warning| warning.to_string()).collect();
+        (ast, warnings)
+    }
+
+    fn root(ast: &Ast) -> &Block {
+        match ast.root {
+            Node::Block(root) => &ast[root],
+            _ => panic!("Root node should be a block"),
+        }
+    }
+
+    #[test]
+    fn unreachable_and_constant_branches() {
+        let text = "\
+            print(1)
+            if true then print(2) else print(3) end
+            if nil then print(4) end
+            while false do print(5) end
+            if 1 > 2 then print(6) elseif 'x' then local y = 7 print(y) end
+            while true do
+                if f() then print(7) end
+                break
+                print(8)
+            end
+            print(10)
+            return
+        ";
+        let (ast, warnings) = eliminate(text);
+        let main = root(&ast);
+        assert_eq!(main.stats.len(), 5);
+        match main.stats[1] {
+            Stat::GeneralCall(..) => {}
+            ref other => panic!("Branch should be inlined, got {:?}", other),
+        }
+        match main.stats[2] {
+            Stat::IfElse(_, block, None) => assert_eq!(ast[block].stats.len(), 2),
+            ref other => panic!("Scope should be kept, got {:?}", other),
+        }
+        assert_eq!(
+            warnings,
+            vec![
+                "2:13: branch never taken, the condition is constant",
+                "3:13: branch never taken, the condition is constant",
+                "4:13: loop never runs, the condition is constant",
+                "5:13: branch never taken, the condition is constant",
+                "9:17: unreachable code",
+            ]
+        );
+
+        let (ast, warnings) = eliminate("while 1 do print(1) end\nprint(2)\nreturn 3");
+        assert_eq!(root(&ast).stats.len(), 1);
+        assert!(root(&ast).ret.is_none());
+        assert_eq!(warnings, vec!["2:1: unreachable code"]);
+        let (ast, _) = eliminate("if true then return 1 end\nprint(2)");
+        assert!(root(&ast).stats.is_empty() && root(&ast).ret.is_some());
+    }
+
+    #[test]
+    fn unused_functions() {
+        let text = "\
+            local helper = function() return 1 end
+            local used = function() return 2 end
+            local only_dead = function() return helper() end
+            local unused = 3
+            if false then only_dead() end
+            print(used())
+        ";
+        let (ast, warnings) = eliminate(text);
+        match root(&ast).stats[0] {
+            Stat::AssignLocal(_, ref exprs) => assert_eq!(ast[exprs[0]], Expr::Nil),
+            ref other => panic!("Local statement expected, got {:?}", other),
+        }
+        assert_eq!(
+            warnings,
+            vec![
+                "1:16: unused function 'helper'",
+                "3:31: unused function 'only_dead'",
+                "5:13: branch never taken, the condition is constant",
+            ]
+        );
+    }
+}
@@ -0,0 +1,409 @@
+use std::cmp::Ordering;
+use lexer::tokens::FlagType;
+use parser::types::*;
+
+/// value of a constant expression
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Const {
+    Nil,
+    Boole(bool),
+    Num(f64),
+    Str(Name),
+}
+
+impl Const {
+    fn is_truthy(self) -> bool {
+        !matches!(self, Const::Nil | Const::Boole(false))
+    }
+
+    fn into_expr(self) -> Expr {
+        match self {
+            Const::Nil => Expr::Nil,
+            Const::Boole(b) => Expr::Boole(b),
+            Const::Num(n) => Expr::Num(n),
+            Const::Str(s) => Expr::Str(s),
+        }
+    }
+}
+
+/// evaluates constant expressions in place, following Lua 5.1 semantics
+/// operations that would raise an error or produce NaN are left to run time
+#[derive(Debug)]
+pub struct Folder<'a> {
+    ast: &'a mut Ast,
+}
+
+impl<'a> Folder<'a> {
+    pub fn fold(ast: &'a mut Ast) {
+        if let Node::Block(root) = ast.root {
+            Folder { ast }.block(root);
+        }
+    }
+
+    fn block(&mut self, block: BlockId) {
+        let stats = self.ast[block].stats.clone();
+        for stat in &stats {
+            self.stat(stat);
+        }
+        if let Some(ret) = self.ast[block].ret.clone() {
+            self.exprs(&ret);
+        }
+    }
+
+    fn stat(&mut self, stat: &Stat) {
+        match *stat {
+            Stat::Empty | Stat::Break => {}
+            Stat::Assign(ref targets, ref exprs) => {
+                self.exprs(targets);
+                self.exprs(exprs);
+            }
+            Stat::AssignLocal(_, ref exprs) | Stat::Ret(ref exprs) => self.exprs(exprs),
+            Stat::IfElse(test, then_block, else_block) => {
+                self.expr(test);
+                self.block(then_block);
+                if let Some(else_block) = else_block {
+                    self.block(else_block);
+                }
+            }
+            Stat::While(test, block) => {
+                self.expr(test);
+                self.block(block);
+            }
+            Stat::ForRange(_, ref exprs, block) => {
+                self.exprs(exprs);
+                self.block(block);
+            }
+            Stat::ForNumeric(_, start, end, step, block) => {
+                self.exprs(&[start, end, step]);
+                self.block(block);
+            }
+            Stat::GeneralCall(func, ref args) => {
+                self.expr(func);
+                self.exprs(args);
+            }
+            Stat::ColonCall(table, _, ref args) => {
+                self.expr(table);
+                self.exprs(args);
+            }
+        }
+    }
+
+    fn exprs(&mut self, exprs: &[ExprId]) {
+        for &expr in exprs {
+            self.expr(expr);
+        }
+    }
+
+    /// fold the operands first, then the expression itself
+    fn expr(&mut self, expr: ExprId) {
+        let folded = match self.ast[expr].clone() {
+            Expr::Num(_) | Expr::Boole(_) | Expr::Str(_) | Expr::Nil | Expr::Var(_) |
+            Expr::Vararg => None,
+            Expr::BinOp(op, left, right) => {
+                self.exprs(&[left, right]);
+                self.binop(op, left, right)
+            }
+            Expr::UnaryOp(op, operand) => {
+                self.expr(operand);
+                self.unop(op, operand)
+            }
+            Expr::Paren(inner) => {
+                self.expr(inner);
+                self.constant(inner).map(Const::into_expr)
+            }
+            Expr::FunctionDef(_, body) => {
+                self.block(body);
+                None
+            }
+            Expr::GeneralCall(func, args) => {
+                self.expr(func);
+                self.exprs(&args);
+                None
+            }
+            Expr::ColonCall(table, _, args) => {
+                self.expr(table);
+                self.exprs(&args);
+                None
+            }
+            Expr::TableCtor(entrys) => {
+                for (key, value) in entrys {
+                    if let Some(key) = key {
+                        self.expr(key);
+                    }
+                    self.expr(value);
+                }
+                None
+            }
+            Expr::TableRef(table, key) => {
+                self.exprs(&[table, key]);
+                None
+            }
+        };
+        if let Some(folded) = folded {
+            self.ast[expr] = folded;
+        }
+    }
+
+    fn constant(&self, expr: ExprId) -> Option<Const> {
+        match self.ast[expr] {
+            Expr::Nil => Some(Const::Nil),
+            Expr::Boole(b) => Some(Const::Boole(b)),
+            Expr::Num(n) => Some(Const::Num(n)),
+            Expr::Str(s) => Some(Const::Str(s)),
+            Expr::Paren(inner) => self.constant(inner),
+            _ => None,
+        }
+    }
+
+    /// arithmetic operand, strings are coerced to numbers
+    fn number(&self, expr: ExprId) -> Option<f64> {
+        match self.constant(expr)? {
+            Const::Num(n) => Some(n),
+            Const::Str(s) => str_to_number(self.ast.name(s)),
+            _ => None,
+        }
+    }
+
+    /// operand of `..`, numbers are converted to strings
+    fn text(&self, expr: ExprId) -> Option<String> {
+        match self.constant(expr)? {
+            Const::Str(s) => Some(self.ast.name(s).to_string()),
+            // the text of a NaN depends on the machine
+            Const::Num(n) if !n.is_nan() => Some(number_to_str(n)),
+            _ => None,
+        }
+    }
+
+    fn binop(&mut self, op: FlagType, left: ExprId, right: ExprId) -> Option<Expr> {
+        match op {
+            FlagType::AND | FlagType::OR => {
+                let left_value = self.constant(left)?;
+                // `and` keeps a false left operand, `or` keeps a true one
+                if left_value.is_truthy() == (op == FlagType::OR) {
+                    return Some(left_value.into_expr());
+                }
+                // the right operand is truncated to one value like the whole expression
+                Some(match self.constant(right) {
+                    Some(right_value) => right_value.into_expr(),
+                    None => Expr::Paren(right),
+                })
+            }
+            FlagType::EQ | FlagType::NEQ => {
+                let equal = self.constant(left)? == self.constant(right)?;
+                Some(Expr::Boole(equal == (op == FlagType::EQ)))
+            }
+            FlagType::LESS | FlagType::LEQ | FlagType::GREATER | FlagType::GEQ => {
+                let order = match (self.constant(left)?, self.constant(right)?) {
+                    // NaN is unordered, every comparison is false
+                    (Const::Num(l), Const::Num(r)) => l.partial_cmp(&r),
+                    (Const::Str(l), Const::Str(r)) => {
+                        Some(self.ast.name(l).as_bytes().cmp(self.ast.name(r).as_bytes()))
+                    }
+                    // comparing other types is an error
+                    _ => return None,
+                };
+                let result = match (op, order) {
+                    (_, None) => false,
+                    (FlagType::LESS, Some(order)) => order == Ordering::Less,
+                    (FlagType::LEQ, Some(order)) => order != Ordering::Greater,
+                    (FlagType::GREATER, Some(order)) => order == Ordering::Greater,
+                    (_, Some(order)) => order != Ordering::Less,
+                };
+                Some(Expr::Boole(result))
+            }
+            FlagType::Plus | FlagType::Minus | FlagType::Mul | FlagType::Div | FlagType::Pow => {
+                let (l, r) = (self.number(left)?, self.number(right)?);
+                let result = match op {
+                    FlagType::Plus => l + r,
+                    FlagType::Minus => l - r,
+                    FlagType::Mul => l * r,
+                    FlagType::Div => l / r,
+                    _ => l.powf(r),
+                };
+                // the sign of a NaN made at run time depends on the machine
+                if result.is_nan() {
+                    None
+                } else {
+                    Some(Expr::Num(result))
+                }
+            }
+            FlagType::DoubleDot => {
+                let text = self.text(left)? + &self.text(right)?;
+                Some(Expr::Str(self.ast.interner.intern(&text)))
+            }
+            _ => None,
+        }
+    }
+
+    fn unop(&self, op: FlagType, operand: ExprId) -> Option<Expr> {
+        match op {
+            FlagType::Minus => self.number(operand).map(|n| Expr::Num(-n)),
+            // the length of a string is its number of bytes
+            FlagType::Len => match self.constant(operand)? {
+                Const::Str(s) => Some(Expr::Num(self.ast.name(s).len() as f64)),
+                _ => None,
+            },
+            FlagType::Not => match self.constant(operand) {
+                Some(value) => Some(Expr::Boole(!value.is_truthy())),
+                // `not (a == b)` is `a ~= b` even with metamethods
+                None => match self.ast[self.strip_paren(operand)] {
+                    Expr::BinOp(FlagType::EQ, left, right) => {
+                        Some(Expr::BinOp(FlagType::NEQ, left, right))
+                    }
+                    Expr::BinOp(FlagType::NEQ, left, right) => {
+                        Some(Expr::BinOp(FlagType::EQ, left, right))
+                    }
+                    _ => None,
+                },
+            },
+            _ => None,
+        }
+    }
+
+    fn strip_paren(&self, expr: ExprId) -> ExprId {
+        match self.ast[expr] {
+            Expr::Paren(inner) => self.strip_paren(inner),
+            _ => expr,
+        }
+    }
+}
+
+/// string to number conversion of Lua, limited to decimal numbers
+/// other strings, such as hexadecimals, are converted at run time
+fn str_to_number(text: &str) -> Option<f64> {
+    // isspace of C
+    let text = text.trim_matches(|c| c == ' ' || ('\t'..='\r').contains(&c));
+    let is_decimal = !text.is_empty() &&
+        text.chars().all(|c| c.is_ascii_digit() || "+-.eE".contains(c));
+    if is_decimal {
+        text.parse().ok()
+    } else {
+        None
+    }
+}
+
+/// number to string conversion of Lua, `%.14g` of C
+fn number_to_str(n: f64) -> String {
+    if n.is_infinite() {
+        return if n > 0.0 { "inf" } else { "-inf" }.to_string();
+    }
+    // the exponent once rounded to 14 significant digits
+    let scientific = format!("{:.13e}", n);
+    let (mantissa, exponent) = scientific.split_at(scientific.find('e').unwrap_or(0));
+    let exponent: i32 = exponent[1..].parse().unwrap_or(0);
+    if exponent < -4 || exponent >= 14 {
+        let sign = if exponent < 0 { '-' } else { '+' };
+        format!("{}e{}{:02}", trim_fraction(mantissa), sign, exponent.abs())
+    } else {
+        trim_fraction(&format!("{:.*}", (13 - exponent) as usize, n)).to_string()
+    }
+}
+
+/// trailing zeros of the fraction are dropped, and the point if nothing is left after it
+fn trim_fraction(text: &str) -> &str {
+    if text.contains('.') {
+        text.trim_end_matches('0').trim_end_matches('.')
+    } else {
+        text
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use parser::Parser;
+    use std::str::Chars;
+
+    /// folded values of a return statement
+    fn fold(text: &str) -> Vec<Expr> {
+        let mut ast = Parser::<Chars>::ast_from_text(&format!("return {}", text))
+            .expect("Parse Error");
+        Folder::fold(&mut ast);
+        let root = match ast.root {
+            Node::Block(root) => root,
+            _ => panic!("Root node should be a block"),
+        };
+        let ret = ast[root].ret.clone().expect("Return expected");
+        ret.into_iter().map(|expr| ast[expr].clone()).collect()
+    }
+
+    fn num(n: f64) -> Expr {
+        Expr::Num(n)
+    }
+
+    #[test]
+    fn arith() {
+        assert_eq!(
+            fold("3 * (1 + 1), 10 / 4, -(2 - 5), ' 8 ' * '2', 1 / 0, -1 / 0"),
+            vec![num(6.0), num(2.5), num(3.0), num(16.0), num(f64::INFINITY),
+                 num(f64::NEG_INFINITY)]
+        );
+        assert_eq!(fold("-2 ^ 2, 2 ^ -1, #'abc', #''"),
+                   vec![num(-4.0), num(0.5), num(3.0), num(0.0)]);
+        // left to run time
+        for text in &["0 / 0", "'0x10' + 1", "'a' + 1", "nil + 1", "-true", "x + 1", "#{}"] {
+            if let Expr::Num(_) = fold(text)[0] {
+                panic!("{} should not be folded", text);
+            }
+        }
+    }
+
+    #[test]
+    fn logic() {
+        assert_eq!(
+            fold("not nil, not 0, 1 == 1, 1 == '1', 'a' ~= 'b', 'a' < 'b', 2 >= 3, nil == false"),
+            vec![
+                Expr::Boole(true),
+                Expr::Boole(false),
+                Expr::Boole(true),
+                Expr::Boole(false),
+                Expr::Boole(true),
+                Expr::Boole(true),
+                Expr::Boole(false),
+                Expr::Boole(false),
+            ]
+        );
+        let folded = fold("nil or 5, false and x, 1 and f(), 1 < 'a', not (x == y)");
+        assert_eq!(folded[0], num(5.0));
+        assert_eq!(folded[1], Expr::Boole(false));
+        match folded[2] {
+            Expr::Paren(_) => {}
+            ref other => panic!("Call should be truncated, got {:?}", other),
+        }
+        match folded[3] {
+            Expr::BinOp(FlagType::LESS, _, _) => {}
+            ref other => panic!("Comparison should be kept, got {:?}", other),
+        }
+        match folded[4] {
+            Expr::BinOp(FlagType::NEQ, _, _) => {}
+            ref other => panic!("Negation should be simplified, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn concat() {
+        let text = "'a' .. 'b' .. 1, 2 .. '', 0.1 .. '', -0 .. '', 2 ^ 53 .. '', 1 / 3 .. '', \
+                    10 ^ 100 .. '', 2 ^ -20 .. '', 1 / 0 .. '', 123456789012345 .. ''";
+        let mut ast = Parser::<Chars>::ast_from_text(&format!("return {}", text))
+            .expect("Parse Error");
+        Folder::fold(&mut ast);
+        let root = match ast.root {
+            Node::Block(root) => root,
+            _ => panic!("Root node should be a block"),
+        };
+        let folded = ast[root].ret.clone().expect("Return expected").into_iter().map(|expr| {
+            match ast[expr] {
+                Expr::Str(s) => ast.name(s).to_string(),
+                ref other => panic!("String expected, got {:?}", other),
+            }
+        }).collect::<Vec<_>>();
+        assert_eq!(folded, vec!["ab1", "2", "0.1", "-0", "9.007199254741e+15", "0.33333333333333",
+                                "1e+100", "9.5367431640625e-07", "inf", "1.2345678901234e+14"]);
+        // left to run time
+        for text in &["'a' .. x", "'a' .. nil", "{} .. 'a'", "0 / 0 .. ''"] {
+            if let Expr::Str(_) = fold(text)[0] {
+                panic!("{} should not be folded", text);
+            }
+        }
+    }
+}
@@ -0,0 +1,30 @@
+use lexer::tokens::Location;
+use parser::types::Ast;
+use self::dead_code::DeadCode;
+use self::folder::Folder;
+use std::fmt;
+
+pub mod dead_code;
+pub mod folder;
+
+/// code removed by the optimizer, which is likely a mistake
+#[derive(Clone, Debug, PartialEq)]
+pub struct Warning {
+    pub location: Location,
+    pub message: String,
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.location, self.message)
+    }
+}
+
+/// simplify the tree before code generation
+/// ret: warnings about removed code, in source order
+pub fn optimize(ast: &mut Ast) -> Vec<Warning> {
+    Folder::fold(ast);
+    let mut warnings = DeadCode::eliminate(ast);
+    warnings.sort_by_key(|warning| warning.location);
+    warnings
+}
@@ -1,15 +1,34 @@
-use lexer::tokens::{Token, FlagType};
-use lexer::{Lexer, TokenIterator};
+use lexer::tokens::{Token, FlagType, Location};
+use lexer::{Lexer, TokenIterator, TokenizeError};
 use self::types::*;
 use std::iter;
 
 pub mod types;
 
+/// names of parameters, if ends with '...', annotation of each name
+type ParList = ((Vec<Name>, bool), Vec<Option<Type>>);
+
 pub struct Parser<'a, Tit>
     where Tit: iter::Iterator<Item = char> + Clone
 {
-    /// peekable tokenizer
-    token_iter: iter::Peekable<TokenIterator<'a, Tit>>,
+    /// tokenizer
+    token_iter: TokenIterator<'a, Tit>,
+    /// next token, filled when peeked
+    lookahead: Option<Result<Token, TokenizeError>>,
+    lookahead_location: Location,
+    /// the token after lookahead, filled by `peek_second`
+    second_lookahead: Option<Result<Token, TokenizeError>>,
+    second_location: Location,
+    /// error reported by tokenizer, parsing stops at the bad token
+    tokenize_error: Option<TokenizeError>,
+    /// depth of nested blocks and expressions
+    depth: usize,
+    /// lines where the enclosing functions start, the innermost last
+    functions: Vec<u32>,
+    /// if type annotations are accepted
+    typed: bool,
+    /// arena of parsed nodes
+    ast: Ast,
 }
 
 /// Common method
@@ -17,41 +36,173 @@ pub struct Parser<'a, Tit>
 impl<'a, Tit> Parser<'a, Tit>
     where Tit: iter::Iterator<Item = char> + Clone
 {
-    pub fn parse(text: TokenIterator<'a, Tit>) -> Result<Node, ParserError> {
-        let mut obj = Parser { token_iter: text.peekable() };
-        obj.program()
+    fn new(text: TokenIterator<'a, Tit>) -> Parser<'a, Tit> {
+        Parser {
+            token_iter: text,
+            lookahead: None,
+            lookahead_location: Location::default(),
+            second_lookahead: None,
+            second_location: Location::default(),
+            tokenize_error: None,
+            depth: 0,
+            functions: vec![],
+            typed: false,
+            ast: Ast::new(),
+        }
+    }
+
+    pub fn parse(text: TokenIterator<'a, Tit>) -> Result<Ast, ParserError> {
+        Parser::new(text).chunk()
+    }
+
+    /// parse the typed dialect, annotations are kept in `Ast::annotations`
+    pub fn parse_typed(text: TokenIterator<'a, Tit>) -> Result<Ast, ParserError> {
+        let mut obj = Parser::new(text);
+        obj.typed = true;
+        obj.chunk()
+    }
+
+    fn chunk(mut self) -> Result<Ast, ParserError> {
+        let result = self.program();
+        let root = self.report(result)?;
+        Ok(self.finish(Node::Block(root)))
     }
 
-    pub fn ast_from_text(text: &String) -> Result<Node, ParserError> {
+    /// parse a single expression which takes the whole input
+    /// e.g. `1 + 2` typed in a REPL
+    pub fn parse_expr(text: TokenIterator<'a, Tit>) -> Result<Ast, ParserError> {
+        let mut obj = Parser::new(text);
+        let result = obj.expr().and_then(|expr| {
+            obj.eat(FlagType::EOF).or(Err(ParserError::SyntaxError))?;
+            Ok(expr)
+        });
+        let root = obj.report(result)?;
+        Ok(obj.finish(Node::Expr(root)))
+    }
+
+    pub fn ast_from_text(text: &String) -> Result<Ast, ParserError> {
         let lex = Lexer::new();
         let token_it = lex.tokenize(text.chars());
         Parser::parse(token_it)
     }
 
+    pub fn typed_ast_from_text(text: &str) -> Result<Ast, ParserError> {
+        let lex = Lexer::new();
+        let token_it = lex.tokenize(text.chars());
+        Parser::parse_typed(token_it)
+    }
+
+    pub fn expr_from_text(text: &String) -> Result<Ast, ParserError> {
+        let lex = Lexer::new();
+        let token_it = lex.tokenize(text.chars());
+        Parser::parse_expr(token_it)
+    }
+
+    /// hand over the nodes and names to the tree
+    fn finish(self, root: Node) -> Ast {
+        let mut ast = self.ast;
+        ast.comments = self.token_iter.comments().to_vec();
+        ast.interner = self.token_iter.into_interner();
+        ast.root = root;
+        ast
+    }
+
+    /// prefer the tokenizer error to the syntax error it caused,
+    /// and tell errors caused by running out of input
+    fn report<T>(&mut self, result: Result<T, ParserError>) -> Result<T, ParserError> {
+        let err = match result {
+            Ok(_) | Err(ParserError::Limit(_)) => return result,
+            Err(err) => err,
+        };
+        if let Some(ref tokenize_err) = self.tokenize_error {
+            return Err(ParserError::TokenizeError(tokenize_err.clone()));
+        }
+        match self.peek_clone() {
+            Some(Token::Flag(FlagType::EOF)) => Err(ParserError::UnexpectedEOF),
+            _ => Err(err),
+        }
+    }
+
+    /// a tokenizer error is seen as the end of token stream
     fn peek_clone(&mut self) -> Option<Token> {
-        // println!("{:?}", self.token_iter.peek());
-        self.token_iter.peek().map(|t| t.clone())
+        if self.lookahead.is_none() {
+            self.lookahead = self.token_iter.next();
+            self.lookahead_location = self.token_iter.location();
+        }
+        match self.lookahead {
+            Some(Ok(ref token)) => Some(token.clone()),
+            Some(Err(ref err)) => {
+                self.tokenize_error = Some(err.clone());
+                None
+            }
+            None => None,
+        }
+    }
+
+    /// look at the token after the peeked one
+    /// its tokenizer error is recorded when it becomes the peeked one
+    fn peek_second(&mut self) -> Option<Token> {
+        self.peek_clone()?;
+        if self.second_lookahead.is_none() {
+            self.second_lookahead = self.token_iter.next();
+            self.second_location = self.token_iter.location();
+        }
+        match self.second_lookahead {
+            Some(Ok(ref token)) => Some(token.clone()),
+            _ => None,
+        }
+    }
+
+    /// where the peeked token starts
+    fn location(&mut self) -> Location {
+        self.peek_clone();
+        self.lookahead_location
     }
 
     /// compare the current token with the passed token
     /// if they match, advance tokenizer
     /// otherwise raise an error
     fn eat(&mut self, ft: FlagType) -> Result<(), ParserError> {
-        if let Some(true) = self.token_iter.peek().map(|token| {
-            // map: end reference scope
-            match *token {
+        if let Some(true) = self.peek_clone().map(|token| {
+            match token {
                 Token::Num(_) => ft == FlagType::Integer,
                 Token::Name(_) => ft == FlagType::Name,
                 Token::Str(_) => ft == FlagType::Str,
                 Token::Flag(t) => ft == t,
             }
         }) {
-            self.token_iter.next();
+            self.lookahead = self.second_lookahead.take();
+            self.lookahead_location = self.second_location;
             Ok(())
         } else {
             Err(ParserError::ExpectationUnmeet)
         }
     }
+
+    /// guard recursive rules against stack overflow
+    fn enter(&mut self) -> Result<(), ParserError> {
+        if self.depth >= Limit::Nesting.max() {
+            return Err(ParserError::Limit(LimitError {
+                limit: Limit::Nesting,
+                function: self.functions.last().cloned(),
+                location: self.location(),
+            }));
+        }
+        self.depth += 1;
+        Ok(())
+    }
+
+    fn leave(&mut self) {
+        self.depth -= 1;
+    }
+
+    fn add_expr(&mut self, expr: Expr, location: Location) -> ExprId {
+        self.ast.add_expr(expr, location)
+    }
+
+    fn add_block(&mut self, block: Block) -> BlockId {
+        self.ast.add_block(block)
+    }
 }
 
 /// Expression parser
@@ -59,38 +210,72 @@ impl<'a, Tit> Parser<'a, Tit>
 impl<'a, Tit> Parser<'a, Tit>
     where Tit: iter::Iterator<Item = char> + Clone
 {
-    /// rule: factor: (Plus | Minus | Not) factor | Integer | String| Boolean | LParen expr RParen | Var
-    fn factor(&mut self) -> Result<Expr, ParserError> {
+    /// rule: factor: (Plus | Minus | Not | Len) factor | power
+    fn factor(&mut self) -> Result<ExprId, ParserError> {
+        let start = self.location();
+        match self.peek_clone() {
+            Some(Token::Flag(t)) if t == FlagType::Plus || t == FlagType::Minus ||
+                                    t == FlagType::Not || t == FlagType::Len => {
+                self.eat(t)?;
+                self.enter()?;
+                let node = self.factor();
+                self.leave();
+                let node = node?;
+                Ok(self.add_expr(Expr::UnaryOp(t, node), start))
+            }
+            _ => self.power(),
+        }
+    }
+
+    /// `^` binds tighter than unary operators on its left, but not on its right
+    /// rule: power: simple [Pow factor]
+    fn power(&mut self) -> Result<ExprId, ParserError> {
+        let start = self.location();
+        let node = self.simple()?;
+        if let Some(Token::Flag(FlagType::Pow)) = self.peek_clone() {
+            self.eat(FlagType::Pow)?;
+            self.enter()?;
+            let right = self.factor();
+            self.leave();
+            let right = right?;
+            return Ok(self.add_expr(Expr::BinOp(FlagType::Pow, node, right), start));
+        }
+        Ok(node)
+    }
+
+    /// rule: simple: Integer | String | Boolean | Nil | '...' | FunctionDef | TableCtor | prefixexp
+    fn simple(&mut self) -> Result<ExprId, ParserError> {
+        let start = self.location();
         if let Some(token) = self.peek_clone() {
             match token {
+                Token::Flag(FlagType::Function) => self.function_def(),
+                Token::Flag(FlagType::LBrace) => self.table_constructor(),
                 Token::Num(n) => {
-                    self.eat(FlagType::Integer).unwrap();
-                    Ok(Expr::Num(n))
-                }
-                Token::Flag(t) if t == FlagType::Plus || t == FlagType::Minus ||
-                                  t == FlagType::Not => {
-                    self.eat(t).unwrap();
-                    let node = Box::new(self.factor()?);
-                    Ok(Expr::UnaryOp(t, node))
+                    self.eat(FlagType::Integer)?;
+                    Ok(self.add_expr(Expr::Num(n), start))
                 }
                 // TODO: use prefixexr() to parse parentheses
                 Token::Flag(FlagType::LParen) |
                 Token::Name(_) => self.prefixexp().map(|r| r.0), // dispose type info
                 Token::Str(s) => {
-                    self.eat(FlagType::Str).unwrap();
-                    Ok(Expr::Str(s))
+                    self.eat(FlagType::Str)?;
+                    Ok(self.add_expr(Expr::Str(s), start))
                 }
                 Token::Flag(FlagType::True) => {
-                    self.eat(FlagType::True).unwrap();
-                    Ok(Expr::Boole(true))
+                    self.eat(FlagType::True)?;
+                    Ok(self.add_expr(Expr::Boole(true), start))
                 }
                 Token::Flag(FlagType::False) => {
-                    self.eat(FlagType::False).unwrap();
-                    Ok(Expr::Boole(false))
+                    self.eat(FlagType::False)?;
+                    Ok(self.add_expr(Expr::Boole(false), start))
                 }
                 Token::Flag(FlagType::Nil) => {
-                    self.eat(FlagType::Nil).unwrap();
-                    Ok(Expr::Nil)
+                    self.eat(FlagType::Nil)?;
+                    Ok(self.add_expr(Expr::Nil, start))
+                }
+                Token::Flag(FlagType::TripleDot) => {
+                    self.eat(FlagType::TripleDot)?;
+                    Ok(self.add_expr(Expr::Vararg, start))
                 }
                 _ => Err(ParserError::SyntaxError),
             }
@@ -101,17 +286,20 @@ impl<'a, Tit> Parser<'a, Tit>
     }
 
     /// rule: term : factor((Mul | Div) factor)*
-    fn term(&mut self) -> Result<Expr, ParserError> {
+    fn term(&mut self) -> Result<ExprId, ParserError> {
+        let start = self.location();
         let mut node = self.factor()?;
         while let Some(token) = self.peek_clone() {
             match token {
                 Token::Flag(FlagType::Mul) => {
-                    self.eat(FlagType::Mul).unwrap(); // must succeed
-                    node = Expr::BinOp(FlagType::Mul, Box::new(node), Box::new(self.factor()?));
+                    self.eat(FlagType::Mul)?;
+                    let right = self.factor()?;
+                    node = self.add_expr(Expr::BinOp(FlagType::Mul, node, right), start);
                 }
                 Token::Flag(FlagType::Div) => {
-                    self.eat(FlagType::Div).unwrap(); // must secceed
-                    node = Expr::BinOp(FlagType::Div, Box::new(node), Box::new(self.factor()?));
+                    self.eat(FlagType::Div)?;
+                    let right = self.factor()?;
+                    node = self.add_expr(Expr::BinOp(FlagType::Div, node, right), start);
                 }
                 _ => break, 
             }
@@ -120,17 +308,20 @@ impl<'a, Tit> Parser<'a, Tit>
     }
 
     /// rule: LogicalTerm: term {(Plus | Minus) term}
-    fn logical_term(&mut self) -> Result<Expr, ParserError> {
+    fn logical_term(&mut self) -> Result<ExprId, ParserError> {
+        let start = self.location();
         let mut node = self.term()?;
         while let Some(token) = self.peek_clone() {
             match token {
                 Token::Flag(FlagType::Plus) => {
-                    self.eat(FlagType::Plus).unwrap();
-                    node = Expr::BinOp(FlagType::Plus, Box::new(node), Box::new(self.term()?));
+                    self.eat(FlagType::Plus)?;
+                    let right = self.term()?;
+                    node = self.add_expr(Expr::BinOp(FlagType::Plus, node, right), start);
                 }
                 Token::Flag(FlagType::Minus) => {
-                    self.eat(FlagType::Minus).unwrap();
-                    node = Expr::BinOp(FlagType::Minus, Box::new(node), Box::new(self.term()?));
+                    self.eat(FlagType::Minus)?;
+                    let right = self.term()?;
+                    node = self.add_expr(Expr::BinOp(FlagType::Minus, node, right), start);
                 }
                 _ => break,
             };
@@ -138,15 +329,33 @@ impl<'a, Tit> Parser<'a, Tit>
         Ok(node)
     }
 
+    /// `..` is right associative
+    /// rule: concat: LogicalTerm [DoubleDot concat]
+    fn concat(&mut self) -> Result<ExprId, ParserError> {
+        let start = self.location();
+        let node = self.logical_term()?;
+        if let Some(Token::Flag(FlagType::DoubleDot)) = self.peek_clone() {
+            self.eat(FlagType::DoubleDot)?;
+            self.enter()?;
+            let right = self.concat();
+            self.leave();
+            let right = right?;
+            return Ok(self.add_expr(Expr::BinOp(FlagType::DoubleDot, node, right), start));
+        }
+        Ok(node)
+    }
+
     /// rule: cmp: Disj [ ( EQ | NEQ ) Disj]
-    fn cmp(&mut self) -> Result<Expr, ParserError> {
-        let mut node = self.logical_term()?;
+    fn cmp(&mut self) -> Result<ExprId, ParserError> {
+        let start = self.location();
+        let mut node = self.concat()?;
         while let Some(Token::Flag(flag)) = self.peek_clone() {
             match flag {
                 FlagType::LESS | FlagType::LEQ | FlagType::GREATER | FlagType::GEQ |
                 FlagType::EQ | FlagType::NEQ => {
-                    self.eat(flag).unwrap();
-                    node = Expr::BinOp(flag, Box::new(node), Box::new(self.logical_term()?));
+                    self.eat(flag)?;
+                    let right = self.concat()?;
+                    node = self.add_expr(Expr::BinOp(flag, node, right), start);
                 }
                 _ => break,
             }
@@ -155,21 +364,25 @@ impl<'a, Tit> Parser<'a, Tit>
     }
 
     /// rule: conj : LogicalTerm AND LogicalTerm
-    fn conj(&mut self) -> Result<Expr, ParserError> {
+    fn conj(&mut self) -> Result<ExprId, ParserError> {
+        let start = self.location();
         let mut node = self.cmp()?;
         while let Some(Token::Flag(FlagType::AND)) = self.peek_clone() {
-            self.eat(FlagType::AND).unwrap();
-            node = Expr::BinOp(FlagType::AND, Box::new(node), Box::new(self.cmp()?));
+            self.eat(FlagType::AND)?;
+            let right = self.cmp()?;
+            node = self.add_expr(Expr::BinOp(FlagType::AND, node, right), start);
         }
         Ok(node)
     }
 
     /// rule: disj: Conj OR Conj
-    fn disj(&mut self) -> Result<Expr, ParserError> {
+    fn disj(&mut self) -> Result<ExprId, ParserError> {
+        let start = self.location();
         let mut node = self.conj()?;
         while let Some(Token::Flag(FlagType::OR)) = self.peek_clone() {
-            self.eat(FlagType::OR).unwrap();
-            node = Expr::BinOp(FlagType::OR, Box::new(node), Box::new(self.conj()?));
+            self.eat(FlagType::OR)?;
+            let right = self.conj()?;
+            node = self.add_expr(Expr::BinOp(FlagType::OR, node, right), start);
         }
         Ok(node)
     }
@@ -177,128 +390,85 @@ impl<'a, Tit> Parser<'a, Tit>
 
     /// rule: prefixexp ::= var | GeneralCall | '(' expr ')'
     /// ret: (prefixexp, GeneralCall or Var)
-    fn prefixexp(&mut self) -> Result<(Expr, PrefixExp), ParserError> {
+    fn prefixexp(&mut self) -> Result<(ExprId, PrefixExp), ParserError> {
+        let start = self.location();
         // look forward (1)
-        let prefix = match self.peek_clone().unwrap() {
+        let prefix = match self.peek_clone() {
             // '(' expr ')'
-            Token::Flag(FlagType::LParen) => {
-                self.eat(FlagType::LParen).unwrap();
+            Some(Token::Flag(FlagType::LParen)) => {
+                self.eat(FlagType::LParen)?;
                 let node = self.expr()?;
                 self.eat(FlagType::RParen)?;
-                (node, PrefixExp::Other)
+                (self.add_expr(Expr::Paren(node), start), PrefixExp::Other)
             }
-            // could be name or name + modifier
-            Token::Name(name) => {
-                self.eat(FlagType::Name).unwrap();
-                let node = Expr::Var(Var::Name(name));
-                self.name_complement(node)?
+            Some(Token::Name(name)) => {
+                self.eat(FlagType::Name)?;
+                (self.add_expr(Expr::Var(Var::Name(name)), start), PrefixExp::Name)
             }
-            _ => panic!("Token won't be used by prefixexp: {:?}", self.peek_clone()),
+            _ => return Err(ParserError::SyntaxError),
         };
         // println!("Prefix: {:?}", prefix);
         self.prefixexp_expand(prefix)
     }
 
-    /// Convert PrefixExp(Exp::Var(Var::Name)) to Var::Name
-    /// leave other unchanged
-    fn strip_prefixexp_to_var(&self, expr: Expr, cat: PrefixExp) -> Result<Var, ParserError> {
+    /// only a name or a prefixexp ended by an index can be assigned to
+    fn strip_prefixexp_to_var(&self, expr: ExprId, cat: PrefixExp) -> Result<ExprId, ParserError> {
         match cat {
             PrefixExp::Name => {
-                if let Expr::Var(Var::Name(name)) = expr {
-                    Ok(Var::Name(name))
+                if let Expr::Var(Var::Name(_)) = self.ast[expr] {
+                    Ok(expr)
                 } else {
-                    panic!("Auxiliary type info is inconsistent");
+                    // auxiliary type info is inconsistent
+                    Err(ParserError::ParseFailed)
                 }
             }
-            PrefixExp::Var => Ok(Var::PrefixExp(Box::new(expr))),
+            PrefixExp::Var => Ok(expr),
             _ => Err(ParserError::SyntaxError),
         }
     }
 
-    /// expend prefixexp with ':' '[' '.' '('
-    /// if can not expand
-    /// original prefix is returned (epsilon)
-    /// Notice: '(' is the terminal expension
+    /// expand prefixexp with any number of suffixes
+    /// rule: { '.' Name | '[' exp ']' | ':' Name args | args }
+    /// the category of the last suffix is returned,
+    /// if can not expand, original prefix is returned (epsilon)
     fn prefixexp_expand(&mut self,
-                        prefix: (Expr, PrefixExp))
-                        -> Result<(Expr, PrefixExp), ParserError> {
-        if let Some(token) = self.peek_clone() {
+                        prefix: (ExprId, PrefixExp))
+                        -> Result<(ExprId, PrefixExp), ParserError> {
+        let (mut node, mut cat) = prefix;
+        let start = self.ast.location(node);
+        // a loop rather than recursion, so long chains can not overflow the stack
+        while let Some(token) = self.peek_clone() {
             match token {
-                // recurse
                 Token::Flag(FlagType::LCrotchet) => {
-                    let (table_name, _) = prefix;
-                    let table_ref = self.table_crotchet_ref(table_name)?;
-                    self.prefixexp_expand((table_ref, PrefixExp::Var))
-                }
-                Token::Flag(FlagType::Colon) => {
-                    let (table_name, _) = prefix;
-                    Ok((self.table_colon_call(table_name)?, PrefixExp::ColonCall))
-                    // Stop expend
+                    node = self.table_crotchet_ref(node)?;
+                    cat = PrefixExp::Var;
                 }
                 Token::Flag(FlagType::Dot) => {
-                    let (table_name, _) = prefix;
-                    let table_ref = self.table_dot_ref(table_name)?;
-                    self.prefixexp_expand((table_ref, PrefixExp::Var))
-                }
-                Token::Flag(FlagType::LParen) => {
-                    // TODO: extract as func_call()
-                    //       or maybe merge with stat ::= function funcname args
-                    self.eat(FlagType::LParen).unwrap();
-                    let (args, is_vararg) = self.arglist()?;
-                    self.eat(FlagType::RParen)?;
-                    let (func_name, _) = prefix;
-                    let node = Expr::GeneralCall(Box::new(func_name), args, is_vararg);
-                    Ok((node, PrefixExp::GeneralCall))
-                }
-                _ => {
-                    //println!("Name unchanged");
-                    Ok(prefix)
-                }
-            }
-        } else {
-            Ok(prefix)
-        }
-    }
-
-    /// try parse complement form (e.g. ':' , '[', '(')
-    /// and combine complement form with prefix
-    /// if no complement is found, original_prefix is returned
-    fn name_complement(&mut self, prefix: Expr) -> Result<(Expr, PrefixExp), ParserError> {
-        if let Some(token) = self.peek_clone() {
-            match token {
-                // Name args
-                Token::Flag(FlagType::LParen) => {
-                    self.eat(FlagType::LParen).unwrap();
-                    let (args, is_vararg) = self.arglist()?;
-                    self.eat(FlagType::RParen)?;
-                    let node = Expr::GeneralCall(Box::new(prefix), args, is_vararg);
-                    Ok((node, PrefixExp::GeneralCall))
-                }
-                // Name '[' exp ']'
-                Token::Flag(FlagType::LCrotchet) => {
-                    Ok((self.table_crotchet_ref(prefix)?, PrefixExp::Var))
+                    node = self.table_dot_ref(node)?;
+                    cat = PrefixExp::Var;
                 }
-                // Name ':' Name args
                 Token::Flag(FlagType::Colon) => {
-                    Ok((self.table_colon_call(prefix)?, PrefixExp::ColonCall))
+                    node = self.table_colon_call(node)?;
+                    cat = PrefixExp::ColonCall;
+                }
+                Token::Flag(FlagType::LParen) |
+                Token::Flag(FlagType::LBrace) |
+                Token::Str(_) => {
+                    let args = self.args()?;
+                    node = self.add_expr(Expr::GeneralCall(node, args), start);
+                    cat = PrefixExp::GeneralCall;
                 }
-                Token::Flag(FlagType::Dot) => Ok((self.table_dot_ref(prefix)?, PrefixExp::Var)),
-                // no expansion performed
-                _ => Ok((prefix, PrefixExp::Name)),
+                _ => break,
             }
-        } else {
-            Ok((prefix, PrefixExp::Name))
         }
+        Ok((node, cat))
     }
 
-    fn expr(&mut self) -> Result<Expr, ParserError> {
-        match self.peek_clone() {
-            // function def
-            Some(Token::Flag(FlagType::Function)) => self.function_def(),
-            // table constructor
-            Some(Token::Flag(FlagType::LBrace)) => self.table_constructor(),
-            _ => self.disj(),
-        }
+    fn expr(&mut self) -> Result<ExprId, ParserError> {
+        self.enter()?;
+        let result = self.disj();
+        self.leave();
+        result
     }
 }
 
@@ -307,107 +477,114 @@ impl<'a, Tit> Parser<'a, Tit>
     where Tit: iter::Iterator<Item = char> + Clone
 {
     /// rule: program: Block [newline]
-    fn program(&mut self) -> Result<Node, ParserError> {
-        self.block().map(|block| Node::Block(block))
-        // TODO: handle newline
+    fn program(&mut self) -> Result<BlockId, ParserError> {
+        let block = self.block()?;
+        // the whole input must be consumed
+        self.eat(FlagType::EOF).or(Err(ParserError::SyntaxError))?;
+        Ok(block)
     }
 
     /// rule: Block: {Stat} [Retstat]
-    fn block(&mut self) -> Result<Block, ParserError> {
+    fn block(&mut self) -> Result<BlockId, ParserError> {
+        self.enter()?;
+        let result = self.block_content();
+        self.leave();
+        let block = result?;
+        Ok(self.add_block(block))
+    }
+
+    fn block_content(&mut self) -> Result<Block, ParserError> {
         let mut stats: Vec<Stat> = vec![];
+        let mut locations = vec![];
         loop {
+            let location = self.location();
             let stat = self.stat();
-            //println!("{:?}", stat);
             match stat {
-                Ok(s) => stats.push(s),
+                // empty statements are dropped
+                Ok(Stat::Empty) => continue,
+                Ok(s) => {
+                    stats.push(s);
+                    locations.push(location);
+                }
                 Err(ParserError::ExpectationUnmeet) => break,
                 Err(err) => return Err(err),
             }
         }
-        let ret = self.retstat().ok();
-        Ok(Block::new(stats, ret))
-        // TODO: Retstat
+        let ret = if let Some(Token::Flag(FlagType::Return)) = self.peek_clone() {
+            Some(self.retstat()?)
+        } else {
+            None
+        };
+        Ok(Block::new(stats, locations, ret))
     }
 
     /// rule: Stat: Semi | (Varlist Assign ExprList)
     fn stat(&mut self) -> Result<Stat, ParserError> {
-        loop {
-            let attempt = if let Some(token) = self.peek_clone() {
-                //println!("{:?}", token);
-
-                match token {
-                    Token::Flag(FlagType::Semi) => {
-                        self.eat(FlagType::Semi).unwrap();
-                        Ok(Stat::Empty)
-                    }
-                    Token::Flag(FlagType::Local) => self.assign_local(),
-                    Token::Flag(FlagType::If) => self.if_else_clause(),
-                    // do not handle
-                    Token::Flag(FlagType::Else) |
-                    Token::Flag(FlagType::Elseif) => Err(ParserError::ExpectationUnmeet), 
-                    Token::Flag(FlagType::While) => self.while_do(),
-                    Token::Flag(FlagType::For) => self.for_clause(),
-                    Token::Flag(FlagType::Break) => {
-                        self.eat(FlagType::Break).unwrap();
-                        Ok(Stat::Break)
-                    }
-                    // do not handle retstat, leave it to block
-                    Token::Flag(FlagType::Return) => Err(ParserError::ExpectationUnmeet), 
-                    //  return an error , this will stop parsing block
-                    Token::Flag(FlagType::End) => Err(ParserError::ExpectationUnmeet),
-                    Token::Flag(FlagType::EOF) => Err(ParserError::ExpectationUnmeet),
-                    _ => self.assign_or_funcall(),
-                }
-            } else {
-                Err(ParserError::SyntaxError)
-            };
-            if let Ok(stat) = attempt {
-                // if stat is empty, drop it and keep parsing
-                if stat != Stat::Empty {
-                    // ownership, attempt is moved
-                    return Ok(stat);
+        let attempt = if let Some(token) = self.peek_clone() {
+            match token {
+                Token::Flag(FlagType::Semi) => {
+                    self.eat(FlagType::Semi)?;
+                    Ok(Stat::Empty)
                 }
-                // Err
-            } else {
-                return attempt;
+                Token::Flag(FlagType::Local) => self.assign_local(),
+                Token::Flag(FlagType::If) => self.if_else_clause(),
+                // do not handle
+                Token::Flag(FlagType::Else) |
+                Token::Flag(FlagType::Elseif) => return Err(ParserError::ExpectationUnmeet),
+                Token::Flag(FlagType::While) => self.while_do(),
+                Token::Flag(FlagType::For) => self.for_clause(),
+                Token::Flag(FlagType::Break) => {
+                    self.eat(FlagType::Break)?;
+                    Ok(Stat::Break)
+                }
+                // do not handle retstat, leave it to block
+                Token::Flag(FlagType::Return) => return Err(ParserError::ExpectationUnmeet),
+                //  return an error , this will stop parsing block
+                Token::Flag(FlagType::End) => return Err(ParserError::ExpectationUnmeet),
+                Token::Flag(FlagType::EOF) => return Err(ParserError::ExpectationUnmeet),
+                _ => self.assign_or_funcall(),
             }
+        } else {
+            Err(ParserError::SyntaxError)
+        };
+        match attempt {
+            // the statement has started, it can not be the end of block
+            Err(ParserError::ExpectationUnmeet) => Err(ParserError::SyntaxError),
+            result => result,
         }
-
     }
 
     fn assign_or_funcall(&mut self) -> Result<Stat, ParserError> {
         let (prefix_expr, prefix_type) = self.prefixexp()?;
         match prefix_type {
             PrefixExp::GeneralCall => {
-                if let Expr::GeneralCall(func_name, args, is_vararg) = prefix_expr {
-                    Ok(Stat::GeneralCall(func_name, args, is_vararg))
+                if let Expr::GeneralCall(func_name, ref args) = self.ast[prefix_expr] {
+                    Ok(Stat::GeneralCall(func_name, args.clone()))
                 } else {
-                    panic!("Auxiliary type info is inconsistent");
+                    Err(ParserError::ParseFailed)
                 }
             }
             PrefixExp::ColonCall => {
-                if let Expr::ColonCall(table_name, func_name, args, is_vararg) = prefix_expr {
-                    Ok(Stat::ColonCall(table_name, func_name, args, is_vararg))
+                if let Expr::ColonCall(table_name, func_name, ref args) = self.ast[prefix_expr] {
+                    Ok(Stat::ColonCall(table_name, func_name, args.clone()))
                 } else {
-                    panic!("Auxiliary type info is inconsistent");
+                    Err(ParserError::ParseFailed)
                 }
             }
             PrefixExp::Var | PrefixExp::Name => {
                 let var = self.strip_prefixexp_to_var(prefix_expr, prefix_type)?;
                 self.assign(var)
             }
-            _ => {
-                println!("Neither funcall nor var {:?}", prefix_expr);
-                Err(ParserError::SyntaxError)
-            }
+            // neither funcall nor var
+            _ => Err(ParserError::SyntaxError),
         }
     }
 
     /// rule: assign: Varlist = Exprlist
-    fn assign(&mut self, first_var: Var) -> Result<Stat, ParserError> {
+    fn assign(&mut self, first_var: ExprId) -> Result<Stat, ParserError> {
         let varlist = self.varlist(first_var)?;
         if let Ok(_) = self.eat(FlagType::Assign) {
-            let mut exprlist = self.exprlist()?;
+            let exprlist = self.exprlist()?;
             Ok(Stat::Assign(varlist, exprlist))
         } else {
             Err(ParserError::ExpectationUnmeet)
@@ -415,9 +592,22 @@ impl<'a, Tit> Parser<'a, Tit>
     }
 
     /// rule : assign_local : Local Namelist = Exprlist
+    /// in the typed dialect, each name may be followed by ':' Type
     fn assign_local(&mut self) -> Result<Stat, ParserError> {
-        self.eat(FlagType::Local).unwrap();
-        let namelist = self.namelist()?;
+        let start = self.location();
+        self.eat(FlagType::Local)?;
+        let mut namelist = vec![];
+        let mut types = vec![];
+        loop {
+            namelist.push(self.name()?);
+            types.push(self.annotation()?);
+            if self.eat(FlagType::Comma).is_err() {
+                break;
+            }
+        }
+        if types.iter().any(Option::is_some) {
+            self.ast.annotations.locals.insert(start, types);
+        }
         if let Ok(_) = self.eat(FlagType::Assign) {
             let exprlist = self.exprlist()?;
             Ok(Stat::AssignLocal(namelist, exprlist))
@@ -428,27 +618,34 @@ impl<'a, Tit> Parser<'a, Tit>
     }
 
     /// rule: retstat:
-    fn retstat(&mut self) -> Result<Vec<Expr>, ParserError> {
-        try!(self.eat(FlagType::Return));
-        self.exprlist().or(Ok(vec![])).map(|r| {
-            self.eat(FlagType::Semi).ok(); // ignore
-            r
-        })
+    fn retstat(&mut self) -> Result<Vec<ExprId>, ParserError> {
+        self.eat(FlagType::Return)?;
+        let exprlist = match self.peek_clone() {
+            // void return
+            Some(Token::Flag(FlagType::End)) |
+            Some(Token::Flag(FlagType::Else)) |
+            Some(Token::Flag(FlagType::Elseif)) |
+            Some(Token::Flag(FlagType::Semi)) |
+            Some(Token::Flag(FlagType::EOF)) => vec![],
+            _ => self.exprlist()?,
+        };
+        self.eat(FlagType::Semi).ok(); // ignore
+        Ok(exprlist)
     }
 
     /// rule: var: Name | PrefixExpr |
-    fn var(&mut self) -> Result<Var, ParserError> {
+    fn var(&mut self) -> Result<ExprId, ParserError> {
         self.prefixexp().and_then(|(expr, cat)| self.strip_prefixexp_to_var(expr, cat))
     }
 
     /// rule: varlist ::= Var { Comma Name}
-    fn varlist(&mut self, first_var: Var) -> Result<Vec<Var>, ParserError> {
+    fn varlist(&mut self, first_var: ExprId) -> Result<Vec<ExprId>, ParserError> {
         let mut list = vec![first_var];
 
         while let Some(token) = self.peek_clone() {
             match token {
                 Token::Flag(FlagType::Comma) => {
-                    self.eat(FlagType::Comma).unwrap();
+                    self.eat(FlagType::Comma)?;
                     let var = self.var()?;
                     list.push(var);
                 }
@@ -460,7 +657,7 @@ impl<'a, Tit> Parser<'a, Tit>
 
     fn name(&mut self) -> Result<Name, ParserError> {
         if let Some(Token::Name(id)) = self.peek_clone() {
-            self.eat(FlagType::Name).unwrap();
+            self.eat(FlagType::Name)?;
             Ok(id)
         } else {
             Err(ParserError::SyntaxError)
@@ -468,12 +665,12 @@ impl<'a, Tit> Parser<'a, Tit>
     }
 
     fn namelist(&mut self) -> Result<Vec<Name>, ParserError> {
-        let mut list = vec![try!(self.name())];
+        let mut list = vec![self.name()?];
         while let Some(token) = self.peek_clone() {
             match token {
                 Token::Flag(FlagType::Comma) => {
-                    self.eat(FlagType::Comma).unwrap();
-                    list.push(try!(self.name()));
+                    self.eat(FlagType::Comma)?;
+                    list.push(self.name()?);
                 }
                 _ => break,
             }
@@ -481,50 +678,37 @@ impl<'a, Tit> Parser<'a, Tit>
         Ok(list)
     }
 
-    /// args for a function call
-    /// could end with TripleDot
-    fn arglist(&mut self) -> Result<(Vec<Expr>, bool), ParserError> {
-        let (mut list, mut var_arg) = match self.expr() {
-            Ok(expr) => (vec![expr], false),
-            Err(_) => {
-                if let Some(Token::Flag(FlagType::TripleDot)) = self.peek_clone() {
-                    self.eat(FlagType::TripleDot).unwrap();
-                    (vec![], true)
-                } else {
-                    (vec![], false)
-                }
+    /// rule: args ::= '(' [arglist] ')' | tableconstructor | String
+    fn args(&mut self) -> Result<Vec<ExprId>, ParserError> {
+        match self.peek_clone() {
+            Some(Token::Flag(FlagType::LBrace)) => Ok(vec![self.table_constructor()?]),
+            Some(Token::Str(s)) => {
+                let start = self.location();
+                self.eat(FlagType::Str)?;
+                Ok(vec![self.add_expr(Expr::Str(s), start)])
             }
-        };
-        while let Some(token) = self.peek_clone() {
-            match token {
-                Token::Flag(FlagType::Comma) => {
-                    self.eat(FlagType::Comma).unwrap();
-                    match self.expr() {
-                        Ok(expr) => list.push(expr),
-                        Err(_) => {
-                            if let Some(Token::Flag(FlagType::TripleDot)) = self.peek_clone() {
-                                self.eat(FlagType::TripleDot).unwrap();
-                                var_arg = true;
-                            } else {
-                                return Err(ParserError::SyntaxError);
-                            }
-                        }
-                    }
+            _ => {
+                self.eat(FlagType::LParen)?;
+                // no args
+                if let Some(Token::Flag(FlagType::RParen)) = self.peek_clone() {
+                    self.eat(FlagType::RParen)?;
+                    return Ok(vec![]);
                 }
-                _ => break,
+                let args = self.exprlist()?;
+                self.eat(FlagType::RParen)?;
+                Ok(args)
             }
         }
-        Ok((list, var_arg))
     }
 
     /// rule: exprlist:  Expr { Comma Expr}
-    fn exprlist(&mut self) -> Result<Vec<Expr>, ParserError> {
+    fn exprlist(&mut self) -> Result<Vec<ExprId>, ParserError> {
         let expr = self.expr()?;
         let mut list = vec![expr];
         while let Some(token) = self.peek_clone() {
             match token {
                 Token::Flag(FlagType::Comma) => {
-                    self.eat(FlagType::Comma).unwrap();
+                    self.eat(FlagType::Comma)?;
                     let expr = self.expr()?;
                     list.push(expr);
                 }
@@ -537,65 +721,55 @@ impl<'a, Tit> Parser<'a, Tit>
     /// ifelse: if Expr then Block { elseif Expr then Block} [else Block] end
     fn if_else_clause(&mut self) -> Result<Stat, ParserError> {
         self.eat(FlagType::If)?;
-        let expr = Box::new(self.expr()?);
+        let expr = self.expr()?;
         self.eat(FlagType::Then)?;
-        let then_node = Box::new(self.block()?);
-        let mut result = Stat::IfElse(expr, then_node, None);
-        let mut bottom_clause = &mut result as *mut Stat;
-        // {elseif exp then exp}
-        unsafe {
-            // bypass borrow checker
-            while let Some(Token::Flag(FlagType::Elseif)) = self.peek_clone() {
-                self.eat(FlagType::Elseif).unwrap();
-                let expr = Box::new(self.expr()?);
-                self.eat(FlagType::Then).or(Err(ParserError::SyntaxError))?;
-                let then_node = Box::new(self.block()?);
-                // create new if-else node and walk down
-                let sub_clause =
-                    Box::into_raw(Box::new(Block::new(vec![Stat::IfElse(expr, then_node, None)],
-                                                      None)));
-                if let Stat::IfElse(_, _, ref mut e) = *bottom_clause {
-                    *e = Some(Box::from_raw(sub_clause));
-                } else {
-                    panic!("Should not refute");
-                }
-                bottom_clause = &mut (*sub_clause).stats[0] as *mut Stat;
-            }
-            // [else block]
-            if let Some(Token::Flag(FlagType::Else)) = self.peek_clone() {
-                self.eat(FlagType::Else).unwrap();
-                let block = Box::new(self.block()?);
-                if let Stat::IfElse(_, _, ref mut e) = *bottom_clause {
-                    *e = Some(block);
-                } else {
-                    panic!("Should not refute");
-                }
-            }
-            if let Err(_) = self.eat(FlagType::End) {
-                Err(ParserError::SyntaxError)
-            } else {
-                Ok(result)
+        let then_node = self.block()?;
+        let mut clauses = vec![(expr, then_node, Location::default())];
+        // {elseif exp then block}
+        while let Some(Token::Flag(FlagType::Elseif)) = self.peek_clone() {
+            let location = self.location();
+            self.eat(FlagType::Elseif)?;
+            let expr = self.expr()?;
+            self.eat(FlagType::Then).or(Err(ParserError::SyntaxError))?;
+            let then_node = self.block()?;
+            clauses.push((expr, then_node, location));
+        }
+        // [else block]
+        let mut else_node = if let Some(Token::Flag(FlagType::Else)) = self.peek_clone() {
+            self.eat(FlagType::Else)?;
+            Some(self.block()?)
+        } else {
+            None
+        };
+        self.eat(FlagType::End).or(Err(ParserError::SyntaxError))?;
+        // each elseif is nested in the else block of its previous clause
+        loop {
+            let (expr, then_node, location) = clauses.pop().ok_or(ParserError::ParseFailed)?;
+            let clause = Stat::IfElse(expr, then_node, else_node);
+            if clauses.is_empty() {
+                return Ok(clause);
             }
+            else_node = Some(self.add_block(Block::new(vec![clause], vec![location], None)));
         }
     }
 
     /// WhileDo: while Expr do Block end
     fn while_do(&mut self) -> Result<Stat, ParserError> {
-        self.eat(FlagType::While).unwrap();
-        let expr = Box::new(self.expr()?);
+        self.eat(FlagType::While)?;
+        let expr = self.expr()?;
         self.eat(FlagType::Do).or(Err(ParserError::SyntaxError))?;
-        let block = Box::new(self.block()?);
+        let block = self.block()?;
         self.eat(FlagType::End).or(Err(ParserError::SyntaxError))?;
         Ok(Stat::While(expr, block))
     }
 
     fn for_clause(&mut self) -> Result<Stat, ParserError> {
-        self.eat(FlagType::For).unwrap();
+        self.eat(FlagType::For)?;
         let namelist = self.namelist()?;
         match self.peek_clone() {
             Some(Token::Flag(FlagType::Assign)) => {
                 if namelist.len() == 1 {
-                    self.numeric_for(namelist[0].clone())
+                    self.numeric_for(namelist[0])
                 } else {
                     Err(ParserError::SyntaxError)
                 }
@@ -607,28 +781,29 @@ impl<'a, Tit> Parser<'a, Tit>
 
     /// for Name = expr, expr [, expr] do Block end
     fn numeric_for(&mut self, name: Name) -> Result<Stat, ParserError> {
-        self.eat(FlagType::Assign).unwrap();
-        let start = Box::new(self.expr()?);
+        self.eat(FlagType::Assign)?;
+        let start = self.expr()?;
         self.eat(FlagType::Comma).or(Err(ParserError::SyntaxError))?;
-        let end = Box::new(self.expr()?);
+        let end = self.expr()?;
         // step : default 1
         let step = if let Ok(_) = self.eat(FlagType::Comma) {
             self.expr()?
         } else {
-            Expr::Num(1_f64)
+            let start = self.location();
+            self.add_expr(Expr::Num(1_f64), start)
         };
         self.eat(FlagType::Do).or(Err(ParserError::SyntaxError))?;
-        let block = Box::new(self.block()?);
+        let block = self.block()?;
         self.eat(FlagType::End)?;
-        Ok(Stat::ForNumeric(name, start, end, Box::new(step), block))
+        Ok(Stat::ForNumeric(name, start, end, step, block))
     }
 
     /// RangedFor: for Namelist in Exprlist do block end
     fn ranged_for(&mut self, namelist: Vec<Name>) -> Result<Stat, ParserError> {
-        self.eat(FlagType::In).unwrap();
+        self.eat(FlagType::In)?;
         let exprlist = self.exprlist()?;
         self.eat(FlagType::Do).or(Err(ParserError::SyntaxError))?;
-        let block = Box::new(self.block()?);
+        let block = self.block()?;
         self.eat(FlagType::End)?;
         Ok(Stat::ForRange(namelist, exprlist, block))
     }
@@ -639,21 +814,30 @@ impl<'a, Tit> Parser<'a, Tit>
     where Tit: iter::Iterator<Item = char> + Clone
 {
     /// rule : function FunctionBody
-    fn function_def(&mut self) -> Result<Expr, ParserError> {
-        self.eat(FlagType::Function).unwrap();
-        let (paras, content) = try!(self.function_body());
-        try!(self.eat(FlagType::End));
-        Ok(Expr::FunctionDef(paras, content))
+    /// in the typed dialect: function '(' Name [':' Type] ... ')' [':' Types] Block end
+    fn function_def(&mut self) -> Result<ExprId, ParserError> {
+        let start = self.location();
+        self.eat(FlagType::Function)?;
+        self.functions.push(start.line);
+        let body = self.function_body();
+        self.functions.pop();
+        let (paras, content) = body?;
+        self.eat(FlagType::End)?;
+        Ok(self.add_expr(Expr::FunctionDef(paras, content), start))
     }
     /// rule: Namelist [ , ...]
-    fn parlist(&mut self) -> Result<(Vec<Name>, bool), ParserError> {
+    fn parlist(&mut self) -> Result<ParList, ParserError> {
+        let mut types = vec![];
         // can not use namelist, can not parse three dot
         let (mut list, mut multiret) = match self.name() {
-            Ok(name) => (vec![name], false),
+            Ok(name) => {
+                types.push(self.annotation()?);
+                (vec![name], false)
+            }
             // (...)
             Err(_) => {
                 if let Some(Token::Flag(FlagType::TripleDot)) = self.peek_clone() {
-                    self.eat(FlagType::TripleDot).unwrap();
+                    self.eat(FlagType::TripleDot)?;
                     (vec![], true)
                 } else {
                     (vec![], false)
@@ -663,12 +847,15 @@ impl<'a, Tit> Parser<'a, Tit>
         while let Some(token) = self.peek_clone() {
             match token {
                 Token::Flag(FlagType::Comma) => {
-                    self.eat(FlagType::Comma).unwrap();
+                    self.eat(FlagType::Comma)?;
                     match self.name() {
-                        Ok(name) => list.push(name),
+                        Ok(name) => {
+                            list.push(name);
+                            types.push(self.annotation()?);
+                        }
                         Err(_) => {
                             if let Some(Token::Flag(FlagType::TripleDot)) = self.peek_clone() {
-                                self.eat(FlagType::TripleDot).unwrap();
+                                self.eat(FlagType::TripleDot)?;
                                 multiret = true;
                             } else {
                                 return Err(ParserError::SyntaxError);
@@ -679,48 +866,142 @@ impl<'a, Tit> Parser<'a, Tit>
                 _ => break,
             }
         }
-        Ok((list, multiret))
+        Ok(((list, multiret), types))
     }
 
     /// rule ( [parlist] ) Block end
-    fn function_body(&mut self) -> Result<((Vec<Name>, bool), Box<Block>), ParserError> {
-        try!(self.eat(FlagType::LParen));
+    fn function_body(&mut self) -> Result<((Vec<Name>, bool), BlockId), ParserError> {
+        self.eat(FlagType::LParen)?;
         // if parlist parse failed
         // it means no paras, use a empty list
-        let paras = self.parlist().unwrap_or((vec![], false));
-        try!(self.eat(FlagType::RParen));
-        let body = Box::new(self.block()?);
+        let (paras, params) = self.parlist().unwrap_or(((vec![], false), vec![]));
+        self.eat(FlagType::RParen)?;
+        let returns = self.return_types()?;
+        let body = self.block()?;
+        if returns.is_some() || params.iter().any(Option::is_some) {
+            let signature = FunctionType {
+                params,
+                is_vararg: paras.1,
+                returns,
+            };
+            self.ast.annotations.functions.insert(body, signature);
+        }
         Ok((paras, body))
     }
 }
 
+/// type annotations of the typed dialect
+impl<'a, Tit> Parser<'a, Tit>
+    where Tit: iter::Iterator<Item = char> + Clone
+{
+    /// rule: [':' Type], never present in plain Lua
+    fn annotation(&mut self) -> Result<Option<Type>, ParserError> {
+        if !self.typed || self.eat(FlagType::Colon).is_err() {
+            return Ok(None);
+        }
+        self.type_expr().map(Some)
+    }
+
+    /// rule: [':' (Type | '(' Type {',' Type} ')')]
+    fn return_types(&mut self) -> Result<Option<Vec<Type>>, ParserError> {
+        if !self.typed || self.eat(FlagType::Colon).is_err() {
+            return Ok(None);
+        }
+        if self.eat(FlagType::LParen).is_err() {
+            return Ok(Some(vec![self.type_expr()?]));
+        }
+        let mut types = vec![];
+        if self.eat(FlagType::RParen).is_ok() {
+            return Ok(Some(types));
+        }
+        loop {
+            types.push(self.type_expr()?);
+            if self.eat(FlagType::Comma).is_err() {
+                break;
+            }
+        }
+        self.eat(FlagType::RParen).or(Err(ParserError::SyntaxError))?;
+        Ok(Some(types))
+    }
+
+    fn type_expr(&mut self) -> Result<Type, ParserError> {
+        self.enter()?;
+        let result = self.type_content();
+        self.leave();
+        result
+    }
+
+    /// rule: Type ::= Name | nil | function | '{' Type '}' | '{' Name ':' Type {',' Name ':' Type} '}'
+    fn type_content(&mut self) -> Result<Type, ParserError> {
+        match self.peek_clone() {
+            Some(Token::Name(name)) => {
+                self.eat(FlagType::Name)?;
+                match self.token_iter.interner().resolve(name) {
+                    "any" => Ok(Type::Any),
+                    "boolean" => Ok(Type::Boolean),
+                    "number" => Ok(Type::Number),
+                    "string" => Ok(Type::String),
+                    "table" => Ok(Type::Table),
+                    _ => Err(ParserError::SyntaxError),
+                }
+            }
+            Some(Token::Flag(FlagType::Nil)) => {
+                self.eat(FlagType::Nil)?;
+                Ok(Type::Nil)
+            }
+            Some(Token::Flag(FlagType::Function)) => {
+                self.eat(FlagType::Function)?;
+                Ok(Type::Function(None))
+            }
+            Some(Token::Flag(FlagType::LBrace)) => {
+                self.eat(FlagType::LBrace)?;
+                let is_record = matches!((self.peek_clone(), self.peek_second()),
+                                         (Some(Token::Name(_)), Some(Token::Flag(FlagType::Colon))));
+                let ty = if is_record {
+                    let mut fields = vec![];
+                    loop {
+                        let name = self.name()?;
+                        self.eat(FlagType::Colon).or(Err(ParserError::SyntaxError))?;
+                        fields.push((name, self.type_expr()?));
+                        if self.eat(FlagType::Comma).is_err() {
+                            break;
+                        }
+                    }
+                    Type::Record(fields)
+                } else {
+                    Type::Array(Box::new(self.type_expr()?))
+                };
+                self.eat(FlagType::RBrace).or(Err(ParserError::SyntaxError))?;
+                Ok(ty)
+            }
+            _ => Err(ParserError::SyntaxError),
+        }
+    }
+}
+
 // table operations
 impl<'a, Tit> Parser<'a, Tit>
     where Tit: iter::Iterator<Item = char> + Clone
 {
     /// table constructor ::= '{' [fieldlist] '}'
-    fn table_constructor(&mut self) -> Result<Expr, ParserError> {
-        self.eat(FlagType::LBrace).unwrap();
-        let result = match self.field_list() {
-            Ok(list) => Ok(Expr::TableCtor(list)),
-            // allow empty field
-            Err(ParserError::ExpectationUnmeet) => Ok(Expr::TableCtor(vec![])),
-            Err(err) => Err(err), // TODO: why err @ Err(_) => err not work
-        };
-        self.eat(FlagType::RBrace).and(result)
+    fn table_constructor(&mut self) -> Result<ExprId, ParserError> {
+        let start = self.location();
+        self.eat(FlagType::LBrace)?;
+        let list = self.field_list()?;
+        self.eat(FlagType::RBrace)?;
+        Ok(self.add_expr(Expr::TableCtor(list), start))
     }
 
     /// fieldlist ::= field {(, | ;) field} [(, | ;)]
+    /// empty fieldlist is allowed
     fn field_list(&mut self) -> Result<Vec<TableEntry>, ParserError> {
-        let mut list = vec![self.field().map_err(|_| ParserError::ExpectationUnmeet)?];
-        while let Some(Token::Flag(flag)) = self.peek_clone() {
-            if flag == FlagType::Comma || flag == FlagType::Semi {
-                self.eat(flag).unwrap();
-                match self.field() {
-                    Ok(field) => list.push(field),
-                    Err(_) => break,
-                }
-            } else {
+        let mut list = vec![];
+        loop {
+            if let Some(Token::Flag(FlagType::RBrace)) = self.peek_clone() {
+                break;
+            }
+            list.push(self.field()?);
+            if self.eat(FlagType::Comma).is_err() && self.eat(FlagType::Semi).is_err() {
                 break;
             }
         }
@@ -732,17 +1013,18 @@ impl<'a, Tit> Parser<'a, Tit>
         let entry = match self.peek_clone() {
             // '[' exp ']' '=' exp
             Some(Token::Flag(FlagType::LCrotchet)) => {
-                self.eat(FlagType::LCrotchet).unwrap();
+                self.eat(FlagType::LCrotchet)?;
                 let key = self.expr()?;
                 self.eat(FlagType::RCrotchet)?;
                 self.eat(FlagType::Assign)?;
                 let value = self.expr()?;
                 (Some(key), value)
             }
-            // Name '=' exp
-            Some(Token::Name(name)) => {
-                self.eat(FlagType::Name).unwrap();
-                let key = Expr::Var(Var::Name(name));
+            // Name '=' exp, a name followed by anything else starts an exp
+            Some(Token::Name(name)) if self.peek_second() == Some(Token::Flag(FlagType::Assign)) => {
+                let start = self.location();
+                self.eat(FlagType::Name)?;
+                let key = self.add_expr(Expr::Str(name), start);
                 self.eat(FlagType::Assign)?;
                 let value = self.expr()?;
                 (Some(key), value)
@@ -758,27 +1040,288 @@ impl<'a, Tit> Parser<'a, Tit>
 
     /// build table reference syntax from provided table Exp
     /// table_ref ::= Exp '[ Expr ']'
-    fn table_crotchet_ref(&mut self, expr: Expr) -> Result<Expr, ParserError> {
-        self.eat(FlagType::LCrotchet).unwrap();
+    fn table_crotchet_ref(&mut self, expr: ExprId) -> Result<ExprId, ParserError> {
+        self.eat(FlagType::LCrotchet)?;
         let refer_field = self.expr()?;
         self.eat(FlagType::RCrotchet)?;
-        Ok(Expr::TableRef(Box::new(expr), Box::new(refer_field)))
+        let start = self.ast.location(expr);
+        Ok(self.add_expr(Expr::TableRef(expr, refer_field), start))
     }
 
-    fn table_dot_ref(&mut self, expr: Expr) -> Result<Expr, ParserError> {
-        self.eat(FlagType::Dot).unwrap();
+    fn table_dot_ref(&mut self, expr: ExprId) -> Result<ExprId, ParserError> {
+        self.eat(FlagType::Dot)?;
+        let key_start = self.location();
         let refer_name = self.name()?;
-        Ok(Expr::TableRef(Box::new(expr), Box::new(Expr::Var(Var::Name(refer_name)))))
+        let key = self.add_expr(Expr::Str(refer_name), key_start);
+        let start = self.ast.location(expr);
+        Ok(self.add_expr(Expr::TableRef(expr, key), start))
     }
 
     /// given table_name: Expr and peeked a ':'
     /// parse a colon call
-    fn table_colon_call(&mut self, table_name: Expr) -> Result<Expr, ParserError> {
-        self.eat(FlagType::Colon).unwrap();
+    fn table_colon_call(&mut self, table_name: ExprId) -> Result<ExprId, ParserError> {
+        self.eat(FlagType::Colon)?;
         let func_name = self.name()?;
-        self.eat(FlagType::LParen)?;
-        let (args, is_vararg) = self.arglist()?;
-        self.eat(FlagType::RParen)?;
-        Ok(Expr::ColonCall(Box::new(table_name),func_name,  args, is_vararg))
+        let args = self.args()?;
+        let start = self.ast.location(table_name);
+        Ok(self.add_expr(Expr::ColonCall(table_name, func_name, args), start))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::Chars;
+
+    fn parse(text: &str) -> Result<Ast, ParserError> {
+        Parser::<Chars>::ast_from_text(&text.to_string())
+    }
+
+    #[test]
+    fn malformed_input() {
+        let cases = [
+            "a, 1 = 2",
+            "a = 1 )",
+            "a = 1 end",
+            "if a then b = 1",
+            "if a then b = 1 elseif c then",
+            "while true do",
+            "f(1 +)",
+            "local = 1",
+            "return return",
+            "t = {1, 2",
+            "x",
+            "(",
+            "",
+        ];
+        for case in cases.iter().filter(|c| !c.is_empty()) {
+            assert!(parse(case).is_err(), "{:?} should be rejected", case);
+        }
+        assert_eq!(
+            parse("a = 'abc\n").unwrap_err(),
+            ParserError::TokenizeError(TokenizeError::UnfinishedString)
+        );
+        assert_eq!(
+            parse("a = 1 @ 2").unwrap_err(),
+            ParserError::TokenizeError(TokenizeError::Invalidoperator)
+        );
+    }
+
+    #[test]
+    fn expr_or_chunk() {
+        let expr = |text: &str| Parser::<Chars>::expr_from_text(&text.to_string());
+        let ast = expr("1 + 2").expect("Parse Error");
+        match ast.root {
+            Node::Expr(id) => match ast[id] {
+                Expr::BinOp(FlagType::Plus, left, right) => {
+                    assert_eq!(ast[left], Expr::Num(1.0));
+                    assert_eq!(ast[right], Expr::Num(2.0));
+                }
+                ref other => panic!("Unexpected expression {:?}", other),
+            },
+            _ => panic!("Root node should be an expression"),
+        }
+        assert!(expr("a = 1").is_err());
+        assert!(parse("a = 1").is_ok());
+        assert!(expr("1 2").is_err());
+
+        let incomplete = ["if a then", "f(", "f = function()", "x = 'abc", "while true do b = 1", "a ="];
+        for case in incomplete.iter() {
+            let err = parse(case).unwrap_err();
+            assert!(err.is_incomplete(), "{:?} should be incomplete, got {:?}", case, err);
+        }
+        assert!(expr("1 +").unwrap_err().is_incomplete());
+
+        let invalid = ["a = 1 )", "a = 1 end", "x = 'abc\n", "a = 1 @ 2"];
+        for case in invalid.iter() {
+            let err = parse(case).unwrap_err();
+            assert!(!err.is_incomplete(), "{:?} should be invalid, got {:?}", case, err);
+        }
+    }
+
+    #[test]
+    fn elseif_chain() {
+        let ast = parse("\
+            if a then b = 1
+            elseif c then b = 2
+            elseif d then b = 3
+            else b = 4
+            end
+        ").expect("Parse Error");
+        let block = match ast.root {
+            Node::Block(block) => &ast[block],
+            _ => panic!("Root node should be a block"),
+        };
+        let mut depth = 0;
+        let mut clause = &block.stats[0];
+        while let Stat::IfElse(_, _, Some(else_block)) = *clause {
+            depth += 1;
+            match ast[else_block].stats.first() {
+                Some(stat @ &Stat::IfElse(..)) => clause = stat,
+                _ => break,
+            }
+        }
+        assert_eq!(depth, 3);
+    }
+
+    #[test]
+    fn nesting_depth() {
+        let nested = |n: usize| format!("a = {}1{}", "(".repeat(n), ")".repeat(n));
+        assert!(parse(&nested(50)).is_ok());
+        let too_deep = |text: &str| match parse(text) {
+            Err(ParserError::Limit(err)) => err.to_string(),
+            other => panic!("Nesting limit expected, got {:?}", other),
+        };
+        assert_eq!(too_deep(&nested(100_000)),
+                   "1:204: main function is nested more than 200 levels deep");
+        let unary = format!("a = {}1", "- ".repeat(100_000));
+        assert_eq!(too_deep(&unary),
+                   "1:403: main function is nested more than 200 levels deep");
+        let blocks = format!("{}{}", "while a do ".repeat(1000), "end ".repeat(1000));
+        assert!(too_deep(&blocks).ends_with(": main function is nested more than 200 levels deep"));
+        let function = format!("f = function()\nreturn {}1 end", "- ".repeat(1000));
+        assert!(too_deep(&function).starts_with("2:"));
+        assert!(too_deep(&function).contains("function at line 1 is nested"));
+    }
+
+    #[test]
+    fn shared_names() {
+        let ast = parse("local a = a + b\nb = 'a'").expect("Parse Error");
+        let a = ast.interner.get("a").expect("Name not interned");
+        let block = match ast.root {
+            Node::Block(block) => &ast[block],
+            _ => panic!("Root node should be a block"),
+        };
+        match block.stats[0] {
+            Stat::AssignLocal(ref names, ref exprs) => {
+                assert_eq!(names, &vec![a]);
+                match ast[exprs[0]] {
+                    Expr::BinOp(FlagType::Plus, left, _) => {
+                        assert_eq!(ast[left], Expr::Var(Var::Name(a)))
+                    }
+                    ref other => panic!("Unexpected expression {:?}", other),
+                }
+            }
+            ref other => panic!("Unexpected statement {:?}", other),
+        }
+        match block.stats[1] {
+            Stat::Assign(_, ref exprs) => assert_eq!(ast[exprs[0]], Expr::Str(a)),
+            ref other => panic!("Unexpected statement {:?}", other),
+        }
+        assert_eq!(ast.name(a), "a");
+    }
+
+    #[test]
+    fn paren_expr() {
+        let returned = |text: &str| {
+            let ast = parse(text).expect("Parse Error");
+            let block = match ast.root {
+                Node::Block(block) => block,
+                _ => panic!("Root node should be a block"),
+            };
+            let ret = ast[block].ret.clone().expect("No return statement");
+            ast[ret[0]].clone()
+        };
+        match returned("return f()") {
+            Expr::GeneralCall(..) => (),
+            other => panic!("Unexpected expression {:?}", other),
+        }
+        match returned("return (f())") {
+            Expr::Paren(_) => (),
+            other => panic!("Unexpected expression {:?}", other),
+        }
+        // parentheses can not be assigned to
+        assert!(parse("(a) = 1").is_err());
+        assert!(parse("(t).a = 1").is_ok());
+    }
+
+    #[test]
+    fn unary_and_power() {
+        // the returned expression with its operators grouped in parentheses
+        fn shape(ast: &Ast, expr: ExprId) -> String {
+            match ast[expr] {
+                Expr::UnaryOp(op, operand) => {
+                    let op = match op {
+                        FlagType::Minus => "-",
+                        FlagType::Len => "#",
+                        _ => "not ",
+                    };
+                    format!("({}{})", op, shape(ast, operand))
+                }
+                Expr::BinOp(op, left, right) => {
+                    let op = match op {
+                        FlagType::Pow => "^",
+                        FlagType::Plus => "+",
+                        _ => "*",
+                    };
+                    format!("({}{}{})", shape(ast, left), op, shape(ast, right))
+                }
+                Expr::Num(n) => n.to_string(),
+                Expr::Var(Var::Name(name)) => ast.name(name).to_string(),
+                ref other => panic!("Unexpected expression {:?}", other),
+            }
+        }
+        let returned = |text: &str| {
+            let ast = parse(&format!("return {}", text)).expect("Parse Error");
+            let block = match ast.root {
+                Node::Block(block) => block,
+                _ => panic!("Root node should be a block"),
+            };
+            let ret = ast[block].ret.clone().expect("No return statement");
+            shape(&ast, ret[0])
+        };
+        assert_eq!(returned("-x ^ 2"), "(-(x^2))");
+        assert_eq!(returned("2 ^ -x ^ 2"), "(2^(-(x^2)))");
+        assert_eq!(returned("2 ^ 3 ^ 2"), "(2^(3^2))");
+        assert_eq!(returned("#t + 1 * -#t"), "((#t)+(1*(-(#t))))");
+        assert_eq!(returned("not #t ^ 2"), "(not (#(t^2)))");
+        assert!(parse("return 2 ^").is_err());
+    }
+
+    #[test]
+    fn concat() {
+        let ast = parse("return a .. b .. c, 1 + 2 .. 3 < x").expect("Parse Error");
+        let block = match ast.root {
+            Node::Block(block) => block,
+            _ => panic!("Root node should be a block"),
+        };
+        let ret = ast[block].ret.clone().expect("No return statement");
+        // right associative
+        match ast[ret[0]] {
+            Expr::BinOp(FlagType::DoubleDot, _, right) => {
+                assert!(matches!(ast[right], Expr::BinOp(FlagType::DoubleDot, _, _)))
+            }
+            ref other => panic!("Concatenation expected, got {:?}", other),
+        }
+        // between comparisons and additions
+        match ast[ret[1]] {
+            Expr::BinOp(FlagType::LESS, left, _) => match ast[left] {
+                Expr::BinOp(FlagType::DoubleDot, sum, _) => {
+                    assert!(matches!(ast[sum], Expr::BinOp(FlagType::Plus, _, _)))
+                }
+                ref other => panic!("Concatenation expected, got {:?}", other),
+            },
+            ref other => panic!("Comparison expected, got {:?}", other),
+        }
+        assert!(parse("return 'a' ..").is_err());
+    }
+
+    #[test]
+    fn prefix_chains() {
+        for text in &[
+            "f()()",
+            "local x = t[1]:m().x",
+            "local s = (\"s\"):upper()",
+            "a.b.c.d = v",
+            "getobj().field = 1",
+            "obj:m():n(1)[2] = 3",
+            "f{1, 2} f'str' t:m{} t:m'str'",
+            "t = {x, y.z, f(), a:b(), w = 1, [k] = v}",
+        ] {
+            assert!(parse(text).is_ok(), "Failed to parse {:?}", text);
+        }
+        for text in &["f() = 1", "a:b() = 1", "t:m", "t:m.x()", "f() g"] {
+            assert!(parse(text).is_err(), "Accepted {:?}", text);
+        }
     }
 }
@@ -1,52 +1,70 @@
 #![allow(unused)]
 
-use lexer::tokens::{FlagType};
+use lexer::tokens::{Comment, FlagType, Location};
+use lexer::TokenizeError;
+use lexer::interner::{Interner, Symbol};
+use std::collections::HashMap;
+use std::fmt;
+use std::ops::{Index, IndexMut};
 
-pub type Name = String;
-pub type TableEntry = (Option<Expr>, Expr);
+pub type Name = Symbol;
+pub type TableEntry = (Option<ExprId>, ExprId);
+
+/// index of an expression in `Ast`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct ExprId(u32);
+
+/// index of a block in `Ast`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct BlockId(u32);
 
 #[derive(Clone, Debug, PartialEq)]
 pub enum Expr {
     Num(f64),
     Boole(bool),
     Var(Var),
-    Str(String),
+    Str(Symbol),
     Nil,
-    BinOp(FlagType, Box<Expr>, Box<Expr>),
-    UnaryOp(FlagType, Box<Expr>),
+    BinOp(FlagType, ExprId, ExprId),
+    UnaryOp(FlagType, ExprId),
     // named paras and has_unnamed
-    FunctionDef((Vec<Name>, bool), Box<Block>),
-    // evaluating the expr yields the name of function, args, is_vararg
-    GeneralCall(Box<Expr>, Vec<Expr>, bool),
-    ColonCall(Box<Expr>, Name, Vec<Expr>, bool),
+    FunctionDef((Vec<Name>, bool), BlockId),
+    // evaluating the expr yields the name of function, args
+    GeneralCall(ExprId, Vec<ExprId>),
+    ColonCall(ExprId, Name, Vec<ExprId>),
     // vector of expr '=' expr
     TableCtor(Vec<TableEntry>),
     // Exp[Exp]
-    TableRef(Box<Expr>, Box<Expr>),
+    TableRef(ExprId, ExprId),
+    // '(' Exp ')', truncates multiple results to one
+    Paren(ExprId),
+    // '...', the extra arguments of a vararg function
+    Vararg,
 }
 
 #[derive(Clone, Debug, PartialEq)]
 pub struct Block {
     pub stats: Vec<Stat>,
-    pub ret: Option<Vec<Expr>>,
+    /// where each statement starts
+    pub locations: Vec<Location>,
+    pub ret: Option<Vec<ExprId>>,
 }
 
 impl Block {
-    pub fn new(body: Vec<Stat>, ret: Option<Vec<Expr>>) -> Block {
+    pub fn new(body: Vec<Stat>, locations: Vec<Location>, ret: Option<Vec<ExprId>>) -> Block {
         Block {
             stats: body,
+            locations,
             ret: ret,
         }
     }
 }
 
+/// a name in an expression or as an assignment target,
+/// `t.name` and `t[exp]` are table refs
 #[derive(Clone, Debug, PartialEq)]
 pub enum Var {
-    /// serve as recursion base
     Name(Name),
-    PrefixExp(Box<Expr>),
-    /// pass register position directly
-    Reg(u32),
 }
 
 // if a prefixexp is ended by '(' ... ')', GeneralCall
@@ -65,27 +83,222 @@ pub enum PrefixExp {
 pub enum Stat {
     Empty,
     Break,
-    // is_local
-    Assign(Vec<Var>, Vec<Expr>),
-    AssignLocal(Vec<Name>, Vec<Expr>),
-    IfElse(Box<Expr>, Box<Block>, Option<Box<Block>>),
-    While(Box<Expr>, Box<Block>),
-    ForRange(Vec<Name>, Vec<Expr>, Box<Block>),
-    ForNumeric(Name, Box<Expr>, Box<Expr>, Box<Expr>, Box<Block>),
-    GeneralCall(Box<Expr>, Vec<Expr>, bool),
-    ColonCall(Box<Expr>, Name, Vec<Expr>, bool),
-    Ret(Vec<Expr>),
+    // targets are names or table refs
+    Assign(Vec<ExprId>, Vec<ExprId>),
+    AssignLocal(Vec<Name>, Vec<ExprId>),
+    IfElse(ExprId, BlockId, Option<BlockId>),
+    While(ExprId, BlockId),
+    ForRange(Vec<Name>, Vec<ExprId>, BlockId),
+    ForNumeric(Name, ExprId, ExprId, ExprId, BlockId),
+    GeneralCall(ExprId, Vec<ExprId>),
+    ColonCall(ExprId, Name, Vec<ExprId>),
+    Ret(Vec<ExprId>),
 }
 
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum Node {
-    Expr(Expr),
-    Block(Block),
+    Expr(ExprId),
+    Block(BlockId),
+}
+
+/// type written in an annotation of the typed dialect
+#[derive(Clone, Debug, PartialEq)]
+pub enum Type {
+    Any,
+    Nil,
+    Boolean,
+    Number,
+    String,
+    /// a table of unknown layout
+    Table,
+    /// `{T}`, a sequence of T
+    Array(Box<Type>),
+    /// `{name: T, ...}`
+    Record(Vec<(Name, Type)>),
+    /// the signature is None for `function`, whose calls are not checked
+    Function(Option<FunctionType>),
+}
+
+/// parameters and results of a function, unannotated parameters are None
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct FunctionType {
+    pub params: Vec<Option<Type>>,
+    pub is_vararg: bool,
+    /// None if the results are not annotated
+    pub returns: Option<Vec<Type>>,
+}
+
+/// annotations of the typed dialect, code generation never reads them
+#[derive(Clone, Debug, Default)]
+pub struct Annotations {
+    /// types of the names of each local statement, by where the statement starts
+    pub locals: HashMap<Location, Vec<Option<Type>>>,
+    /// signatures of annotated functions, by their bodies
+    pub functions: HashMap<BlockId, FunctionType>,
+}
+
+/// syntax tree whose nodes are stored in flat arenas
+/// and refer to each other by index
+#[derive(Clone, Debug)]
+pub struct Ast {
+    exprs: Vec<Expr>,
+    /// where each expression starts
+    locations: Vec<Location>,
+    blocks: Vec<Block>,
+    pub interner: Interner,
+    pub comments: Vec<Comment>,
+    pub annotations: Annotations,
+    pub root: Node,
+}
+
+impl Ast {
+    /// an empty tree, root is set when parsing is finished
+    pub fn new() -> Ast {
+        Ast {
+            exprs: vec![],
+            locations: vec![],
+            blocks: vec![],
+            interner: Interner::new(),
+            comments: vec![],
+            annotations: Annotations::default(),
+            root: Node::Block(BlockId(0)),
+        }
+    }
+
+    pub fn add_expr(&mut self, expr: Expr, location: Location) -> ExprId {
+        self.locations.push(location);
+        push_node(&mut self.exprs, expr, ExprId)
+    }
+
+    pub fn location(&self, expr: ExprId) -> Location {
+        self.locations[expr.0 as usize]
+    }
+
+    pub fn add_block(&mut self, block: Block) -> BlockId {
+        push_node(&mut self.blocks, block, BlockId)
+    }
+
+    /// the string of an interned name or literal
+    pub fn name(&self, symbol: Symbol) -> &str {
+        self.interner.resolve(symbol)
+    }
+}
+
+/// append node to arena, return its index
+fn push_node<T, Id, F>(arena: &mut Vec<T>, node: T, id: F) -> Id
+    where F: Fn(u32) -> Id
+{
+    arena.push(node);
+    id((arena.len() - 1) as u32)
+}
+
+impl Index<ExprId> for Ast {
+    type Output = Expr;
+
+    fn index(&self, id: ExprId) -> &Expr {
+        &self.exprs[id.0 as usize]
+    }
+}
+
+impl IndexMut<ExprId> for Ast {
+    fn index_mut(&mut self, id: ExprId) -> &mut Expr {
+        &mut self.exprs[id.0 as usize]
+    }
+}
+
+impl Index<BlockId> for Ast {
+    type Output = Block;
+
+    fn index(&self, id: BlockId) -> &Block {
+        &self.blocks[id.0 as usize]
+    }
+}
+
+impl IndexMut<BlockId> for Ast {
+    fn index_mut(&mut self, id: BlockId) -> &mut Block {
+        &mut self.blocks[id.0 as usize]
+    }
+}
+
+/// implementation limits of Lua 5.1
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Limit {
+    /// active local variables of a function, LUAI_MAXVARS
+    Locals,
+    /// upvalues of a function, LUAI_MAXUPVALUES
+    UpValues,
+    /// constants of a function, MAXARG_Bx
+    Constants,
+    /// registers of a function, MAXSTACK
+    Registers,
+    /// instructions a jump can skip, MAXARG_sBx
+    JumpDistance,
+    /// nested blocks and expressions, LUAI_MAXCCALLS
+    Nesting,
+}
+
+impl Limit {
+    pub fn max(self) -> usize {
+        match self {
+            Limit::Locals => 200,
+            Limit::UpValues => 60,
+            Limit::Constants => 0x3FFFF,
+            Limit::Registers => 250,
+            Limit::JumpDistance => 0x1FFFF,
+            Limit::Nesting => 200,
+        }
+    }
+}
+
+/// a limit exceeded by a function of the source
+#[derive(Debug, Clone, PartialEq)]
+pub struct LimitError {
+    pub limit: Limit,
+    /// line where the function starts, None for the main chunk
+    pub function: Option<u32>,
+    pub location: Location,
 }
 
-#[derive(Debug)]
+impl fmt::Display for LimitError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let function = match self.function {
+            Some(line) => format!("function at line {}", line),
+            None => "main function".to_string(),
+        };
+        let max = self.limit.max();
+        write!(f, "{}: ", self.location)?;
+        match self.limit {
+            Limit::Locals => write!(f, "{} has more than {} local variables", function, max),
+            Limit::UpValues => write!(f, "{} has more than {} upvalues", function, max),
+            Limit::Constants => write!(f, "{} has more than {} constants", function, max),
+            Limit::Registers => write!(f, "{} needs more than {} registers", function, max),
+            Limit::JumpDistance => {
+                write!(f, "{} has a jump over more than {} instructions", function, max)
+            }
+            Limit::Nesting => write!(f, "{} is nested more than {} levels deep", function, max),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub enum ParserError {
     SyntaxError,
     ExpectationUnmeet,
     ParseFailed,
+    TokenizeError(TokenizeError),
+    /// blocks or expressions are nested too deeply
+    Limit(LimitError),
+    /// input ends in the middle of a statement or expression
+    UnexpectedEOF,
+}
+
+impl ParserError {
+    /// incomplete input may become valid with more text appended,
+    /// e.g. an unclosed `function`, `if` or string.
+    /// otherwise the input is invalid whatever follows
+    pub fn is_incomplete(&self) -> bool {
+        matches!(*self,
+                 ParserError::UnexpectedEOF |
+                 ParserError::TokenizeError(TokenizeError::UnexpectedEOF))
+    }
 }
\ No newline at end of file